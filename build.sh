@@ -1,11 +1,11 @@
 #!/bin/bash
 
 cd dummy-parachain
-cargo build
+cargo build --target wasm32-unknown-unknown
 cd -
 
 cd spree-lamport-clock
-cargo build
+cargo build --target wasm32-unknown-unknown
 cd -
 
 cd polkadot-re-mock
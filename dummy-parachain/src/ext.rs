@@ -2,6 +2,7 @@
 
 pub type SpreeHandle = usize;
 
+#[cfg(not(test))]
 mod ffi {
 	use super::SpreeHandle;
 
@@ -16,12 +17,134 @@ mod ffi {
 			blob_ptr: *const u8,
 			blob_len: usize,
 		);
+
+		/// Read the result of the last call into the SPREE module specified by `handle`, i.e.
+		/// the scratch buffer it was left with, into the buffer specified by `out_ptr` and
+		/// `out_len`.
+		///
+		/// Returns the length of the result, or `-1` if there is no module with the given handle.
+		/// If the buffer is too small, only the part of the result that fits is copied.
+		pub fn spree_result_read(handle: SpreeHandle, out_ptr: *mut u8, out_len: usize) -> i32;
+
+		/// Check whether the SPREE module specified by `handle` has inbound messages to poll,
+		/// without calling into it.
+		///
+		/// Returns `1` if it has, `0` if it hasn't, or `-1` if there is no module with the given
+		/// handle.
+		pub fn spree_has_inbound(handle: SpreeHandle) -> i32;
+
+		/// Read the value stored in the storage of the parachain under the key specified by
+		/// `key_ptr` and `key_len` into the buffer specified by `out_ptr` and `out_len`.
+		///
+		/// Returns the length of the value, or `-1` if there is no value under the key. If the
+		/// buffer is too small, only the part of the value that fits is copied.
+		pub fn storage_read(
+			key_ptr: *const u8,
+			key_len: usize,
+			out_ptr: *mut u8,
+			out_len: usize,
+		) -> i32;
+
+		/// Store the value specified by `val_ptr` and `val_len` in the storage of the parachain
+		/// under the key specified by `key_ptr` and `key_len`.
+		pub fn storage_write(
+			key_ptr: *const u8,
+			key_len: usize,
+			val_ptr: *const u8,
+			val_len: usize,
+		);
 	}
 }
 
+#[cfg(test)]
+pub(crate) mod mock;
+#[cfg(test)]
+use self::mock as ffi;
+
 /// Call into a SPREE module specified by a given `handle`.
 pub fn call_spree(handle: SpreeHandle, time_slice: usize, blob: &[u8]) {
 	unsafe {
 		ffi::call_spree(handle, time_slice, blob.as_ptr(), blob.len());
 	}
 }
+
+/// Read the result of the last call into the SPREE module specified by the given `handle`.
+pub fn spree_result(handle: SpreeHandle) -> Option<Vec<u8>> {
+	unsafe {
+		let len = ffi::spree_result_read(handle, core::ptr::null_mut(), 0);
+		if len < 0 {
+			return None;
+		}
+		let mut output = Vec::with_capacity(len as usize);
+		ffi::spree_result_read(handle, output.as_mut_ptr(), len as usize);
+		output.set_len(len as usize);
+		Some(output)
+	}
+}
+
+/// Call into the SPREE module `first` and pass its result as the blob to the SPREE module
+/// `second`.
+///
+/// Returns the result of `second`.
+pub fn call_spree_chained(
+	first: SpreeHandle,
+	second: SpreeHandle,
+	time_slice: usize,
+	blob: &[u8],
+) -> Option<Vec<u8>> {
+	call_spree(first, time_slice, blob);
+	let intermediate = spree_result(first)?;
+	call_spree(second, time_slice, &intermediate);
+	spree_result(second)
+}
+
+/// Returns `true` if the SPREE module specified by the given `handle` has inbound messages to
+/// poll.
+pub fn spree_has_inbound(handle: SpreeHandle) -> bool {
+	unsafe { ffi::spree_has_inbound(handle) == 1 }
+}
+
+/// Read the value stored under the given key in the storage of the parachain.
+pub fn storage_read(key: &[u8]) -> Option<Vec<u8>> {
+	unsafe {
+		let len = ffi::storage_read(key.as_ptr(), key.len(), core::ptr::null_mut(), 0);
+		if len < 0 {
+			return None;
+		}
+		let mut output = Vec::with_capacity(len as usize);
+		ffi::storage_read(key.as_ptr(), key.len(), output.as_mut_ptr(), len as usize);
+		output.set_len(len as usize);
+		Some(output)
+	}
+}
+
+/// Store the given value under the given key in the storage of the parachain.
+pub fn storage_write(key: &[u8], val: &[u8]) {
+	unsafe {
+		ffi::storage_write(key.as_ptr(), key.len(), val.as_ptr(), val.len());
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn chained_call_passes_the_result_of_the_first_module_to_the_second() {
+		mock::set_module(0, |blob| blob.to_ascii_uppercase());
+		mock::set_module(1, |blob| blob.iter().rev().copied().collect());
+
+		assert_eq!(call_spree_chained(0, 1, 7, b"foo"), Some(b"OOF".to_vec()));
+		assert_eq!(
+			mock::calls(),
+			vec![(0, 7, b"foo".to_vec()), (1, 7, b"FOO".to_vec())]
+		);
+		assert_eq!(spree_result(0), Some(b"FOO".to_vec()));
+	}
+
+	#[test]
+	fn result_of_an_unknown_module_is_absent() {
+		assert_eq!(spree_result(3), None);
+		assert!(!spree_has_inbound(3));
+	}
+}
@@ -0,0 +1,118 @@
+//! An in-process implementation of the polkadot runtime interface for native tests.
+//!
+//! Mirrors the signatures of the host functions declared in `ffi`, so the bindings can be
+//! exercised without a wasm host. SPREE modules are stood in for by plain functions of the blob
+//! they are called with to their result. The state of the host is kept per thread, and since every
+//! test runs on a thread of its own, tests don't observe each other's state.
+
+use super::SpreeHandle;
+use std::{
+	cell::RefCell,
+	collections::{HashMap, HashSet},
+	slice,
+};
+
+/// A stand-in for a SPREE module: maps the blob of a call to its result.
+pub type Module = fn(&[u8]) -> Vec<u8>;
+
+#[derive(Default)]
+struct Host {
+	modules: HashMap<SpreeHandle, Module>,
+	results: HashMap<SpreeHandle, Vec<u8>>,
+	/// Every call into a SPREE module as `(handle, time_slice, blob)`, in order.
+	calls: Vec<(SpreeHandle, usize, Vec<u8>)>,
+	has_inbound: HashSet<SpreeHandle>,
+	storage: HashMap<Vec<u8>, Vec<u8>>,
+}
+
+thread_local! {
+	static HOST: RefCell<Host> = RefCell::new(Host::default());
+}
+
+fn with<R>(f: impl FnOnce(&mut Host) -> R) -> R {
+	HOST.with(|host| f(&mut host.borrow_mut()))
+}
+
+unsafe fn bytes<'a>(ptr: *const u8, len: usize) -> &'a [u8] {
+	if len == 0 {
+		return &[];
+	}
+	slice::from_raw_parts(ptr, len)
+}
+
+/// Copy as much of `data` as fits into the buffer and return the full length, like the host does.
+unsafe fn read_into(data: &[u8], out_ptr: *mut u8, out_len: usize) -> i32 {
+	let len = data.len().min(out_len);
+	if len > 0 {
+		slice::from_raw_parts_mut(out_ptr, len).copy_from_slice(&data[..len]);
+	}
+	data.len() as i32
+}
+
+pub unsafe fn call_spree(
+	handle: SpreeHandle,
+	time_slice: usize,
+	blob_ptr: *const u8,
+	blob_len: usize,
+) {
+	let blob = bytes(blob_ptr, blob_len).to_vec();
+	with(|host| {
+		let module = *host
+			.modules
+			.get(&handle)
+			.unwrap_or_else(|| panic!("no SPREE module with handle {}", handle));
+		host.results.insert(handle, module(&blob));
+		host.calls.push((handle, time_slice, blob));
+	})
+}
+
+pub unsafe fn spree_result_read(handle: SpreeHandle, out_ptr: *mut u8, out_len: usize) -> i32 {
+	with(|host| match host.results.get(&handle) {
+		Some(result) => read_into(result, out_ptr, out_len),
+		None => -1,
+	})
+}
+
+pub unsafe fn spree_has_inbound(handle: SpreeHandle) -> i32 {
+	with(|host| {
+		if !host.modules.contains_key(&handle) {
+			-1
+		} else {
+			host.has_inbound.contains(&handle) as i32
+		}
+	})
+}
+
+pub unsafe fn storage_read(
+	key_ptr: *const u8,
+	key_len: usize,
+	out_ptr: *mut u8,
+	out_len: usize,
+) -> i32 {
+	let key = bytes(key_ptr, key_len);
+	with(|host| match host.storage.get(key) {
+		Some(val) => read_into(val, out_ptr, out_len),
+		None => -1,
+	})
+}
+
+pub unsafe fn storage_write(
+	key_ptr: *const u8,
+	key_len: usize,
+	val_ptr: *const u8,
+	val_len: usize,
+) {
+	let key = bytes(key_ptr, key_len).to_vec();
+	let val = bytes(val_ptr, val_len).to_vec();
+	with(|host| host.storage.insert(key, val));
+}
+
+/// Register a SPREE module under the given handle.
+pub fn set_module(handle: SpreeHandle, module: Module) {
+	with(|host| host.modules.insert(handle, module));
+}
+
+/// Returns every call into a SPREE module as `(handle, time_slice, blob)`, in order.
+pub fn calls() -> Vec<(SpreeHandle, usize, Vec<u8>)> {
+	with(|host| host.calls.clone())
+}
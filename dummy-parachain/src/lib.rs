@@ -1,9 +1,12 @@
 //! A striped-down version of a parachain validation function.
 
-use codec::Encode;
+use codec::{Decode, Encode};
 use spree_lamport_clock_primitives::Req;
 
-mod ext;
+pub mod ext;
+
+/// The key under which the number of validated blocks is stored.
+const KEY_BLOCK_COUNT: &[u8] = b":block_count";
 
 fn call_lamport_clock(req: Req) {
 	ext::call_spree(0, 1337, &req.encode());
@@ -11,10 +14,18 @@ fn call_lamport_clock(req: Req) {
 
 #[no_mangle]
 pub extern "C" fn validate_block() {
-	call_lamport_clock(Req::Poll);
+	// Save the call if there is nothing to poll.
+	if ext::spree_has_inbound(0) {
+		call_lamport_clock(Req::Poll);
+	}
 	call_lamport_clock(Req::Enqueue {
 		recepient: 1,
 		payload: b"foo".to_vec(),
 	});
 	call_lamport_clock(Req::FanOut);
+
+	let block_count = ext::storage_read(KEY_BLOCK_COUNT)
+		.map(|raw| u32::decode(&mut &raw[..]).expect("block count is always a u32"))
+		.unwrap_or(0);
+	ext::storage_write(KEY_BLOCK_COUNT, &(block_count + 1).encode());
 }
@@ -0,0 +1,69 @@
+//! Compares the overhead of the hourglass dispatch through `handle` with a direct call of a named
+//! export (ADC).
+//!
+//! Both paths perform the same logical operation: enqueueing a message in the lamport clock.
+//! Requires the lamport clock to be built with `./build.sh` first.
+
+use codec::Encode;
+use criterion::{criterion_group, criterion_main, Criterion};
+use polkadot_re_mock::spree::{SpreeIcmpAccumulator, SpreeModule};
+use spree_lamport_clock_primitives::Req;
+use wasmi::RuntimeValue;
+
+const SPREE_LAMPORT_CLOCK_WASM: &str = concat!(
+	env!("CARGO_MANIFEST_DIR"),
+	"/../spree-lamport-clock/target/wasm32-unknown-unknown/debug/spree_lamport_clock.wasm"
+);
+const CALLER_PARA_ID: u32 = 100;
+const TIME_SLICE: u32 = 1337;
+const RECEPIENT: u32 = 1;
+
+/// Create the lamport clock module and instantiate it, so that the instantiation is not accounted.
+fn instantiated_module() -> SpreeModule {
+	let mut spree_module = SpreeModule::new(
+		SPREE_LAMPORT_CLOCK_WASM,
+		SpreeIcmpAccumulator::with_inbound_msgs(vec![]),
+	);
+	spree_module
+		.invoke(CALLER_PARA_ID, TIME_SLICE, Req::QueueLen.encode())
+		.expect("the lamport clock is built");
+	spree_module
+}
+
+fn dispatch(c: &mut Criterion) {
+	let payload = b"foo".to_vec();
+	let mut group = c.benchmark_group("enqueue");
+
+	let mut spree_module = instantiated_module();
+	group.bench_function("hourglass", |b| {
+		b.iter(|| {
+			let req = Req::Enqueue {
+				recepient: RECEPIENT,
+				payload: payload.clone(),
+			};
+			spree_module
+				.invoke(CALLER_PARA_ID, TIME_SLICE, req.encode())
+				.unwrap()
+		})
+	});
+
+	let mut spree_module = instantiated_module();
+	group.bench_function("direct", |b| {
+		b.iter(|| {
+			spree_module
+				.invoke_direct(
+					CALLER_PARA_ID,
+					TIME_SLICE,
+					"enqueue_direct",
+					&[RuntimeValue::I32(RECEPIENT as i32)],
+					payload.clone(),
+				)
+				.unwrap()
+		})
+	});
+
+	group.finish();
+}
+
+criterion_group!(benches, dispatch);
+criterion_main!(benches);
@@ -9,6 +9,57 @@ pub enum Error {
 	/// A generic I/O error has happened.
 	#[error("I/O error")]
 	Io(#[from] io::Error),
+	/// A module couldn't be instantiated because one of its imports couldn't be resolved.
+	#[error("{path}: bad import: {reason}")]
+	BadImport { path: String, reason: String },
+	/// A module couldn't be instantiated because of a problem with its linear memory, e.g. it
+	/// is missing.
+	#[error("{path}: bad memory: {reason}")]
+	BadMemory { path: String, reason: String },
+	/// An entrypoint exported by a module has an unexpected signature.
+	#[error("{path}: `{name}` has signature {found}, expected {expected}")]
+	BadEntrypointSignature {
+		path: String,
+		name: String,
+		expected: String,
+		found: String,
+	},
+	/// A module couldn't be instantiated because it has a start function, which is not supported.
+	#[error("{path}: start function is not supported")]
+	StartFunction { path: String },
+	/// A SPREE module deliberately aborted the execution.
+	#[error("Module aborted with code {code}: {message}")]
+	ModuleAbort { code: i32, message: String },
+	/// A SPREE module exceeded the maximum call depth.
+	#[error("Call stack of {limit} frames exhausted")]
+	StackOverflow { limit: usize },
+	/// A SPREE module ran out of gas given for the invocation.
+	#[error("Out of gas")]
+	OutOfGas,
+	/// A host function failed deliberately, as configured by a `FailureInjector`.
+	#[error("Injected failure in `{fn_name}`")]
+	InjectedFailure { fn_name: String },
+	/// A SPREE module made more host calls than allowed for a single invocation.
+	#[error("Host call limit of {limit} exceeded")]
+	HostCallLimitExceeded { limit: u32 },
+	/// A SPREE module called `send` more times than allowed for a single invocation.
+	#[error("Outbound message limit of {limit} exceeded")]
+	OutboundLimitExceeded { limit: u32 },
+	/// A parachain called into more distinct SPREE modules within a block than allowed.
+	#[error("Limit of {limit} SPREE modules per block exceeded")]
+	SpreeModuleLimitExceeded { limit: u32 },
+	/// A value read from the storage of a SPREE module doesn't match its checksum.
+	#[error("Storage value under the key {key:?} is corrupted")]
+	StorageCorruption { key: Vec<u8> },
+	/// A SPREE module read an absent storage key that is not expected to be absent.
+	#[error("Read of the unknown storage key {key:?}")]
+	UnknownStorageKey { key: Vec<u8> },
+	/// An inbound bundle doesn't carry a valid signature of the sender.
+	#[error("Bad signature of the bundle from {sender}")]
+	BadSignature { sender: u32 },
+	/// A string passed from a module is not valid UTF-8.
+	#[error("Invalid UTF-8 string: {0}")]
+	InvalidUtf8(#[from] std::string::FromUtf8Error),
 	#[error("{0}")]
 	Msg(String),
 }
@@ -19,4 +70,54 @@ impl From<String> for Error {
 	}
 }
 
+impl Error {
+	/// Returns a copy of this error, unless it wraps an error that can't be copied.
+	///
+	/// This allows to recover an error raised by a host function from the trap it caused.
+	pub fn try_clone(&self) -> Option<Self> {
+		Some(match self {
+			Self::Interpreter(_) | Self::Io(_) => return None,
+			Self::BadImport { path, reason } => Self::BadImport {
+				path: path.clone(),
+				reason: reason.clone(),
+			},
+			Self::BadMemory { path, reason } => Self::BadMemory {
+				path: path.clone(),
+				reason: reason.clone(),
+			},
+			Self::BadEntrypointSignature {
+				path,
+				name,
+				expected,
+				found,
+			} => Self::BadEntrypointSignature {
+				path: path.clone(),
+				name: name.clone(),
+				expected: expected.clone(),
+				found: found.clone(),
+			},
+			Self::StartFunction { path } => Self::StartFunction { path: path.clone() },
+			Self::ModuleAbort { code, message } => Self::ModuleAbort {
+				code: *code,
+				message: message.clone(),
+			},
+			Self::StackOverflow { limit } => Self::StackOverflow { limit: *limit },
+			Self::OutOfGas => Self::OutOfGas,
+			Self::InjectedFailure { fn_name } => Self::InjectedFailure {
+				fn_name: fn_name.clone(),
+			},
+			Self::HostCallLimitExceeded { limit } => Self::HostCallLimitExceeded { limit: *limit },
+			Self::OutboundLimitExceeded { limit } => Self::OutboundLimitExceeded { limit: *limit },
+			Self::SpreeModuleLimitExceeded { limit } => {
+				Self::SpreeModuleLimitExceeded { limit: *limit }
+			}
+			Self::StorageCorruption { key } => Self::StorageCorruption { key: key.clone() },
+			Self::UnknownStorageKey { key } => Self::UnknownStorageKey { key: key.clone() },
+			Self::BadSignature { sender } => Self::BadSignature { sender: *sender },
+			Self::InvalidUtf8(err) => Self::InvalidUtf8(err.clone()),
+			Self::Msg(msg) => Self::Msg(msg.clone()),
+		})
+	}
+}
+
 impl wasmi::HostError for Error {}
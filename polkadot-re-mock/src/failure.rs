@@ -0,0 +1,51 @@
+//! Injection of host function failures for exercising error paths of SPREE modules.
+
+use spree_lamport_clock_primitives::StatusCode;
+use std::collections::HashMap;
+
+/// The way an injected failure manifests itself to the module.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InjectedFailure {
+	/// The host function returns the given status code.
+	///
+	/// Only applicable to the host functions that return a status code, other functions trap
+	/// instead.
+	Status(StatusCode),
+	/// The host function traps with `Error::InjectedFailure`.
+	Trap,
+}
+
+/// Makes the specified host functions fail on the specified calls.
+///
+/// Calls are counted per host function over the whole lifetime of a SPREE module, starting from 1.
+#[derive(Debug, Default)]
+pub struct FailureInjector {
+	/// Failures by the name of the host function and the number of the call.
+	rules: HashMap<(String, u32), InjectedFailure>,
+	/// The number of calls made so far by the name of the host function.
+	calls: HashMap<String, u32>,
+}
+
+impl FailureInjector {
+	/// Make the `nth` call of the host function with the given name fail.
+	pub fn fail_nth(
+		mut self,
+		fn_name: impl Into<String>,
+		nth: u32,
+		failure: InjectedFailure,
+	) -> Self {
+		self.rules.insert((fn_name.into(), nth), failure);
+		self
+	}
+
+	/// Count a call of the host function with the given name, returning the failure to inject, if
+	/// any.
+	pub fn check(&mut self, fn_name: &str) -> Option<InjectedFailure> {
+		if self.rules.is_empty() {
+			return None;
+		}
+		let calls = self.calls.entry(fn_name.to_string()).or_insert(0);
+		*calls += 1;
+		self.rules.get(&(fn_name.to_string(), *calls)).cloned()
+	}
+}
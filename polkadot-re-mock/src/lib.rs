@@ -0,0 +1,13 @@
+//! Polkadot Runtime Environment mock.
+//!
+//! The binary runs a scenario of the dummy parachain calling into the lamport clock SPREE module.
+//! The library exposes the environment itself, so that other SPREE modules and parachains can be
+//! exercised against it.
+
+pub mod error;
+pub mod failure;
+pub mod parachain;
+pub mod scenario;
+pub mod spree;
+pub mod storage;
+pub mod util;
@@ -1,38 +1,59 @@
 //! Polkadot Runtime Environment mock.
 
 use codec::Encode;
-use spree_lamport_clock_primitives::TimestampedMsg;
-
-mod error;
-mod parachain;
-mod spree;
-mod util;
-
-use error::Error;
-use spree::{SpreeIcmpAccumulator, SpreeModule};
+use polkadot_re_mock::{
+	error::Error,
+	failure::{FailureInjector, InjectedFailure},
+	scenario::{ModuleSpec, Scenario},
+};
+use spree_lamport_clock_primitives::{Req, TimestampedMsg};
 
+const PARACHAIN_ID: u32 = 100;
 const PARACHAIN_WASM: &str =
 	"./dummy-parachain/target/wasm32-unknown-unknown/debug/dummy_parachain.wasm";
 const SPREE_LAMPORT_CLOCK_WASM: &str =
 	"./spree-lamport-clock/target/wasm32-unknown-unknown/debug/spree_lamport_clock.wasm";
 
 fn main() -> Result<(), Error> {
-	// Initialize a SPREE module with the given wasm module and inbound messages.
-	let mut lamport_clock = SpreeModule::new(
-		SPREE_LAMPORT_CLOCK_WASM,
-		SpreeIcmpAccumulator::with_inbound_msgs(vec![(
-			0,
-			TimestampedMsg {
-				at: 0,
-				payload: b"bar".to_vec(),
-			}
-			.encode(),
-		)]),
-	);
+	// Describe the scenario: the dummy parachain with the lamport clock SPREE module that has a
+	// single inbound message.
+	let scenario = Scenario {
+		para_id: PARACHAIN_ID,
+		parachain: PARACHAIN_WASM.to_string(),
+		modules: vec![ModuleSpec {
+			name: "lamport_clock".to_string(),
+			path: SPREE_LAMPORT_CLOCK_WASM.to_string(),
+			inbound: vec![(
+				0,
+				TimestampedMsg {
+					at: 0,
+					payload: b"bar".to_vec(),
+					nonce: 0,
+				}
+				.encode(),
+			)],
+		}],
+		..Scenario::default()
+	};
 
 	// Call in the polkadot validation function with the given parachain wasm and given set
 	// of SPREE modules.
-	parachain::validate_block(PARACHAIN_WASM, &mut [&mut lamport_clock])?;
+	let mut run = scenario.run()?;
+
+	// The lamport clock has an inbound message, so the dummy parachain polls, enqueues a message
+	// and fans out.
+	assert_eq!(run.outcome.spree_calls.len(), 3);
+
+	// The dummy parachain counts the blocks it validated in its own storage.
+	assert_eq!(
+		run.parachain_storage.get(&b":block_count"[..]),
+		Some(&1u32.encode())
+	);
+
+	let lamport_clock = run
+		.modules
+		.get_mut("lamport_clock")
+		.expect("the module is a part of the scenario");
 
 	// Verify that expected messages were sent by the SPREE module.
 	assert_eq!(
@@ -41,7 +62,8 @@ fn main() -> Result<(), Error> {
 			1,
 			vec![TimestampedMsg {
 				at: 1,
-				payload: b"foo".to_vec()
+				payload: b"foo".to_vec(),
+				nonce: 1,
 			}]
 			.encode()
 		)]
@@ -49,5 +71,22 @@ fn main() -> Result<(), Error> {
 		.collect(),
 	);
 
+	// Enqueue a message, making it trap once it bumped the timestamp and tries to write the queue.
+	// Verify that none of the storage changes made by the trapped invocation persisted.
+	let snapshot = lamport_clock.storage_snapshot();
+	lamport_clock.set_failure_injector(FailureInjector::default().fail_nth(
+		"storage_write",
+		1,
+		InjectedFailure::Trap,
+	));
+	let enqueue = Req::Enqueue {
+		recepient: 1,
+		payload: b"baz".to_vec(),
+	};
+	assert!(lamport_clock
+		.invoke(PARACHAIN_ID, 1, enqueue.encode())
+		.is_err());
+	assert_eq!(lamport_clock.storage_snapshot(), snapshot);
+
 	Ok(())
 }
@@ -4,56 +4,170 @@
 //! bother ourselves here about concerns like `head_data` or anything similar.
 //!
 //! OTOH, we provide the `call_spree` function which allows parachain wasm code to call in to a
-//! given SPREE module.
+//! given SPREE module, and a simple key-value storage private to the parachain.
 
-use crate::{error::Error, spree::SpreeModule, util};
+use crate::{
+	error::Error,
+	spree::{HostFunction, HostResult, InvokeMetrics, SpreeHandle, SpreeRegistry},
+	util,
+};
+use std::{
+	cell::RefCell,
+	collections::{HashMap, HashSet},
+	time::{Duration, Instant},
+};
 use wasmi::{
-	Externals, FuncInstance, FuncRef, ImportsBuilder, MemoryRef, ModuleImportResolver,
-	ModuleInstance, ModuleRef, RuntimeArgs, RuntimeValue, Signature, Trap, ValueType,
+	memory_units::Pages, Externals, FuncInstance, FuncRef, ImportsBuilder, MemoryDescriptor,
+	MemoryInstance, MemoryRef, ModuleImportResolver, ModuleRef, RuntimeArgs, RuntimeValue,
+	Signature, Trap, ValueType,
 };
 
-/// Indexes for the host functions.
+/// The maximum number of wasm pages a parachain can get for the linear memory it imports.
+const MAX_IMPORTED_MEMORY_PAGES: u32 = 1024;
+
+/// Resolver for the functions that might be imported by a wasm blob.
 ///
-/// This module is exclusively for constant definitions.
-mod fn_index {
-	pub const CALL_SPREE: usize = 0;
+/// Apart from the functions from the host, it also resolves `memory` for the parachains that
+/// import the linear memory instead of exporting it.
+#[derive(Default)]
+struct ParachainImportResolver {
+	/// The linear memory created for the parachain, if it imports one.
+	imported_memory: RefCell<Option<MemoryRef>>,
 }
 
-/// Resolver for the functions that might be imported by a wasm blob.
+/// The implementation of a host function available to parachains.
+type ParachainHandler = fn(&mut ParachainHostEnv, RuntimeArgs) -> HostResult;
+
+/// The table of the host functions available to parachains.
 ///
-/// Currently, it only resolves functions from the host.
-struct ParachainImportResolver;
+/// Parachains don't have capabilities, so every function is always available.
+const HOST_FNS: &[HostFunction<ParachainHandler>] = {
+	use self::ValueType::*;
+	&[
+		HostFunction {
+			name: "call_spree",
+			params: &[I32, I32, I32, I32],
+			ret: None,
+			capability: 0,
+			handler: handlers::call_spree,
+		},
+		HostFunction {
+			name: "storage_read",
+			params: &[I32, I32, I32, I32],
+			ret: Some(I32),
+			capability: 0,
+			handler: handlers::storage_read,
+		},
+		HostFunction {
+			name: "storage_write",
+			params: &[I32, I32, I32, I32],
+			ret: None,
+			capability: 0,
+			handler: handlers::storage_write,
+		},
+		HostFunction {
+			name: "spree_result_read",
+			params: &[I32, I32, I32],
+			ret: Some(I32),
+			capability: 0,
+			handler: handlers::spree_result_read,
+		},
+		HostFunction {
+			name: "spree_has_inbound",
+			params: &[I32],
+			ret: Some(I32),
+			capability: 0,
+			handler: handlers::spree_has_inbound,
+		},
+	]
+};
 
-impl<'a> ModuleImportResolver for ParachainImportResolver {
+impl ModuleImportResolver for ParachainImportResolver {
 	fn resolve_func(
 		&self,
 		field_name: &str,
 		req_signature: &Signature,
 	) -> Result<FuncRef, wasmi::Error> {
-		use self::ValueType::*;
-
-		let func_ref = match field_name {
-			"call_spree" => FuncInstance::alloc_host(
-				Signature::new(&[I32, I32, I32, I32][..], None),
-				fn_index::CALL_SPREE,
-			),
-			_ => {
-				return Err(wasmi::Error::Function(format!(
-					"host module doesn't export function with name {}",
-					field_name
-				)));
-			}
-		};
-		if req_signature != func_ref.signature() {
-			return Err(wasmi::Error::Function(format!(
-				"wrong signature requested {}",
+		let (index, host_fn) = HostFunction::resolve(HOST_FNS, field_name, req_signature)?;
+		Ok(FuncInstance::alloc_host(host_fn.signature(), index))
+	}
+
+	fn resolve_memory(
+		&self,
+		field_name: &str,
+		descriptor: &MemoryDescriptor,
+	) -> Result<MemoryRef, wasmi::Error> {
+		if field_name != "memory" {
+			return Err(wasmi::Error::Memory(format!(
+				"host module doesn't export memory with name {}",
 				field_name
 			)));
 		}
-		Ok(func_ref)
+
+		let maximum = descriptor
+			.maximum()
+			.unwrap_or(MAX_IMPORTED_MEMORY_PAGES)
+			.min(MAX_IMPORTED_MEMORY_PAGES);
+		if descriptor.initial() > maximum {
+			return Err(wasmi::Error::Memory(format!(
+				"requested {} initial pages, but at most {} are allowed",
+				descriptor.initial(),
+				maximum
+			)));
+		}
+		let memory = MemoryInstance::alloc(
+			Pages(descriptor.initial() as usize),
+			Some(Pages(maximum as usize)),
+		)?;
+		*self.imported_memory.borrow_mut() = Some(memory.clone());
+		Ok(memory)
 	}
 }
 
+/// A summary of a call into a SPREE module made during the validation of a block.
+#[derive(Debug)]
+pub struct SpreeCall {
+	/// The handle of the called module.
+	pub handle: SpreeHandle,
+	/// The size of the blob passed to the module.
+	pub blob_len: usize,
+	/// The time spent in the module, as seen by the parachain.
+	pub duration: Duration,
+	/// The metrics collected during the call.
+	pub metrics: InvokeMetrics,
+	/// The recepients and the lengths of the outbound blobs of the module after the call, sorted
+	/// by the recepient.
+	pub outbound: Vec<(u32, usize)>,
+}
+
+/// The result of a successful validation of a block.
+#[derive(Debug, Default)]
+pub struct ValidationOutcome {
+	/// The calls into SPREE modules in the order they were made.
+	pub spree_calls: Vec<SpreeCall>,
+}
+
+impl ValidationOutcome {
+	/// Returns the total number of host calls made by the SPREE modules.
+	pub fn host_calls(&self) -> u64 {
+		self.spree_calls
+			.iter()
+			.map(|call| call.metrics.host_calls as u64)
+			.sum()
+	}
+}
+
+/// The state of the parachain and the limits applied while validating its blocks.
+#[derive(Debug, Default)]
+pub struct ValidationConfig {
+	/// The storage of the parachain, persisted across blocks.
+	pub storage: HashMap<Vec<u8>, Vec<u8>>,
+	/// The maximum number of distinct SPREE modules the parachain may call within a block.
+	///
+	/// Unlimited if `None`.
+	pub max_spree_modules: Option<u32>,
+}
+
 /// Host environment for parachain wasm.
 ///
 /// It serves calls from the wasm instance to the host.
@@ -63,8 +177,18 @@ struct ParachainHostEnv<'a, 'b> {
 	/// Linear memory of the calling wasm. Used for access the wasm's linear memory during
 	/// the host calls.
 	linear_memory: MemoryRef,
+	/// The ID of the parachain being validated.
+	para_id: u32,
 	/// Registered instances for this parachain.
-	spree_modules: &'b mut [&'a mut SpreeModule],
+	spree_registry: &'b mut SpreeRegistry<'a>,
+	/// The outcome accumulated so far.
+	outcome: ValidationOutcome,
+	/// The storage of the parachain, persisted across blocks.
+	storage: &'b mut HashMap<Vec<u8>, Vec<u8>>,
+	/// The maximum number of distinct SPREE modules the parachain may call within the block.
+	max_spree_modules: Option<u32>,
+	/// The handles of the SPREE modules called so far.
+	called_handles: HashSet<SpreeHandle>,
 }
 
 impl<'a, 'b> Externals for ParachainHostEnv<'a, 'b> {
@@ -73,67 +197,218 @@ impl<'a, 'b> Externals for ParachainHostEnv<'a, 'b> {
 		index: usize,
 		args: RuntimeArgs,
 	) -> Result<Option<RuntimeValue>, Trap> {
-		match index {
-			fn_index::CALL_SPREE => {
-				let handle: u32 = args.nth(0);
-				let time_slice: u32 = args.nth(1);
-				let blob_ptr: u32 = args.nth(2);
-				let blob_len: u32 = args.nth(3);
-
-				// Copy the specified blob.
-				let blob_buf = self
-					.linear_memory
-					.get(blob_ptr, blob_len as usize)
+		let host_fn = HOST_FNS.get(index).expect("unknown function index");
+		(host_fn.handler)(self, args)
+	}
+}
+
+/// Implementations of the host functions available to parachains.
+mod handlers {
+	use super::*;
+
+	pub(super) fn call_spree(env: &mut ParachainHostEnv, args: RuntimeArgs) -> HostResult {
+		let args = util::Args::new("call_spree", args, 4)?;
+		let handle = args.u32(0, "handle")?;
+		let time_slice = args.u32(1, "time_slice")?;
+		let blob_ptr = args.u32(2, "blob_ptr")?;
+		let blob_len = args.u32(3, "blob_len")?;
+
+		if let Some(limit) = env.max_spree_modules {
+			if env.called_handles.insert(handle) && env.called_handles.len() > limit as usize {
+				return Err(Error::SpreeModuleLimitExceeded { limit }.into());
+			}
+		}
+
+		// Copy the specified blob.
+		let blob_buf = env
+			.linear_memory
+			.get(blob_ptr, blob_len as usize)
+			.map_err(Error::from)?;
+
+		// Call in to the specified module passing the blob into it.
+		let started = Instant::now();
+		env.spree_registry
+			.invoke(handle, env.para_id, time_slice, blob_buf)?;
+		let duration = started.elapsed();
+
+		if let Some(spree_module) = env.spree_registry.get_mut(handle) {
+			let mut outbound = spree_module
+				.outbound_messages()
+				.iter()
+				.map(|(recepient, blob)| (*recepient, blob.len()))
+				.collect::<Vec<_>>();
+			outbound.sort();
+			env.outcome.spree_calls.push(SpreeCall {
+				handle,
+				blob_len: blob_len as usize,
+				duration,
+				metrics: spree_module.last_metrics().clone(),
+				outbound,
+			});
+		}
+
+		Ok(None)
+	}
+
+	pub(super) fn storage_read(env: &mut ParachainHostEnv, args: RuntimeArgs) -> HostResult {
+		let args = util::Args::new("storage_read", args, 4)?;
+		let key_ptr = args.u32(0, "key_ptr")?;
+		let key_len = args.u32(1, "key_len")?;
+		let out_ptr = args.u32(2, "out_ptr")?;
+		let out_len = args.u32(3, "out_len")?;
+
+		let key_buf = env
+			.linear_memory
+			.get(key_ptr, key_len as usize)
+			.map_err(Error::from)?;
+		match env.storage.get(&key_buf) {
+			Some(val) => {
+				// Copy as much as fits, the caller can retry with a larger buffer.
+				let copied = val.len().min(out_len as usize);
+				env.linear_memory
+					.set(out_ptr, &val[..copied])
 					.map_err(Error::from)?;
+				Ok(Some(RuntimeValue::I32(val.len() as i32)))
+			}
+			None => Ok(Some(RuntimeValue::I32(-1))),
+		}
+	}
 
-				// Call in to the specified module passing the blob into it.
-				let spree_module = self
-					.spree_modules
-					.get_mut(handle as usize)
-					.ok_or_else(|| Error::Msg(format!("handle `{}` doesn't exist", handle)))?;
-				spree_module.invoke(time_slice, blob_buf)?;
+	pub(super) fn spree_result_read(env: &mut ParachainHostEnv, args: RuntimeArgs) -> HostResult {
+		let args = util::Args::new("spree_result_read", args, 3)?;
+		let handle = args.u32(0, "handle")?;
+		let out_ptr = args.u32(1, "out_ptr")?;
+		let out_len = args.u32(2, "out_len")?;
 
-				Ok(None)
+		match env.spree_registry.get_mut(handle) {
+			Some(spree_module) => {
+				// The scratch buffer left by the last call is the result of the module.
+				let result = spree_module.last_scratch();
+				let copied = result.len().min(out_len as usize);
+				env.linear_memory
+					.set(out_ptr, &result[..copied])
+					.map_err(Error::from)?;
+				Ok(Some(RuntimeValue::I32(result.len() as i32)))
 			}
-			_ => panic!("unknown function index"),
+			None => Ok(Some(RuntimeValue::I32(-1))),
 		}
 	}
+
+	pub(super) fn spree_has_inbound(env: &mut ParachainHostEnv, args: RuntimeArgs) -> HostResult {
+		let args = util::Args::new("spree_has_inbound", args, 1)?;
+		let handle = args.u32(0, "handle")?;
+
+		let has_inbound = match env.spree_registry.get_mut(handle) {
+			Some(spree_module) => spree_module.has_inbound() as i32,
+			None => -1,
+		};
+		Ok(Some(RuntimeValue::I32(has_inbound)))
+	}
+
+	pub(super) fn storage_write(env: &mut ParachainHostEnv, args: RuntimeArgs) -> HostResult {
+		let args = util::Args::new("storage_write", args, 4)?;
+		let key_ptr = args.u32(0, "key_ptr")?;
+		let key_len = args.u32(1, "key_len")?;
+		let val_ptr = args.u32(2, "val_ptr")?;
+		let val_len = args.u32(3, "val_len")?;
+
+		let key_buf = env
+			.linear_memory
+			.get(key_ptr, key_len as usize)
+			.map_err(Error::from)?;
+		let val_buf = env
+			.linear_memory
+			.get(val_ptr, val_len as usize)
+			.map_err(Error::from)?;
+		env.storage.insert(key_buf, val_buf);
+		Ok(None)
+	}
 }
 
-fn instantiate_parachain(parachain_binary: &str) -> Result<ModuleRef, Error> {
+/// An instantiated parachain validation function, along with its linear memory.
+///
+/// Can be reused for validating several blocks with `validate_block_with_instance`.
+pub struct ParachainInstance {
+	instance: ModuleRef,
+	linear_memory: MemoryRef,
+}
+
+/// Instantiate the parachain validation function at the given path.
+///
+/// The imported memory is used if the module imports one, otherwise the memory exported under
+/// `memory_export`.
+pub fn instantiate(
+	parachain_binary: &str,
+	memory_export: &str,
+) -> Result<ParachainInstance, Error> {
+	let resolver = ParachainImportResolver::default();
 	let mut imports = ImportsBuilder::new();
-	imports.push_resolver("env", &ParachainImportResolver);
+	imports.push_resolver("env", &resolver);
 
 	let module = util::load_wasm_module(parachain_binary)?;
-	let instance = ModuleInstance::new(&module, &imports)?.assert_no_start();
+	let instance = util::instantiate(parachain_binary, &module, &imports)?;
+	util::check_entrypoint_signature(
+		parachain_binary,
+		&instance,
+		"validate_block",
+		&Signature::new(&[][..], None),
+	)?;
 
-	Ok(instance)
+	let linear_memory = match resolver.imported_memory.borrow_mut().take() {
+		Some(imported_memory) => imported_memory,
+		None => util::exported_memory(parachain_binary, &instance, memory_export)?,
+	};
+
+	Ok(ParachainInstance {
+		instance,
+		linear_memory,
+	})
 }
 
 /// A function that mocks the polkadot validation function.
 ///
-/// This takes the path to parachain validation function wasm and configuration/state of SPREE
-/// modules accessible (opt-in?) by this parachain.
+/// This takes the ID of the parachain, the path to parachain validation function wasm, the name
+/// under which it exports its linear memory, the registry of SPREE modules accessible (opt-in?)
+/// by this parachain and the config holding the storage of the parachain.
 pub fn validate_block(
+	para_id: u32,
 	parachain_binary: &str,
-	spree_modules: &mut [&mut SpreeModule],
-) -> Result<(), Error> {
-	let instance = instantiate_parachain(parachain_binary)?;
+	memory_export: &str,
+	spree_registry: &mut SpreeRegistry,
+	config: &mut ValidationConfig,
+) -> Result<ValidationOutcome, Error> {
+	let parachain = instantiate(parachain_binary, memory_export)?;
+	validate_block_with_instance(para_id, &parachain, spree_registry, config)
+}
 
+/// Like `validate_block`, but reuses an instance created by `instantiate`.
+///
+/// Note that the state of the instance, i.e. its memory and globals, is carried over from the
+/// previous blocks validated with it.
+pub fn validate_block_with_instance(
+	para_id: u32,
+	parachain: &ParachainInstance,
+	spree_registry: &mut SpreeRegistry,
+	config: &mut ValidationConfig,
+) -> Result<ValidationOutcome, Error> {
 	let mut env = ParachainHostEnv {
-		spree_modules,
-		linear_memory: instance
-			.export_by_name("memory")
-			.ok_or_else(|| {
-				Error::from("spree module expected to have export called `memory`".to_string())
-			})?
-			.as_memory()
-			.ok_or_else(|| {
-				Error::from("spree module: `memory` should be a linear memory".to_string())
-			})?
-			.clone(),
+		para_id,
+		spree_registry,
+		linear_memory: parachain.linear_memory.clone(),
+		outcome: ValidationOutcome::default(),
+		storage: &mut config.storage,
+		max_spree_modules: config.max_spree_modules,
+		called_handles: HashSet::new(),
 	};
-	instance.invoke_export("validate_block", &[], &mut env)?;
+	parachain
+		.instance
+		.invoke_export("validate_block", &[], &mut env)
+		.map_err(|e| {
+			e.as_host_error()
+				.and_then(|e| e.downcast_ref::<Error>())
+				.and_then(Error::try_clone)
+				.unwrap_or_else(|| Error::from(e))
+		})?;
 
-	Ok(())
+	Ok(env.outcome)
 }
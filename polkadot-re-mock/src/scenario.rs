@@ -0,0 +1,189 @@
+//! Description of a simulation run: a parachain along with the SPREE modules it can call into.
+
+use crate::{
+	error::Error,
+	parachain::{self, ValidationConfig, ValidationOutcome},
+	spree::{ModuleState, SpreeIcmpAccumulator, SpreeModule, SpreeRegistry},
+	util,
+};
+use codec::{Decode, Encode};
+use std::collections::HashMap;
+
+/// A SPREE module taking part in a scenario.
+pub struct ModuleSpec {
+	/// The name the module is referred to by.
+	pub name: String,
+	/// The path to the wasm binary of the module.
+	pub path: String,
+	/// The inbound messages of the module as `(sender, blob)`.
+	pub inbound: Vec<(u32, Vec<u8>)>,
+}
+
+/// A parachain validating a single block with access to the given SPREE modules.
+///
+/// The modules are registered under handles equal to their positions in `modules`.
+pub struct Scenario {
+	pub para_id: u32,
+	/// The path to the wasm binary of the parachain validation function.
+	pub parachain: String,
+	/// The name under which the parachain exports its linear memory.
+	pub memory_export: String,
+	pub modules: Vec<ModuleSpec>,
+	/// The maximum number of distinct modules the parachain may call within the block.
+	pub max_spree_modules: Option<u32>,
+	/// The maximum number of live module instances, see `SpreeRegistry::with_max_instances`.
+	///
+	/// The limit applies across blocks: instances left by a block count towards the limit of the
+	/// next one.
+	pub max_instances: Option<usize>,
+}
+
+/// The state left after running a scenario.
+pub struct ScenarioRun {
+	/// The number of blocks validated so far.
+	pub block_number: u32,
+	/// The outcome of the last validated block.
+	pub outcome: ValidationOutcome,
+	/// The SPREE modules by their names.
+	pub modules: HashMap<String, SpreeModule>,
+	/// The storage of the parachain.
+	pub parachain_storage: HashMap<Vec<u8>, Vec<u8>>,
+	/// The names of the modules with a live instance, from the least recently used.
+	usage: Vec<String>,
+}
+
+/// The encoded form of a `ScenarioRun`, see `ScenarioRun::checkpoint`.
+///
+/// All maps are sorted by key so that equal states produce equal checkpoints.
+#[derive(Encode, Decode)]
+struct Checkpoint {
+	block_number: u32,
+	parachain_storage: Vec<(Vec<u8>, Vec<u8>)>,
+	modules: Vec<(String, ModuleState)>,
+}
+
+impl Scenario {
+	/// Instantiate the modules and validate a block of the parachain.
+	pub fn run(&self) -> Result<ScenarioRun, Error> {
+		let mut run = ScenarioRun {
+			block_number: 0,
+			outcome: ValidationOutcome::default(),
+			modules: self
+				.modules
+				.iter()
+				.map(|spec| {
+					let spree_module = SpreeModule::new(
+						spec.path.clone(),
+						SpreeIcmpAccumulator::with_inbound_msgs(spec.inbound.iter().cloned()),
+					);
+					(spec.name.clone(), spree_module)
+				})
+				.collect(),
+			parachain_storage: HashMap::new(),
+			usage: Vec::new(),
+		};
+		run.run_block(self)?;
+		Ok(run)
+	}
+}
+
+impl ScenarioRun {
+	/// Validate the next block of the parachain of `scenario` on top of this state.
+	///
+	/// `scenario` must be the one this run was started with.
+	pub fn run_block(&mut self, scenario: &Scenario) -> Result<(), Error> {
+		let mut spree_registry = match scenario.max_instances {
+			Some(max_instances) => SpreeRegistry::with_max_instances(max_instances),
+			None => SpreeRegistry::new(),
+		};
+		// Register the modules from the least recently used, so that the instances left by the
+		// previous blocks are evicted in the order they were used.
+		let usage = &self.usage;
+		let mut modules = self.modules.iter_mut().collect::<Vec<_>>();
+		modules.sort_by_key(|(name, _)| usage.iter().position(|used| used == *name));
+		for (name, spree_module) in modules {
+			let handle = scenario
+				.modules
+				.iter()
+				.position(|spec| &spec.name == name)
+				.ok_or_else(|| Error::Msg(format!("module `{}` is not in the scenario", name)))?;
+			spree_registry.register(handle as u32, spree_module)?;
+		}
+		let mut config = ValidationConfig {
+			storage: std::mem::take(&mut self.parachain_storage),
+			max_spree_modules: scenario.max_spree_modules,
+		};
+		let outcome = parachain::validate_block(
+			scenario.para_id,
+			&scenario.parachain,
+			&scenario.memory_export,
+			&mut spree_registry,
+			&mut config,
+		);
+		self.parachain_storage = config.storage;
+		self.usage = spree_registry
+			.instantiated()
+			.iter()
+			.map(|handle| scenario.modules[*handle as usize].name.clone())
+			.collect();
+		self.outcome = outcome?;
+		self.block_number += 1;
+		Ok(())
+	}
+
+	/// Serialize the state of the simulation: the block number, the parachain storage and the
+	/// storage and accumulators of every module.
+	///
+	/// The outcome of the last block and the module instances are not included.
+	pub fn checkpoint(&self) -> Vec<u8> {
+		let mut parachain_storage = self
+			.parachain_storage
+			.iter()
+			.map(|(key, value)| (key.clone(), value.clone()))
+			.collect::<Vec<_>>();
+		parachain_storage.sort();
+		let mut modules = self
+			.modules
+			.iter()
+			.map(|(name, spree_module)| (name.clone(), spree_module.save_state()))
+			.collect::<Vec<_>>();
+		modules.sort_by(|a, b| a.0.cmp(&b.0));
+		Checkpoint {
+			block_number: self.block_number,
+			parachain_storage,
+			modules,
+		}
+		.encode()
+	}
+
+	/// Restore the state produced by `checkpoint`.
+	///
+	/// Every module in the checkpoint must be present in this run.
+	pub fn restore(&mut self, checkpoint: &[u8]) -> Result<(), Error> {
+		let checkpoint = Checkpoint::decode(&mut &checkpoint[..])
+			.map_err(|e| Error::Msg(format!("malformed checkpoint: {}", e)))?;
+		for (name, state) in checkpoint.modules {
+			self.modules
+				.get_mut(&name)
+				.ok_or_else(|| Error::Msg(format!("unknown module `{}` in checkpoint", name)))?
+				.load_state(state)?;
+		}
+		self.block_number = checkpoint.block_number;
+		self.parachain_storage = checkpoint.parachain_storage.into_iter().collect();
+		self.outcome = ValidationOutcome::default();
+		Ok(())
+	}
+}
+
+impl Default for Scenario {
+	fn default() -> Self {
+		Self {
+			para_id: 0,
+			parachain: String::new(),
+			memory_export: util::DEFAULT_MEMORY_EXPORT.to_string(),
+			modules: Vec::new(),
+			max_spree_modules: None,
+			max_instances: None,
+		}
+	}
+}
@@ -1,180 +1,1046 @@
 //! Module that implements the wasm environment of a SPREE module.
 
-use crate::error::Error;
-use codec::Encode;
-use std::collections::HashMap;
+use crate::{
+	error::Error,
+	failure::{FailureInjector, InjectedFailure},
+	storage::{self, SpreeStorage, StorageDiff, StorageOverlay},
+};
+use codec::{Decode, Encode};
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use spree_lamport_clock_primitives::{
+	capabilities, encode_inbound, SendError, StatusCode, TargetedMsg, WireParaId, MAX_MESSAGE_LEN,
+};
+use std::{
+	collections::{hash_map::Entry, BTreeMap, BTreeSet, HashMap, HashSet},
+	fmt, mem,
+	ops::Deref,
+	rc::Rc,
+	time::{Duration, Instant},
+};
 use wasmi::{
-	Externals, FuncInstance, FuncRef, ImportsBuilder, MemoryRef, ModuleImportResolver,
-	ModuleInstance, ModuleRef, RuntimeArgs, RuntimeValue, Signature, Trap, ValueType,
+	memory_units::Pages, Externals, FuncInstance, FuncRef, ImportsBuilder, MemoryRef, Module,
+	ModuleImportResolver, ModuleRef, RuntimeArgs, RuntimeValue, Signature, StackRecycler, Trap,
+	TrapKind, ValueType, DEFAULT_CALL_STACK_LIMIT, DEFAULT_VALUE_STACK_LIMIT,
 };
 
-/// Indexes for the host functions.
+/// The amount of gas charged for a single call into the host.
+///
+/// This is a crude approximation: all host functions cost the same regardless of the amount of
+/// work they perform.
+const HOST_CALL_GAS_COST: u64 = 1;
+
+/// The name of the optional export that is called once before the first invocation of a module.
+///
+/// Unlike a wasm start function, it runs with access to the host functions, e.g. for seeding the
+/// genesis storage.
+const INIT_EXPORT: &str = "spree_init";
+
+/// The storage key under which the lamport clock module keeps its message queue.
+const QUEUE_KEY: &[u8] = b":stack";
+
+/// The result of a host function call.
+pub type HostResult = Result<Option<RuntimeValue>, Trap>;
+
+/// The implementation of a host function.
+pub type HostHandler = fn(&mut SpreeModuleHostEnv, RuntimeArgs) -> HostResult;
+
+/// A function provided by the host to SPREE modules, or, with a different handler, to parachains.
 ///
-/// This module is exclusively for constant definitions.
-mod fn_index {
+/// The functions available to a module are kept in a table, the position of a function in which
+/// serves as its index for dispatching calls. A table might contain several functions with the
+/// same name but different signatures, in which case the one matching the signature requested by
+/// the module is resolved.
+#[derive(Clone, Copy)]
+pub struct HostFunction<H = HostHandler> {
+	pub name: &'static str,
+	pub params: &'static [ValueType],
+	pub ret: Option<ValueType>,
+	/// The capability required for importing the function. `0` means the function is always
+	/// available.
+	pub capability: u64,
+	pub handler: H,
+}
+
+impl<H> HostFunction<H> {
+	pub fn signature(&self) -> Signature {
+		Signature::new(self.params, self.ret)
+	}
+
+	/// Find the function with the given name and the requested signature in the table.
+	///
+	/// Returns the function along with its index in the table.
+	pub fn resolve<'a>(
+		table: &'a [Self],
+		field_name: &str,
+		req_signature: &Signature,
+	) -> Result<(usize, &'a Self), wasmi::Error> {
+		let mut overloads = table
+			.iter()
+			.enumerate()
+			.filter(|(_, host_fn)| host_fn.name == field_name)
+			.peekable();
+		if overloads.peek().is_none() {
+			return Err(wasmi::Error::Function(format!(
+				"host module doesn't export function with name {}",
+				field_name
+			)));
+		}
+		overloads
+			.find(|(_, host_fn)| req_signature == &host_fn.signature())
+			.ok_or_else(|| {
+				wasmi::Error::Function(format!("wrong signature requested {}", field_name))
+			})
+	}
+}
 
-	pub const SCRATCH_BUF_SIZE: usize = 1;
-	pub const SCRATCH_BUF_READ: usize = 2;
-	pub const SEND: usize = 3;
-	pub const POLL: usize = 4;
-	pub const STORAGE_READ: usize = 5;
-	pub const STORAGE_WRITE: usize = 6;
+impl<H> fmt::Debug for HostFunction<H> {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.debug_struct("HostFunction")
+			.field("name", &self.name)
+			.field("params", &self.params)
+			.field("ret", &self.ret)
+			.field("capability", &format_args!("{:#x}", self.capability))
+			.finish()
+	}
 }
 
+/// The host functions available to every SPREE module.
+pub const BUILTIN_HOST_FNS: &[HostFunction] = {
+	use self::ValueType::*;
+	&[
+		HostFunction {
+			name: "scratch_buf_size",
+			params: &[],
+			ret: Some(I32),
+			capability: 0,
+			handler: handlers::scratch_buf_size,
+		},
+		HostFunction {
+			name: "scratch_buf_read",
+			params: &[I32],
+			ret: None,
+			capability: 0,
+			handler: handlers::scratch_buf_read,
+		},
+		// The newer ABI of `scratch_buf_read` that also returns the number of bytes read.
+		HostFunction {
+			name: "scratch_buf_read",
+			params: &[I32],
+			ret: Some(I32),
+			capability: 0,
+			handler: handlers::scratch_buf_read_len,
+		},
+		HostFunction {
+			name: "scratch_buf_read_at",
+			params: &[I32, I32, I32],
+			ret: Some(I32),
+			capability: 0,
+			handler: handlers::scratch_buf_read_at,
+		},
+		HostFunction {
+			name: "scratch_buf_write",
+			params: &[I32, I32],
+			ret: None,
+			capability: 0,
+			handler: handlers::scratch_buf_write,
+		},
+		HostFunction {
+			name: "host_capabilities",
+			params: &[],
+			ret: Some(I64),
+			capability: 0,
+			handler: handlers::host_capabilities,
+		},
+		HostFunction {
+			name: "send",
+			params: &[I32, I32, I32],
+			ret: Some(I32),
+			capability: capabilities::ICMP,
+			handler: handlers::send,
+		},
+		HostFunction {
+			name: "poll",
+			params: &[],
+			ret: None,
+			capability: capabilities::ICMP,
+			handler: handlers::poll,
+		},
+		HostFunction {
+			name: "poll_peek",
+			params: &[],
+			ret: None,
+			capability: capabilities::ICMP,
+			handler: handlers::poll_peek,
+		},
+		HostFunction {
+			name: "storage_read",
+			params: &[I32, I32],
+			ret: Some(I32),
+			capability: capabilities::STORAGE,
+			handler: handlers::storage_read,
+		},
+		HostFunction {
+			name: "storage_write",
+			params: &[I32, I32, I32, I32],
+			ret: None,
+			capability: capabilities::STORAGE,
+			handler: handlers::storage_write,
+		},
+		HostFunction {
+			name: "memory_size_pages",
+			params: &[],
+			ret: Some(I32),
+			capability: 0,
+			handler: handlers::memory_size_pages,
+		},
+		HostFunction {
+			name: "memory_grow",
+			params: &[I32],
+			ret: Some(I32),
+			capability: 0,
+			handler: handlers::memory_grow,
+		},
+		HostFunction {
+			name: "ephemeral_read",
+			params: &[I32, I32],
+			ret: Some(I32),
+			capability: 0,
+			handler: handlers::ephemeral_read,
+		},
+		HostFunction {
+			name: "ephemeral_write",
+			params: &[I32, I32, I32, I32],
+			ret: None,
+			capability: 0,
+			handler: handlers::ephemeral_write,
+		},
+		HostFunction {
+			name: "storage_increment",
+			params: &[I32, I32, I64],
+			ret: Some(I64),
+			capability: capabilities::STORAGE,
+			handler: handlers::storage_increment,
+		},
+		HostFunction {
+			name: "gas_left",
+			params: &[],
+			ret: Some(I64),
+			capability: capabilities::GAS,
+			handler: handlers::gas_left,
+		},
+		HostFunction {
+			name: "caller_para_id",
+			params: &[],
+			ret: Some(I32),
+			capability: capabilities::CALLER,
+			handler: handlers::caller_para_id,
+		},
+		HostFunction {
+			name: "storage_clear",
+			params: &[],
+			ret: None,
+			capability: capabilities::STORAGE_CLEAR,
+			handler: handlers::storage_clear,
+		},
+		HostFunction {
+			name: "random",
+			params: &[I32, I32],
+			ret: None,
+			capability: capabilities::RANDOM,
+			handler: handlers::random,
+		},
+		HostFunction {
+			name: "abort",
+			params: &[I32, I32, I32],
+			ret: None,
+			capability: 0,
+			handler: handlers::abort,
+		},
+		HostFunction {
+			name: "last_time_slice",
+			params: &[],
+			ret: Some(I64),
+			capability: 0,
+			handler: handlers::last_time_slice,
+		},
+	]
+};
+
 /// Resolver for the functions that might be imported by a wasm blob.
-struct SpreeModuleImportResolver;
+///
+/// Only resolves the functions that are allowed by the given set of capabilities.
+struct SpreeModuleImportResolver<'a> {
+	capabilities: u64,
+	host_fns: &'a [HostFunction],
+}
 
-impl<'a> ModuleImportResolver for SpreeModuleImportResolver {
+impl<'a> ModuleImportResolver for SpreeModuleImportResolver<'a> {
 	fn resolve_func(
 		&self,
 		field_name: &str,
 		req_signature: &Signature,
 	) -> Result<FuncRef, wasmi::Error> {
-		use self::ValueType::*;
-
-		let (fn_index, param_tys, return_ty) = match field_name {
-			"scratch_buf_size" => (fn_index::SCRATCH_BUF_SIZE, &[][..], Some(I32)),
-			"scratch_buf_read" => (fn_index::SCRATCH_BUF_READ, &[I32][..], None),
-			"send" => (fn_index::SEND, &[I32, I32, I32][..], Some(I32)),
-			"poll" => (fn_index::POLL, &[][..], None),
-			"storage_read" => (fn_index::STORAGE_READ, &[I32, I32][..], Some(I32)),
-			"storage_write" => (fn_index::STORAGE_WRITE, &[I32, I32, I32, I32][..], None),
-			_ => {
-				return Err(wasmi::Error::Function(format!(
-					"host module doesn't export function with name {}",
-					field_name
-				)));
-			}
-		};
-		let sig = Signature::new(param_tys, return_ty);
-		if req_signature != &sig {
+		let (index, host_fn) = HostFunction::resolve(self.host_fns, field_name, req_signature)?;
+		if self.capabilities & host_fn.capability != host_fn.capability {
 			return Err(wasmi::Error::Function(format!(
-				"wrong signature requested {}",
+				"function {} requires a capability that is not enabled",
 				field_name
 			)));
 		}
-		let func_ref = FuncInstance::alloc_host(sig, fn_index);
+		let func_ref = FuncInstance::alloc_host(host_fn.signature(), index);
 		Ok(func_ref)
 	}
 }
 
-struct SpreeModuleHostEnv<'a> {
-	scratch_buf: Vec<u8>,
+/// The scratch buffer of an invocation.
+///
+/// New contents are built in a spare buffer which is then swapped with the active one, so the
+/// allocations of both are reused for the whole invocation and a failed replacement leaves the
+/// contents intact.
+struct ScratchBuf {
+	/// The contents visible to the module.
+	active: Vec<u8>,
+	/// The previous contents, whose allocation is reused by the next replacement.
+	spare: Vec<u8>,
+	/// The number of replacements that had to grow an allocation.
+	allocs: u32,
+}
+
+impl ScratchBuf {
+	/// Create a buffer holding `contents` with at least `capacity` bytes reserved in each half.
+	fn new(capacity: usize, contents: &[u8]) -> Self {
+		let mut active = Vec::with_capacity(capacity.max(contents.len()));
+		active.extend_from_slice(contents);
+		Self {
+			active,
+			spare: Vec::with_capacity(capacity),
+			allocs: 0,
+		}
+	}
+
+	/// Replace the contents with the bytes appended by `fill` to an empty buffer.
+	fn replace(&mut self, fill: impl FnOnce(&mut Vec<u8>)) {
+		let _ = self.try_replace(|buf| {
+			fill(buf);
+			Ok::<_, ()>(())
+		});
+	}
+
+	/// Like `replace`, but keeps the current contents if `fill` fails.
+	fn try_replace<E>(
+		&mut self,
+		fill: impl FnOnce(&mut Vec<u8>) -> Result<(), E>,
+	) -> Result<(), E> {
+		self.spare.clear();
+		let capacity = self.spare.capacity();
+		let result = fill(&mut self.spare);
+		if self.spare.capacity() > capacity {
+			self.allocs += 1;
+		}
+		if result.is_ok() {
+			mem::swap(&mut self.active, &mut self.spare);
+		}
+		result
+	}
+}
+
+impl Deref for ScratchBuf {
+	type Target = [u8];
+
+	fn deref(&self) -> &[u8] {
+		&self.active
+	}
+}
+
+/// The state available to host functions during an invocation of a SPREE module.
+pub struct SpreeModuleHostEnv<'a> {
+	scratch_buf: ScratchBuf,
 	linear_memory: MemoryRef,
 	acc: &'a mut SpreeIcmpAccumulator,
-	storage: &'a mut HashMap<Vec<u8>, Vec<u8>>,
+	/// Whether the inbound messages were consumed by `poll`. They are removed from `acc` only if
+	/// the invocation succeeds.
+	inbound_drained: bool,
+	/// The storage as of the beginning of the invocation.
+	storage: &'a dyn SpreeStorage,
+	/// Changes made to the storage during the invocation. Committed only if the invocation
+	/// succeeds.
+	overlay: StorageOverlay,
+	/// The storage that lives only for the duration of the invocation.
+	ephemeral: HashMap<Vec<u8>, Vec<u8>>,
+	/// The amount of gas left for this invocation, or `None` if the invocation is not metered.
+	gas_left: Option<u64>,
+	/// The time slice of the last successful invocation, if any.
+	last_time_slice: Option<u32>,
+	/// The ID of the parachain that called into this SPREE module.
+	caller_para_id: u32,
+	/// The set of capabilities enabled for the module.
+	capabilities: u64,
+	/// The source of randomness for `random`.
+	rng: Prng,
+	/// The code and the message passed to `abort`, if the module called it.
+	abort: Option<(i32, String)>,
+	/// Whether stored values carry a checksum that is verified on read.
+	verify_storage: bool,
+	/// Whether the storage keys are namespaced by the caller.
+	per_caller_storage: bool,
+	/// If set, `storage_read` of an absent key traps unless the key is in the set.
+	strict_reads: Option<&'a HashSet<Vec<u8>>>,
+	/// The maximum number of host calls allowed during this invocation.
+	max_host_calls: Option<u32>,
+	/// The maximum size of an inbound bundle. Larger bundles are not delivered by `poll`.
+	max_inbound_bundle: Option<u32>,
+	/// The maximum number of `send` calls allowed during this invocation.
+	max_outbound_msgs: Option<u32>,
+	metrics: InvokeMetrics,
+	failures: &'a mut FailureInjector,
+	/// The key used for signing outbound and verifying inbound bundles, if enabled.
+	signing_key: Option<&'a [u8]>,
+	/// Whether the time spent in host functions is measured.
+	profile: bool,
+	/// The host functions the module was instantiated with.
+	host_fns: &'a [HostFunction],
+	/// The indexes of the host functions called so far, if coverage is recorded.
+	touched_host_fns: Option<BTreeSet<usize>>,
+	/// The storage mutations made so far, if the storage history is recorded.
+	storage_history: Option<Vec<StorageOp>>,
 }
 
 impl<'a> SpreeModuleHostEnv<'a> {
-	fn new(
-		blob: Vec<u8>,
-		instance: &ModuleRef,
-		acc: &'a mut SpreeIcmpAccumulator,
-		storage: &'a mut HashMap<Vec<u8>, Vec<u8>>,
-	) -> Result<Self, Error> {
-		Ok(Self {
-			scratch_buf: blob,
-			linear_memory: instance
-				.export_by_name("memory")
-				.ok_or_else(|| {
-					Error::from("spree module expected to have export called `memory`".to_string())
-				})?
-				.as_memory()
-				.ok_or_else(|| {
-					Error::from("spree module: `memory` should be a linear memory".to_string())
-				})?
-				.clone(),
-			acc,
-			storage,
-		})
+	/// The linear memory of the module.
+	pub fn linear_memory(&self) -> &MemoryRef {
+		&self.linear_memory
 	}
-}
 
-impl<'a> Externals for SpreeModuleHostEnv<'a> {
-	fn invoke_index(
-		&mut self,
-		index: usize,
-		args: RuntimeArgs,
-	) -> Result<Option<RuntimeValue>, Trap> {
-		match index {
-			fn_index::SCRATCH_BUF_SIZE => {
-				let size = self.scratch_buf.len();
-				Ok(Some(RuntimeValue::I32(size as i32)))
-			}
-			fn_index::SCRATCH_BUF_READ => {
-				let out_ptr: u32 = args.nth(0);
-				self.linear_memory
-					.set(out_ptr, &self.scratch_buf[..])
-					.map_err(Error::from)?;
-				Ok(None)
+	/// The scratch buffer shared by all host functions within the invocation.
+	pub fn scratch_buf_mut(&mut self) -> &mut Vec<u8> {
+		&mut self.scratch_buf.active
+	}
+
+	/// Charge the given amount of gas, returning an error if there is not enough gas left.
+	fn charge_gas(&mut self, amount: u64) -> Result<(), Error> {
+		let gas_left = match self.gas_left {
+			Some(ref mut gas_left) => gas_left,
+			None => return Ok(()),
+		};
+		match gas_left.checked_sub(amount) {
+			Some(left) => {
+				*gas_left = left;
+				Ok(())
 			}
-			fn_index::SEND => {
-				let recepient: u32 = args.nth(0);
-				let blob_ptr: u32 = args.nth(1);
-				let blob_len: u32 = args.nth(2);
+			None => {
+				*gas_left = 0;
+				Err(Error::OutOfGas)
+			}
+		}
+	}
+
+	/// Returns the value stored under the given key, verifying its checksum if enabled.
+	fn storage_get(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>, Error> {
+		let key = self.storage_key(key);
+		match self.overlay.get(self.storage, &key) {
+			Some(val) if self.verify_storage => storage::unseal(&val)
+				.map(Some)
+				.ok_or(Error::StorageCorruption { key }),
+			val => Ok(val),
+		}
+	}
+
+	/// Store the given value under the given key, along with its checksum if enabled.
+	fn storage_set(&mut self, key: Vec<u8>, val: Vec<u8>) {
+		let val = if self.verify_storage {
+			storage::seal(&val)
+		} else {
+			val
+		};
+		let key = self.storage_key(key);
+		if let Some(ref mut storage_history) = self.storage_history {
+			storage_history.push(StorageOp {
+				old: self.overlay.get(self.storage, &key),
+				new: Some(val.clone()),
+				key: key.clone(),
+			});
+		}
+		self.overlay.set(key, val);
+	}
+
+	/// Record the deletion of all entries which keys start with the given prefix, if the storage
+	/// history is recorded.
+	fn record_clear(&mut self, prefix: &[u8]) {
+		if let Some(ref mut storage_history) = self.storage_history {
+			for (key, val) in self.overlay.scan_prefix(self.storage, prefix) {
+				storage_history.push(StorageOp {
+					key,
+					old: Some(val),
+					new: None,
+				});
+			}
+		}
+	}
+
+	/// Returns the key the given key of the module maps to in the storage.
+	fn storage_key(&self, key: Vec<u8>) -> Vec<u8> {
+		if self.per_caller_storage {
+			storage::caller_key(self.caller_para_id, &key)
+		} else {
+			key
+		}
+	}
+
+	/// Read a UTF-8 string passed by the module.
+	fn read_utf8(&self, ptr: u32, len: u32) -> Result<String, Error> {
+		crate::util::read_utf8(&self.linear_memory, ptr, len)
+	}
+
+	/// Queue the blob at `blob_ptr` for sending to `recepient`.
+	fn send(&mut self, recepient: u32, blob_ptr: u32, blob_len: u32) -> Result<StatusCode, Error> {
+		let status = if self.acc.reserved.contains(&recepient) {
+			StatusCode::ReservedRecepient
+		} else if self.acc.closed.contains(&recepient) {
+			StatusCode::ChannelClosed
+		} else if blob_len == 0 {
+			// An empty blob would be indistinguishable from the absence of a message.
+			StatusCode::EmptyBlob
+		} else if blob_len > MAX_MESSAGE_LEN {
+			StatusCode::TooLarge
+		} else {
+			StatusCode::Ok
+		};
 
+		match status {
+			StatusCode::Ok => {
 				let blob_buf = self
 					.linear_memory
 					.get(blob_ptr, blob_len as usize)
 					.map_err(Error::from)?;
-				match self.acc.outbound.insert(recepient, blob_buf) {
-					Some(_previous) => {
-						// There were an existing message, trap to signal an error.
-						Ok(Some(RuntimeValue::I32(1)))
+				let mut blob_buf = self.acc.compression.compress(&blob_buf);
+				if let Some(key) = self.signing_key {
+					blob_buf = sign(key, blob_buf);
+				}
+				// An existing message is replaced, and the module is told so.
+				if let Some(previous) = self.acc.outbound.insert(recepient, blob_buf) {
+					if let Some(ref mut dropped) = self.acc.dropped {
+						dropped.push((recepient, previous, DropReason::Overwritten));
 					}
-					None => Ok(Some(RuntimeValue::I32(0))),
+					return Ok(StatusCode::AlreadySent);
 				}
 			}
-			fn_index::POLL => {
-				self.scratch_buf = self
-					.acc
-					.inbound
-					.iter()
-					.collect::<Vec<(&u32, &Vec<u8>)>>()
-					.encode();
-				Ok(None)
-			}
-			fn_index::STORAGE_READ => {
-				let key_ptr: u32 = args.nth(0);
-				let key_len: u32 = args.nth(1);
-				let key_buf = self
-					.linear_memory
-					.get(key_ptr, key_len as usize)
-					.map_err(Error::from)?;
-				match self.storage.get(&key_buf) {
-					Some(val_ref) => {
-						self.scratch_buf = val_ref.clone();
-						Ok(Some(RuntimeValue::I32(0)))
+			// There is no message to speak of.
+			StatusCode::EmptyBlob => {}
+			code => {
+				let undeliverable = matches!(
+					code,
+					StatusCode::ReservedRecepient | StatusCode::ChannelClosed
+				);
+				let dead_letter = undeliverable && self.acc.dead_letters.is_some();
+				if self.acc.dropped.is_some() || dead_letter {
+					let blob_buf = self
+						.linear_memory
+						.get(blob_ptr, blob_len as usize)
+						.map_err(Error::from)?;
+					if let (true, Some(dead_letters)) = (dead_letter, &mut self.acc.dead_letters) {
+						dead_letters.push((recepient, blob_buf.clone()));
+					}
+					if let Some(ref mut dropped) = self.acc.dropped {
+						dropped.push((recepient, blob_buf, DropReason::Rejected(code)));
 					}
-					None => Ok(Some(RuntimeValue::I32(1))),
 				}
 			}
-			fn_index::STORAGE_WRITE => {
-				let key_ptr: u32 = args.nth(0);
-				let key_len: u32 = args.nth(1);
-				let val_ptr: u32 = args.nth(2);
-				let val_len: u32 = args.nth(3);
+		}
+		Ok(status)
+	}
+}
 
-				let key_buf = self
-					.linear_memory
-					.get(key_ptr, key_len as usize)
-					.map_err(Error::from)?;
-				let val_buf = self
-					.linear_memory
-					.get(val_ptr, val_len as usize)
-					.map_err(Error::from)?;
-				self.storage.insert(key_buf, val_buf);
-				Ok(None)
+/// Describe why sending a blob of `blob_len` bytes to `recepient` failed with `code`.
+fn send_failure_reason(code: StatusCode, recepient: u32, blob_len: u32) -> String {
+	match code {
+		StatusCode::ReservedRecepient => format!("parachain {} is reserved", recepient),
+		StatusCode::EmptyBlob => "the message is empty".to_string(),
+		StatusCode::TooLarge => format!(
+			"the message is {} bytes long, while at most {} bytes are allowed",
+			blob_len, MAX_MESSAGE_LEN,
+		),
+		StatusCode::AlreadySent => format!(
+			"a message to parachain {} was already sent and is replaced",
+			recepient
+		),
+		code => format!("sending failed with {:?}", code),
+	}
+}
+
+impl<'a> fmt::Debug for SpreeModuleHostEnv<'a> {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.debug_struct("SpreeModuleHostEnv")
+			.field("scratch_buf_len", &self.scratch_buf.len())
+			.field("acc", &self.acc)
+			.field("gas_left", &self.gas_left)
+			.field("caller_para_id", &self.caller_para_id)
+			.field("capabilities", &format_args!("{:#x}", self.capabilities))
+			.field("verify_storage", &self.verify_storage)
+			.finish()
+	}
+}
+
+impl<'a> Externals for SpreeModuleHostEnv<'a> {
+	fn invoke_index(
+		&mut self,
+		index: usize,
+		args: RuntimeArgs,
+	) -> Result<Option<RuntimeValue>, Trap> {
+		self.charge_gas(HOST_CALL_GAS_COST)?;
+		self.metrics.host_calls += 1;
+		if let Some(ref mut touched_host_fns) = self.touched_host_fns {
+			touched_host_fns.insert(index);
+		}
+		if let Some(limit) = self.max_host_calls {
+			if self.metrics.host_calls > limit {
+				return Err(Error::HostCallLimitExceeded { limit }.into());
+			}
+		}
+		let host_fn = *self.host_fns.get(index).expect("unknown function index");
+		match self.failures.check(host_fn.name) {
+			Some(InjectedFailure::Status(code)) if host_fn.ret == Some(ValueType::I32) => {
+				return Ok(Some(RuntimeValue::I32(code.as_i32())));
+			}
+			Some(_) => {
+				return Err(Error::InjectedFailure {
+					fn_name: host_fn.name.to_string(),
+				}
+				.into());
+			}
+			None => {}
+		}
+
+		let started = if self.profile {
+			Some(Instant::now())
+		} else {
+			None
+		};
+		let result = (host_fn.handler)(self, args);
+		if let Some(started) = started {
+			*self.metrics.host_fn_time.entry(host_fn.name).or_default() += started.elapsed();
+		}
+		result
+	}
+}
+
+/// Implementations of the builtin host functions.
+mod handlers {
+	use super::*;
+
+	pub(super) fn scratch_buf_size(env: &mut SpreeModuleHostEnv, _args: RuntimeArgs) -> HostResult {
+		let size = env.scratch_buf.len();
+		Ok(Some(RuntimeValue::I32(size as i32)))
+	}
+
+	pub(super) fn scratch_buf_read(env: &mut SpreeModuleHostEnv, args: RuntimeArgs) -> HostResult {
+		let out_ptr: u32 = args.nth(0);
+		env.linear_memory
+			.set(out_ptr, &env.scratch_buf[..])
+			.map_err(Error::from)?;
+		env.metrics.scratch_bytes += env.scratch_buf.len() as u64;
+		Ok(None)
+	}
+
+	pub(super) fn scratch_buf_read_len(
+		env: &mut SpreeModuleHostEnv,
+		args: RuntimeArgs,
+	) -> HostResult {
+		scratch_buf_read(env, args)?;
+		Ok(Some(RuntimeValue::I32(env.scratch_buf.len() as i32)))
+	}
+
+	pub(super) fn scratch_buf_read_at(
+		env: &mut SpreeModuleHostEnv,
+		args: RuntimeArgs,
+	) -> HostResult {
+		let out_ptr: u32 = args.nth(0);
+		let offset: u32 = args.nth(1);
+		let len: u32 = args.nth(2);
+
+		// Clamp the requested region to the bounds of the scratch buffer.
+		let start = (offset as usize).min(env.scratch_buf.len());
+		let end = start
+			.saturating_add(len as usize)
+			.min(env.scratch_buf.len());
+		env.linear_memory
+			.set(out_ptr, &env.scratch_buf[start..end])
+			.map_err(Error::from)?;
+		env.metrics.scratch_bytes += (end - start) as u64;
+		Ok(Some(RuntimeValue::I32((end - start) as i32)))
+	}
+
+	pub(super) fn scratch_buf_write(env: &mut SpreeModuleHostEnv, args: RuntimeArgs) -> HostResult {
+		let blob_ptr: u32 = args.nth(0);
+		let blob_len: u32 = args.nth(1);
+		let linear_memory = &env.linear_memory;
+		env.scratch_buf.try_replace(|buf| {
+			buf.resize(blob_len as usize, 0);
+			linear_memory.get_into(blob_ptr, buf).map_err(Error::from)
+		})?;
+		env.metrics.scratch_bytes += blob_len as u64;
+		Ok(None)
+	}
+
+	pub(super) fn send(env: &mut SpreeModuleHostEnv, args: RuntimeArgs) -> HostResult {
+		let recepient: u32 = args.nth(0);
+		let blob_ptr: u32 = args.nth(1);
+		let blob_len: u32 = args.nth(2);
+
+		env.metrics.sends += 1;
+		if let Some(limit) = env.max_outbound_msgs {
+			if env.metrics.sends > limit {
+				return Err(Error::OutboundLimitExceeded { limit }.into());
+			}
+		}
+
+		let status = env.send(recepient, blob_ptr, blob_len)?;
+		// Only failures are detailed, a successful send leaves the scratch buffer intact.
+		if status != StatusCode::Ok {
+			let send_error = SendError {
+				code: status.as_i32(),
+				recepient,
+				reason: send_failure_reason(status, recepient, blob_len),
+			};
+			env.scratch_buf.replace(|buf| send_error.encode_to(buf));
+		}
+		Ok(Some(RuntimeValue::I32(status.as_i32())))
+	}
+
+	pub(super) fn poll(env: &mut SpreeModuleHostEnv, _args: RuntimeArgs) -> HostResult {
+		fill_with_inbound(env)?;
+		env.inbound_drained = true;
+		Ok(None)
+	}
+
+	pub(super) fn poll_peek(env: &mut SpreeModuleHostEnv, _args: RuntimeArgs) -> HostResult {
+		fill_with_inbound(env)?;
+		Ok(None)
+	}
+
+	/// Fill the scratch buffer with the encoded inbound bundles as `Vec<(sender, blob)>`.
+	///
+	/// Nothing is left to deliver once the inbound messages were consumed by `poll`.
+	fn fill_with_inbound(env: &mut SpreeModuleHostEnv) -> Result<(), Error> {
+		if env.inbound_drained {
+			env.scratch_buf
+				.replace(|buf| encode_inbound::<WireParaId, _>(&[], buf));
+			return Ok(());
+		}
+		let compression = env.acc.compression;
+		let signing_key = env.signing_key;
+		let max_bundle = env
+			.max_inbound_bundle
+			.map_or(usize::MAX, |max| max as usize);
+		let mut inbound = Vec::with_capacity(env.acc.inbound.len());
+		for (sender, blob) in &env.acc.inbound {
+			if blob.len() > max_bundle {
+				reject_inbound(&mut env.metrics, *sender);
+				continue;
+			}
+			let blob = match signing_key {
+				Some(key) => verify(key, blob).ok_or(Error::BadSignature { sender: *sender })?,
+				None => blob.clone(),
+			};
+			let blob = compression.decompress(&blob)?;
+			if blob.len() > max_bundle {
+				reject_inbound(&mut env.metrics, *sender);
+				continue;
+			}
+			inbound.push((*sender, blob));
+		}
+		env.scratch_buf
+			.replace(|buf| encode_inbound::<WireParaId, _>(&inbound, buf));
+		env.metrics.scratch_bytes += env.scratch_buf.len() as u64;
+		Ok(())
+	}
+
+	/// Record the rejection of the bundle from the given sender, once per invocation.
+	fn reject_inbound(metrics: &mut InvokeMetrics, sender: u32) {
+		if !metrics.rejected_inbound.contains(&sender) {
+			metrics.rejected_inbound.push(sender);
+		}
+	}
+
+	pub(super) fn storage_read(env: &mut SpreeModuleHostEnv, args: RuntimeArgs) -> HostResult {
+		let key_ptr: u32 = args.nth(0);
+		let key_len: u32 = args.nth(1);
+		let key_buf = env
+			.linear_memory
+			.get(key_ptr, key_len as usize)
+			.map_err(Error::from)?;
+		match env.storage_get(key_buf.clone())? {
+			Some(val) => {
+				env.scratch_buf.replace(|buf| buf.extend_from_slice(&val));
+				Ok(Some(RuntimeValue::I32(StatusCode::Ok.as_i32())))
+			}
+			None => match env.strict_reads {
+				Some(allowed) if !allowed.contains(&key_buf) => {
+					Err(Error::UnknownStorageKey { key: key_buf }.into())
+				}
+				_ => Ok(Some(RuntimeValue::I32(StatusCode::NotFound.as_i32()))),
+			},
+		}
+	}
+
+	pub(super) fn storage_write(env: &mut SpreeModuleHostEnv, args: RuntimeArgs) -> HostResult {
+		let key_ptr: u32 = args.nth(0);
+		let key_len: u32 = args.nth(1);
+		let val_ptr: u32 = args.nth(2);
+		let val_len: u32 = args.nth(3);
+
+		let key_buf = env
+			.linear_memory
+			.get(key_ptr, key_len as usize)
+			.map_err(Error::from)?;
+		let val_buf = env
+			.linear_memory
+			.get(val_ptr, val_len as usize)
+			.map_err(Error::from)?;
+		env.storage_set(key_buf, val_buf);
+		Ok(None)
+	}
+
+	pub(super) fn memory_size_pages(
+		env: &mut SpreeModuleHostEnv,
+		_args: RuntimeArgs,
+	) -> HostResult {
+		let Pages(pages) = env.linear_memory.current_size();
+		Ok(Some(RuntimeValue::I32(pages as i32)))
+	}
+
+	pub(super) fn memory_grow(env: &mut SpreeModuleHostEnv, args: RuntimeArgs) -> HostResult {
+		let delta: u32 = args.nth(0);
+		// Like `memory.grow`, return the previous size in pages or -1 on failure.
+		let prev_pages = match env.linear_memory.grow(Pages(delta as usize)) {
+			Ok(Pages(prev_pages)) => prev_pages as i32,
+			Err(_) => -1,
+		};
+		Ok(Some(RuntimeValue::I32(prev_pages)))
+	}
+
+	pub(super) fn ephemeral_read(env: &mut SpreeModuleHostEnv, args: RuntimeArgs) -> HostResult {
+		let key_ptr: u32 = args.nth(0);
+		let key_len: u32 = args.nth(1);
+		let key_buf = env
+			.linear_memory
+			.get(key_ptr, key_len as usize)
+			.map_err(Error::from)?;
+		match env.ephemeral.get(&key_buf) {
+			Some(val) => {
+				env.scratch_buf.replace(|buf| buf.extend_from_slice(val));
+				Ok(Some(RuntimeValue::I32(StatusCode::Ok.as_i32())))
 			}
-			_ => panic!("unknown function index"),
+			None => Ok(Some(RuntimeValue::I32(StatusCode::NotFound.as_i32()))),
+		}
+	}
+
+	pub(super) fn ephemeral_write(env: &mut SpreeModuleHostEnv, args: RuntimeArgs) -> HostResult {
+		let key_ptr: u32 = args.nth(0);
+		let key_len: u32 = args.nth(1);
+		let val_ptr: u32 = args.nth(2);
+		let val_len: u32 = args.nth(3);
+
+		let key_buf = env
+			.linear_memory
+			.get(key_ptr, key_len as usize)
+			.map_err(Error::from)?;
+		let val_buf = env
+			.linear_memory
+			.get(val_ptr, val_len as usize)
+			.map_err(Error::from)?;
+		env.ephemeral.insert(key_buf, val_buf);
+		Ok(None)
+	}
+
+	pub(super) fn storage_increment(env: &mut SpreeModuleHostEnv, args: RuntimeArgs) -> HostResult {
+		let key_ptr: u32 = args.nth(0);
+		let key_len: u32 = args.nth(1);
+		let delta: u64 = args.nth(2);
+
+		let key_buf = env
+			.linear_memory
+			.get(key_ptr, key_len as usize)
+			.map_err(Error::from)?;
+		let current = match env.storage_get(key_buf.clone())? {
+			Some(val) => u64::decode(&mut &val[..]).map_err(|e| {
+				Error::Msg(format!(
+					"the counter under {:?} is malformed: {}",
+					key_buf, e
+				))
+			})?,
+			None => 0,
+		};
+		let next = current
+			.checked_add(delta)
+			.ok_or_else(|| Error::Msg(format!("the counter under {:?} overflowed", key_buf)))?;
+		env.storage_set(key_buf, next.encode());
+		Ok(Some(RuntimeValue::I64(next as i64)))
+	}
+
+	pub(super) fn gas_left(env: &mut SpreeModuleHostEnv, _args: RuntimeArgs) -> HostResult {
+		let gas_left = env.gas_left.unwrap_or(u64::MAX);
+		Ok(Some(RuntimeValue::I64(gas_left as i64)))
+	}
+
+	pub(super) fn last_time_slice(env: &mut SpreeModuleHostEnv, _args: RuntimeArgs) -> HostResult {
+		let last_time_slice = env.last_time_slice.map_or(-1, i64::from);
+		Ok(Some(RuntimeValue::I64(last_time_slice)))
+	}
+
+	pub(super) fn host_capabilities(
+		env: &mut SpreeModuleHostEnv,
+		_args: RuntimeArgs,
+	) -> HostResult {
+		Ok(Some(RuntimeValue::I64(env.capabilities as i64)))
+	}
+
+	pub(super) fn abort(env: &mut SpreeModuleHostEnv, args: RuntimeArgs) -> HostResult {
+		let code: i32 = args.nth(0);
+		let msg_ptr: u32 = args.nth(1);
+		let msg_len: u32 = args.nth(2);
+		let message = env.read_utf8(msg_ptr, msg_len)?;
+		env.abort = Some((code, message.clone()));
+		Err(Error::ModuleAbort { code, message }.into())
+	}
+
+	pub(super) fn random(env: &mut SpreeModuleHostEnv, args: RuntimeArgs) -> HostResult {
+		let out_ptr: u32 = args.nth(0);
+		let len: u32 = args.nth(1);
+		let mut random_buf = vec![0; len as usize];
+		env.rng.fill_bytes(&mut random_buf);
+		env.linear_memory
+			.set(out_ptr, &random_buf)
+			.map_err(Error::from)?;
+		Ok(None)
+	}
+
+	pub(super) fn caller_para_id(env: &mut SpreeModuleHostEnv, _args: RuntimeArgs) -> HostResult {
+		Ok(Some(RuntimeValue::I32(env.caller_para_id as i32)))
+	}
+
+	pub(super) fn storage_clear(env: &mut SpreeModuleHostEnv, _args: RuntimeArgs) -> HostResult {
+		if env.per_caller_storage {
+			// Only wipe the storage of the caller, leaving the others intact.
+			let prefix = storage::caller_prefix(env.caller_para_id);
+			env.record_clear(&prefix);
+			env.overlay.clear_prefix(env.storage, &prefix);
+		} else {
+			env.record_clear(&[]);
+			env.overlay.clear();
+		}
+		Ok(None)
+	}
+}
+
+fn is_stack_overflow(trap: &wasmi::Trap) -> bool {
+	matches!(trap.kind(), TrapKind::StackOverflow)
+}
+
+/// A simple deterministic pseudo-random number generator (SplitMix64).
+///
+/// It is not cryptographically secure and only meant for providing reproducible randomness in
+/// simulations.
+struct Prng(u64);
+
+impl Prng {
+	fn next_u64(&mut self) -> u64 {
+		self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+		let mut z = self.0;
+		z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+		z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+		z ^ (z >> 31)
+	}
+
+	fn fill_bytes(&mut self, buf: &mut [u8]) {
+		for chunk in buf.chunks_mut(8) {
+			let random = self.next_u64().to_le_bytes();
+			chunk.copy_from_slice(&random[..chunk.len()]);
+		}
+	}
+}
+
+/// Compression applied to message blobs on the wire.
+///
+/// This is transparent to SPREE modules: outbound blobs are compressed upon `send` and inbound
+/// blobs are decompressed upon `poll`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+	None,
+	Lz4,
+}
+
+impl Compression {
+	fn compress(self, blob: &[u8]) -> Vec<u8> {
+		match self {
+			Compression::None => blob.to_vec(),
+			Compression::Lz4 => lz4_flex::compress_prepend_size(blob),
+		}
+	}
+
+	fn decompress(self, blob: &[u8]) -> Result<Vec<u8>, Error> {
+		match self {
+			Compression::None => Ok(blob.to_vec()),
+			Compression::Lz4 => lz4_flex::decompress_size_prepended(blob)
+				.map_err(|e| Error::Msg(format!("failed to decompress a blob: {:?}", e))),
 		}
 	}
 }
 
+type HmacSha256 = Hmac<Sha256>;
+
+/// The length of the tag prepended to signed blobs.
+const SIGNATURE_LEN: usize = 32;
+
+/// Prepend the HMAC-SHA256 tag of the blob computed with the given key.
+fn sign(key: &[u8], blob: Vec<u8>) -> Vec<u8> {
+	let mut mac = HmacSha256::new_varkey(key).expect("HMAC accepts keys of any length; qed");
+	mac.input(&blob);
+	let mut signed = mac.result().code().to_vec();
+	signed.extend(blob);
+	signed
+}
+
+/// Verify and strip the tag prepended by `sign`.
+///
+/// Returns `None` if the tag doesn't match the blob.
+fn verify(key: &[u8], signed: &[u8]) -> Option<Vec<u8>> {
+	if signed.len() < SIGNATURE_LEN {
+		return None;
+	}
+	let (tag, blob) = signed.split_at(SIGNATURE_LEN);
+	let mut mac = HmacSha256::new_varkey(key).expect("HMAC accepts keys of any length; qed");
+	mac.input(blob);
+	mac.verify(tag).ok().map(|()| blob.to_vec())
+}
+
+/// The reason a message was dropped.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DropReason {
+	/// The module tried to send the message, but `send` failed with the given status code.
+	Rejected(StatusCode),
+	/// The message was sent, but then replaced by another message to the same recepient.
+	Overwritten,
+	/// The message was sent, but there was nobody to deliver it to in `route_messages`.
+	Unroutable,
+}
+
 /// Accumulator of inbound and outbound messages for a SPREE module instance.
+///
+/// Both inbound and outbound messages are stored as they appear on the wire, i.e. compressed with
+/// the configured compression.
 pub struct SpreeIcmpAccumulator {
 	inbound: HashMap<u32, Vec<u8>>,
 	outbound: HashMap<u32, Vec<u8>>,
+	compression: Compression,
+	/// IDs of the parachains that messages can't be sent to.
+	reserved: HashSet<u32>,
+	/// IDs of the parachains whose channels are closed.
+	closed: HashSet<u32>,
+	/// Messages that were dropped, if recording them is enabled.
+	dropped: Option<Vec<(u32, Vec<u8>, DropReason)>>,
+	/// Messages that can never be delivered, if dead-lettering is enabled.
+	dead_letters: Option<Vec<(u32, Vec<u8>)>>,
 }
 
 impl SpreeIcmpAccumulator {
@@ -182,15 +1048,156 @@ impl SpreeIcmpAccumulator {
 		Self {
 			inbound: inbound.into_iter().collect(),
 			outbound: HashMap::new(),
+			compression: Compression::None,
+			reserved: HashSet::new(),
+			closed: HashSet::new(),
+			dropped: None,
+			dead_letters: None,
 		}
 	}
+
+	/// Set the IDs of the parachains that messages can't be sent to.
+	pub fn with_reserved_para_ids(mut self, reserved: impl IntoIterator<Item = u32>) -> Self {
+		self.reserved = reserved.into_iter().collect();
+		self
+	}
+
+	/// Set the IDs of the parachains whose channels are closed.
+	///
+	/// Sending to them fails with `StatusCode::ChannelClosed`.
+	pub fn with_closed_channels(mut self, closed: impl IntoIterator<Item = u32>) -> Self {
+		self.closed = closed.into_iter().collect();
+		self
+	}
+
+	/// Set the compression used for the messages on the wire.
+	pub fn with_compression(mut self, compression: Compression) -> Self {
+		self.compression = compression;
+		self
+	}
+
+	/// Record the messages that are dropped instead of being delivered.
+	///
+	/// Messages rejected by `send` are recorded as passed by the module, while the overwritten and
+	/// unroutable ones as they appear on the wire.
+	pub fn with_drop_recording(mut self) -> Self {
+		self.dropped = Some(Vec::new());
+		self
+	}
+
+	/// Keep the messages that can never be delivered, e.g. because the recepient is reserved, in
+	/// a dead-letter queue for later inspection or redelivery.
+	pub fn with_dead_letters(mut self) -> Self {
+		self.dead_letters = Some(Vec::new());
+		self
+	}
+
+	/// Returns the messages in the dead-letter queue as `(recepient, blob)`, in the order they
+	/// were sent.
+	///
+	/// Always empty unless enabled with `with_dead_letters`.
+	pub fn dead_letters(&self) -> &[(u32, Vec<u8>)] {
+		self.dead_letters.as_deref().unwrap_or(&[])
+	}
+
+	/// Returns the dropped messages as `(recepient, blob, reason)`.
+	///
+	/// Always empty unless enabled with `with_drop_recording`.
+	pub fn dropped(&self) -> &[(u32, Vec<u8>, DropReason)] {
+		self.dropped.as_deref().unwrap_or(&[])
+	}
+}
+
+/// Formats messages showing only the lengths of the blobs instead of the full contents.
+struct BlobLens<'a>(&'a HashMap<u32, Vec<u8>>);
+
+impl<'a> fmt::Debug for BlobLens<'a> {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.debug_map()
+			.entries(self.0.iter().map(|(para_id, blob)| (para_id, blob.len())))
+			.finish()
+	}
+}
+
+impl fmt::Debug for SpreeIcmpAccumulator {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.debug_struct("SpreeIcmpAccumulator")
+			.field("inbound", &BlobLens(&self.inbound))
+			.field("outbound", &BlobLens(&self.outbound))
+			.field("compression", &self.compression)
+			.field("reserved", &self.reserved)
+			.field("closed", &self.closed)
+			.field("dropped", &self.dropped().len())
+			.field("dead_letters", &self.dead_letters().len())
+			.finish()
+	}
 }
 
 pub struct SpreeModule {
 	wasm_path: String,
 	acc: SpreeIcmpAccumulator,
+	/// The parsed wasm module, if it was loaded upfront by `new_validated` or shared by
+	/// `SpreeRegistry`.
+	module: Option<Rc<Module>>,
 	instance: Option<ModuleRef>,
-	storage: HashMap<Vec<u8>, Vec<u8>>,
+	storage: Box<dyn SpreeStorage>,
+	/// Contents of the scratch buffer at the end of the last successful invocation.
+	last_scratch: Vec<u8>,
+	/// The set of capabilities enabled for the module.
+	capabilities: u64,
+	/// The name under which the module exports its linear memory.
+	memory_export: String,
+	/// The seed for the source of randomness provided to the module.
+	random_seed: u64,
+	/// The number of invocations of this module so far. Used for deriving a distinct random seed
+	/// for each invocation.
+	invocations: u64,
+	/// Inbound messages to expose to the invocations at the given time slices.
+	inbound_schedule: BTreeMap<u64, Vec<(u32, Vec<u8>)>>,
+	/// Whether stored values carry a checksum that is verified on read.
+	verify_storage: bool,
+	/// Whether the storage is namespaced by the caller.
+	per_caller_storage: bool,
+	/// The absent keys the module may read in the strict storage mode, if enabled.
+	strict_reads: Option<HashSet<Vec<u8>>>,
+	/// Metrics collected during the last invocation.
+	last_metrics: InvokeMetrics,
+	/// Whether `spree_init` has been run successfully.
+	initialized: bool,
+	/// The callers on behalf of which `spree_init` has been run successfully, if the storage is
+	/// namespaced by the caller.
+	initialized_callers: BTreeSet<u32>,
+	/// The amount of gas given to every invocation, if limited.
+	gas_limit: Option<u64>,
+	/// The maximum number of host calls allowed per invocation.
+	max_host_calls: Option<u32>,
+	/// The maximum size of an inbound bundle delivered to the module.
+	max_inbound_bundle: Option<u32>,
+	/// The maximum number of `send` calls allowed per invocation.
+	max_outbound_msgs: Option<u32>,
+	/// Failures to inject into the host functions.
+	failures: FailureInjector,
+	/// The key for signing the bundles exchanged with the doppelgangers.
+	signing_key: Option<Vec<u8>>,
+	/// Whether the time spent in host functions is measured.
+	profile: bool,
+	/// The capacity reserved for the scratch buffer at the start of every invocation.
+	scratch_capacity: usize,
+	/// The host functions available to the module.
+	host_fns: Vec<HostFunction>,
+	/// The time the first instantiation of the module took.
+	instantiation_time: Option<Duration>,
+	/// The time slice of the last successful invocation, kept by the host rather than in the
+	/// storage of the module.
+	last_time_slice: Option<u32>,
+	/// Whether the coverage of invocations is recorded.
+	record_coverage: bool,
+	/// The coverage of the last invocation, if recorded.
+	last_coverage: Option<Coverage>,
+	/// The maximum depth of the call stack, if different from the default of the interpreter.
+	max_call_depth: Option<usize>,
+	/// The storage mutations made by all successful invocations so far, if recorded.
+	storage_history: Option<Vec<StorageOp>>,
 }
 
 impl SpreeModule {
@@ -198,40 +1205,1258 @@ impl SpreeModule {
 		Self {
 			wasm_path: wasm_path.into(),
 			acc,
+			module: None,
 			instance: None,
-			storage: HashMap::default(),
+			storage: Box::new(HashMap::new()),
+			last_scratch: Vec::new(),
+			capabilities: capabilities::ALL & !capabilities::STORAGE_CLEAR,
+			memory_export: crate::util::DEFAULT_MEMORY_EXPORT.to_string(),
+			random_seed: 0,
+			invocations: 0,
+			inbound_schedule: BTreeMap::new(),
+			verify_storage: false,
+			per_caller_storage: false,
+			strict_reads: None,
+			last_metrics: InvokeMetrics::default(),
+			initialized: false,
+			initialized_callers: BTreeSet::new(),
+			gas_limit: None,
+			max_host_calls: None,
+			max_inbound_bundle: None,
+			max_outbound_msgs: None,
+			failures: FailureInjector::default(),
+			signing_key: None,
+			profile: false,
+			scratch_capacity: 0,
+			host_fns: BUILTIN_HOST_FNS.to_vec(),
+			instantiation_time: None,
+			last_time_slice: None,
+			record_coverage: false,
+			max_call_depth: None,
+			last_coverage: None,
+			storage_history: None,
 		}
 	}
 
-	pub fn invoke(&mut self, time_slice: u32, blob: Vec<u8>) -> Result<(), Error> {
-		let instance = ensure_instance(&self.wasm_path, &mut self.instance)?;
+	/// Like `new`, but reads and parses the wasm module immediately, failing if it is missing or
+	/// invalid.
+	///
+	/// The instantiation is still deferred until the first invocation. The parsed module is kept
+	/// and reused for every instantiation.
+	pub fn new_validated(
+		wasm_path: impl Into<String>,
+		acc: SpreeIcmpAccumulator,
+	) -> Result<Self, Error> {
+		let mut spree_module = Self::new(wasm_path, acc);
+		spree_module.module = Some(Rc::new(crate::util::load_wasm_module(
+			&spree_module.wasm_path,
+		)?));
+		Ok(spree_module)
+	}
 
-		let mut env = SpreeModuleHostEnv::new(blob, &instance, &mut self.acc, &mut self.storage)?;
-		instance
-			.invoke_export("handle", &[RuntimeValue::I32(time_slice as i32)], &mut env)
-			.map_err(Error::from)?;
-		Ok(())
+	/// Set the name under which the module exports its linear memory.
+	///
+	/// Defaults to `memory`.
+	pub fn with_memory_export(mut self, memory_export: impl Into<String>) -> Self {
+		self.memory_export = memory_export.into();
+		self
+	}
+
+	/// Set the seed for the source of randomness provided to the module.
+	///
+	/// Modules with the same seed observe the same random bytes given the same sequence of
+	/// invocations.
+	pub fn with_random_seed(mut self, random_seed: u64) -> Self {
+		self.random_seed = random_seed;
+		self
+	}
+
+	/// Set the inbound messages exposed to the invocations at specific time slices.
+	///
+	/// When the module is invoked at a time slice that has an entry in the schedule, the inbound
+	/// messages of the accumulator are replaced with the scheduled ones before the invocation.
+	/// Invocations at other time slices see the inbound messages as they were left. Like in
+	/// `SpreeIcmpAccumulator`, the blobs are expected as they appear on the wire.
+	pub fn with_inbound_schedule(
+		mut self,
+		inbound_schedule: BTreeMap<u64, Vec<(u32, Vec<u8>)>>,
+	) -> Self {
+		self.inbound_schedule = inbound_schedule;
+		self
+	}
+
+	/// Store a checksum along with every value written by the module and verify it on read.
+	///
+	/// A mismatch fails the invocation with `Error::StorageCorruption`. The checksum is
+	/// transparent to the module, but it is visible in the underlying storage, so this should be
+	/// set before the module writes anything. Disabled by default.
+	pub fn with_storage_verification(mut self, verify_storage: bool) -> Self {
+		self.verify_storage = verify_storage;
+		self
+	}
+
+	/// Make `storage_read` of an absent key trap with `Error::UnknownStorageKey`.
+	///
+	/// This surfaces reads of keys the module never wrote, which are likely logic errors. The
+	/// keys that are legitimately read before being written, e.g. the ones read on the first use,
+	/// should be listed in `allowed_absent`. Disabled by default.
+	pub fn with_strict_storage_reads(
+		mut self,
+		allowed_absent: impl IntoIterator<Item = Vec<u8>>,
+	) -> Self {
+		self.strict_reads = Some(allowed_absent.into_iter().collect());
+		self
+	}
+
+	/// Isolate the storage of every parachain calling into the module.
+	///
+	/// This allows a single module to serve several parachains, each of which sees only its own
+	/// state. The keys of the module are prefixed with the ID of the caller in the underlying
+	/// storage, see `storage::caller_key`, so this should be set before the module writes
+	/// anything. `spree_init` runs once per caller, within the first invocation by that caller, so
+	/// every caller starts from the genesis state. Disabled by default.
+	pub fn with_per_caller_storage(mut self, per_caller_storage: bool) -> Self {
+		self.per_caller_storage = per_caller_storage;
+		self
+	}
+
+	/// Limit the amount of gas every invocation of the module can spend.
+	///
+	/// Every host call costs `HOST_CALL_GAS_COST`. An invocation that runs out of gas fails with
+	/// `Error::OutOfGas`. The gas left is reported to the module by `gas_left`. Unlimited by
+	/// default, in which case `gas_left` reports `u64::MAX`.
+	pub fn with_gas_limit(mut self, gas_limit: u64) -> Self {
+		self.gas_limit = Some(gas_limit);
+		self
+	}
+
+	/// Limit the number of host calls the module can make per invocation.
+	///
+	/// An invocation that exceeds the limit fails with `Error::HostCallLimitExceeded`. This is a
+	/// rough guard against modules hammering the host, independent of gas. Unlimited by default.
+	pub fn with_max_host_calls(mut self, max_host_calls: u32) -> Self {
+		self.max_host_calls = Some(max_host_calls);
+		self
+	}
+
+	/// Limit the number of `send` calls the module can make per invocation.
+	///
+	/// An invocation that exceeds the limit fails with `Error::OutboundLimitExceeded`. This bounds
+	/// the damage a buggy fan out can do. Note that the messages sent before the limit was hit
+	/// are not retracted. Unlimited by default.
+	pub fn with_max_outbound_msgs(mut self, max_outbound_msgs: u32) -> Self {
+		self.max_outbound_msgs = Some(max_outbound_msgs);
+		self
+	}
+
+	/// Limit the size of the inbound bundles delivered to the module.
+	///
+	/// `poll` skips the bundles that exceed the limit either as received or once decompressed, so
+	/// the module never has to decode unbounded data. The senders of the skipped bundles are
+	/// reported in `InvokeMetrics::rejected_inbound`. Unlimited by default.
+	pub fn with_max_inbound_bundle(mut self, max_inbound_bundle: u32) -> Self {
+		self.max_inbound_bundle = Some(max_inbound_bundle);
+		self
+	}
+
+	/// Sign outbound bundles and verify the signatures of inbound bundles with the given key.
+	///
+	/// The signature is an HMAC tag prepended to the bundle on the wire. Polling an inbound bundle
+	/// with a bad signature fails the invocation with `Error::BadSignature`. The doppelgangers
+	/// are expected to be configured with the same key. Disabled by default.
+	pub fn with_signing_key(mut self, key: impl Into<Vec<u8>>) -> Self {
+		self.signing_key = Some(key.into());
+		self
+	}
+
+	/// Reserve the given capacity for the scratch buffer at the start of every invocation.
+	///
+	/// The scratch buffer is reused by all host functions within an invocation, so reserving
+	/// enough capacity for the largest contents avoids reallocations. Defaults to 0.
+	pub fn with_scratch_capacity(mut self, scratch_capacity: usize) -> Self {
+		self.scratch_capacity = scratch_capacity;
+		self
+	}
+
+	/// Measure the time spent in every host function.
+	///
+	/// The measurements are reported in `InvokeMetrics::host_fn_time`. Disabled by default.
+	pub fn with_profiling(mut self, profile: bool) -> Self {
+		self.profile = profile;
+		self
+	}
+
+	/// Make the given function available to the module in addition to the builtin ones.
+	///
+	/// A function with the same name and signature as an already available one replaces it. Note
+	/// that this doesn't affect an already instantiated module.
+	pub fn with_host_function(mut self, host_fn: HostFunction) -> Self {
+		match self
+			.host_fns
+			.iter_mut()
+			.find(|f| f.name == host_fn.name && f.signature() == host_fn.signature())
+		{
+			Some(existing) => *existing = host_fn,
+			None => self.host_fns.push(host_fn),
+		}
+		self
+	}
+
+	/// Record which exports and host functions every invocation touches.
+	///
+	/// This is a coarse substitute for the coverage of the module code. The record of the last
+	/// invocation is available via `last_coverage`. Disabled by default.
+	pub fn with_coverage(mut self, record_coverage: bool) -> Self {
+		self.record_coverage = record_coverage;
+		self
+	}
+
+	/// Record every storage mutation made by the module, see `storage_history`. Disabled by
+	/// default.
+	pub fn with_storage_history(mut self, record: bool) -> Self {
+		self.storage_history = if record { Some(Vec::new()) } else { None };
+		self
+	}
+
+	/// Limit the depth of the call stack of the module.
+	///
+	/// A module that recurses deeper fails the invocation with `Error::StackOverflow`. Defaults
+	/// to the limit of the interpreter.
+	pub fn with_max_call_depth(mut self, max_call_depth: usize) -> Self {
+		self.max_call_depth = Some(max_call_depth);
+		self
+	}
+
+	/// Make the host functions fail as configured by the given injector.
+	///
+	/// This is useful for exercising the error paths of the module.
+	pub fn with_failure_injector(mut self, failures: FailureInjector) -> Self {
+		self.failures = failures;
+		self
+	}
+
+	/// Like `with_failure_injector`, but for a module that is already in use, e.g. a part of a
+	/// scenario.
+	///
+	/// The calls are counted from the moment the injector is set.
+	pub fn set_failure_injector(&mut self, failures: FailureInjector) {
+		self.failures = failures;
+	}
+
+	/// Set the capabilities enabled for the module.
+	///
+	/// A module that imports a function that requires a disabled capability fails to instantiate.
+	/// By default, all capabilities except `STORAGE_CLEAR` are enabled.
+	pub fn with_capabilities(mut self, capabilities: u64) -> Self {
+		self.capabilities = capabilities;
+		self
+	}
+
+	/// Allow or disallow the module to wipe its own storage with `storage_clear`.
+	///
+	/// Disallowed by default. Disallowing drops the cached instance, since the capability is
+	/// checked when the imports are resolved.
+	pub fn set_allow_storage_clear(&mut self, allow: bool) {
+		if allow {
+			self.capabilities |= capabilities::STORAGE_CLEAR;
+		} else {
+			self.capabilities &= !capabilities::STORAGE_CLEAR;
+			self.evict_instance();
+		}
+	}
+
+	/// Invoke this module on behalf of the parachain specified by `caller_para_id`.
+	///
+	/// Changes to the storage are committed only if the invocation succeeds. If the module traps,
+	/// none of the changes made by this invocation persist. Note that this doesn't apply to
+	/// the messages sent.
+	pub fn invoke(
+		&mut self,
+		caller_para_id: u32,
+		time_slice: u32,
+		blob: Vec<u8>,
+	) -> Result<(), Error> {
+		self.invoke_direct(
+			caller_para_id,
+			time_slice,
+			"handle",
+			&[RuntimeValue::I32(time_slice as i32)],
+			blob,
+		)
+	}
+
+	/// Like `invoke`, but also returns the changes made to the storage by the invocation.
+	pub fn invoke_with_diff(
+		&mut self,
+		caller_para_id: u32,
+		time_slice: u32,
+		blob: Vec<u8>,
+	) -> Result<StorageDiff, Error> {
+		let before = self.storage_snapshot();
+		self.invoke(caller_para_id, time_slice, blob)?;
+		Ok(StorageDiff::between(&before, &self.storage_snapshot()))
+	}
+
+	/// Invoke the given export of this module directly, bypassing the `handle` entrypoint.
+	///
+	/// The scratch buffer is initialized with `blob`. See the design note about ADC in the SPREE
+	/// module.
+	///
+	/// If the module exports `spree_init` and it hasn't been run yet, it is run right before the
+	/// given export within the same invocation.
+	pub fn invoke_direct(
+		&mut self,
+		caller_para_id: u32,
+		time_slice: u32,
+		export: &str,
+		args: &[RuntimeValue],
+		blob: Vec<u8>,
+	) -> Result<(), Error> {
+		self.invoke_export(caller_para_id, time_slice, export, args, blob)
+			.map(|_| ())
+	}
+
+	/// Like `invoke_direct`, but for exports returning several values.
+	///
+	/// The wasm MVP allows only a single return value and wasmi doesn't support the multi-value
+	/// proposal. Instead, the export is expected to store its results in its linear memory one
+	/// after another, each little-endian and of the natural size of its type, and return the
+	/// pointer to the first one as an `i32`. The results are read back according to `ret_types`.
+	pub fn call_export(
+		&mut self,
+		caller_para_id: u32,
+		time_slice: u32,
+		export: &str,
+		args: &[RuntimeValue],
+		blob: Vec<u8>,
+		ret_types: &[ValueType],
+	) -> Result<Vec<RuntimeValue>, Error> {
+		let results_ptr =
+			match self.invoke_export(caller_para_id, time_slice, export, args, blob)? {
+				Some(RuntimeValue::I32(results_ptr)) => results_ptr as u32,
+				_ => {
+					return Err(Error::Msg(format!(
+						"spree module: `{}` should return a pointer to its results",
+						export
+					)))
+				}
+			};
+
+		// The instance is still alive since it was just invoked.
+		let instance = self
+			.instance
+			.as_ref()
+			.expect("instantiated by invoke_export");
+		let memory = crate::util::exported_memory(&self.wasm_path, instance, &self.memory_export)?;
+		let mut offset = results_ptr;
+		let mut results = Vec::with_capacity(ret_types.len());
+		for ret_type in ret_types {
+			let size = match ret_type {
+				ValueType::I32 | ValueType::F32 => 4,
+				ValueType::I64 | ValueType::F64 => 8,
+			};
+			let mut raw = [0u8; 8];
+			memory
+				.get_into(offset, &mut raw[..size])
+				.map_err(Error::from)?;
+			let bits = u64::from_le_bytes(raw);
+			results.push(match ret_type {
+				ValueType::I32 => RuntimeValue::I32(bits as i32),
+				ValueType::I64 => RuntimeValue::I64(bits as i64),
+				ValueType::F32 => RuntimeValue::decode_f32(bits as u32),
+				ValueType::F64 => RuntimeValue::decode_f64(bits),
+			});
+			offset += size as u32;
+		}
+		Ok(results)
+	}
+
+	/// Invoke the given export, returning the value it returned.
+	fn invoke_export(
+		&mut self,
+		caller_para_id: u32,
+		time_slice: u32,
+		export: &str,
+		args: &[RuntimeValue],
+		blob: Vec<u8>,
+	) -> Result<Option<RuntimeValue>, Error> {
+		let initialized = self.is_initialized(caller_para_id);
+		let instance = ensure_instance(
+			&self.wasm_path,
+			self.capabilities,
+			&self.host_fns,
+			&self.memory_export,
+			self.module.as_deref(),
+			&mut self.instance,
+			&mut self.instantiation_time,
+		)?;
+
+		let seed = self.random_seed.wrapping_add(self.invocations);
+		self.invocations += 1;
+
+		if let Some(scheduled) = self.inbound_schedule.get(&(time_slice as u64)) {
+			self.acc.inbound = scheduled.iter().cloned().collect();
+		}
+
+		let mut env = SpreeModuleHostEnv {
+			scratch_buf: ScratchBuf::new(self.scratch_capacity, &blob),
+			linear_memory: crate::util::exported_memory(
+				&self.wasm_path,
+				instance,
+				&self.memory_export,
+			)?,
+			acc: &mut self.acc,
+			inbound_drained: false,
+			storage: &*self.storage,
+			overlay: StorageOverlay::default(),
+			ephemeral: HashMap::new(),
+			gas_left: self.gas_limit,
+			last_time_slice: self.last_time_slice,
+			caller_para_id,
+			capabilities: self.capabilities,
+			rng: Prng(seed),
+			abort: None,
+			verify_storage: self.verify_storage,
+			per_caller_storage: self.per_caller_storage,
+			strict_reads: self.strict_reads.as_ref(),
+			max_host_calls: self.max_host_calls,
+			max_inbound_bundle: self.max_inbound_bundle,
+			max_outbound_msgs: self.max_outbound_msgs,
+			metrics: InvokeMetrics::default(),
+			failures: &mut self.failures,
+			signing_key: self.signing_key.as_deref(),
+			profile: self.profile,
+			host_fns: &self.host_fns,
+			touched_host_fns: if self.record_coverage {
+				Some(BTreeSet::new())
+			} else {
+				None
+			},
+			storage_history: self.storage_history.as_ref().map(|_| Vec::new()),
+		};
+		// The optional initialization runs within the first invocation, so its storage changes are
+		// committed only along with the changes made by the invocation.
+		let needs_init = !initialized && instance.export_by_name(INIT_EXPORT).is_some();
+		let mut stack = StackRecycler::with_limits(
+			DEFAULT_VALUE_STACK_LIMIT,
+			self.max_call_depth.unwrap_or(DEFAULT_CALL_STACK_LIMIT),
+		);
+		let result = if needs_init {
+			instance.invoke_export_with_stack(INIT_EXPORT, &[], &mut env, &mut stack)
+		} else {
+			Ok(None)
+		}
+		.and_then(|_| instance.invoke_export_with_stack(export, args, &mut env, &mut stack));
+		env.metrics.scratch_allocs = env.scratch_buf.allocs;
+		self.last_metrics = mem::take(&mut env.metrics);
+		self.last_coverage = env.touched_host_fns.take().map(|touched_host_fns| {
+			let mut exports = Vec::new();
+			if needs_init {
+				exports.push(INIT_EXPORT.to_string());
+			}
+			exports.push(export.to_string());
+			Coverage {
+				exports,
+				host_fns: touched_host_fns.into_iter().collect(),
+			}
+		});
+		let ret = match result {
+			Ok(ret) => ret,
+			Err(wasmi::Error::Trap(ref trap)) if is_stack_overflow(trap) => {
+				return Err(Error::StackOverflow {
+					limit: self.max_call_depth.unwrap_or(DEFAULT_CALL_STACK_LIMIT),
+				});
+			}
+			Err(e) => {
+				// Distinguish deliberate aborts from accidental traps.
+				return Err(match env.abort {
+					Some((code, message)) => Error::ModuleAbort { code, message },
+					// Host functions trap with our own `Error`, recover the precise variant.
+					None => e
+						.as_host_error()
+						.and_then(|e| e.downcast_ref::<Error>())
+						.and_then(Error::try_clone)
+						.unwrap_or_else(|| Error::from(e)),
+				});
+			}
+		};
+
+		let SpreeModuleHostEnv {
+			scratch_buf,
+			overlay,
+			storage_history,
+			inbound_drained,
+			..
+		} = env;
+		overlay.commit(&mut *self.storage);
+		if inbound_drained {
+			self.acc.inbound.clear();
+		}
+		if let (Some(history), Some(ops)) = (self.storage_history.as_mut(), storage_history) {
+			history.extend(ops);
+		}
+		self.last_scratch = scratch_buf.active;
+		self.last_time_slice = Some(time_slice);
+		self.mark_initialized(caller_para_id);
+		Ok(ret)
+	}
+
+	/// Whether `spree_init` has been run in the storage namespace of the given caller.
+	fn is_initialized(&self, caller_para_id: u32) -> bool {
+		if self.per_caller_storage {
+			self.initialized_callers.contains(&caller_para_id)
+		} else {
+			self.initialized
+		}
+	}
+
+	fn mark_initialized(&mut self, caller_para_id: u32) {
+		if self.per_caller_storage {
+			self.initialized_callers.insert(caller_para_id);
+		} else {
+			self.initialized = true;
+		}
+	}
+
+	/// Returns the names of the host functions this module imports.
+	///
+	/// This allows to decide whether to admit the module before instantiating it.
+	pub fn imported_host_functions(&self) -> Result<Vec<String>, Error> {
+		crate::util::imported_functions(&self.wasm_path, "env")
+	}
+
+	/// Returns the payload of the custom section with the given name, if the module has one.
+	///
+	/// This allows to check e.g. the SPREE API version a module declares before admitting it.
+	pub fn custom_section(&self, name: &str) -> Result<Option<Vec<u8>>, Error> {
+		crate::util::custom_section(&self.wasm_path, name)
+	}
+
+	/// Read the value of the global exported by this module under the given name.
+	///
+	/// Instantiates the module if there is no live instance.
+	pub fn read_global(&mut self, name: &str) -> Result<RuntimeValue, Error> {
+		let instance = ensure_instance(
+			&self.wasm_path,
+			self.capabilities,
+			&self.host_fns,
+			&self.memory_export,
+			self.module.as_deref(),
+			&mut self.instance,
+			&mut self.instantiation_time,
+		)?;
+		let global = instance
+			.export_by_name(name)
+			.ok_or_else(|| Error::Msg(format!("spree module doesn't export `{}`", name)))?
+			.as_global()
+			.ok_or_else(|| Error::Msg(format!("spree module: `{}` should be a global", name)))?
+			.clone();
+		Ok(global.get())
+	}
+
+	/// Read the value of the global defined by this module at the given index.
+	///
+	/// wasmi only allows exporting immutable globals, so this is the way to observe mutable
+	/// state kept in globals, e.g. an invocation counter. Imported globals come first in the index
+	/// space.
+	///
+	/// Instantiates the module if there is no live instance.
+	pub fn read_global_at(&mut self, index: u32) -> Result<RuntimeValue, Error> {
+		let instance = ensure_instance(
+			&self.wasm_path,
+			self.capabilities,
+			&self.host_fns,
+			&self.memory_export,
+			self.module.as_deref(),
+			&mut self.instance,
+			&mut self.instantiation_time,
+		)?;
+		let global = instance
+			.globals()
+			.get(index as usize)
+			.cloned()
+			.ok_or_else(|| Error::Msg(format!("spree module doesn't have global {}", index)))?;
+		Ok(global.get())
+	}
+
+	/// Drop the cached instance of this module, if any.
+	///
+	/// The instance will be created again upon the next invocation. Storage is preserved.
+	pub fn evict_instance(&mut self) {
+		self.instance = None;
+	}
+
+	/// Returns `true` if there are inbound messages for the module to poll.
+	pub fn has_inbound(&self) -> bool {
+		!self.acc.inbound.is_empty()
+	}
+
+	/// Returns `true` if this module has a live instance.
+	pub fn is_instantiated(&self) -> bool {
+		self.instance.is_some()
+	}
+
+	/// Returns `true` if this module and `other` use the very same parsed module, as set up by a
+	/// `SpreeRegistry` with module sharing enabled.
+	pub fn shares_parsed_module(&self, other: &SpreeModule) -> bool {
+		match (&self.module, &other.module) {
+			(Some(module), Some(other_module)) => Rc::ptr_eq(module, other_module),
+			_ => false,
+		}
+	}
+
+	/// Returns the time the first instantiation of this module took, or `None` if it hasn't been
+	/// instantiated yet.
+	///
+	/// This is the one-time cost that is not included in the time of the subsequent invocations.
+	/// Instantiating the module again after `evict_instance` doesn't update it.
+	pub fn instantiation_time(&self) -> Option<Duration> {
+		self.instantiation_time
+	}
+
+	/// Returns the time slice of the last successful invocation, or `None` if there was none.
+	pub fn last_time_slice(&self) -> Option<u32> {
+		self.last_time_slice
 	}
 
 	pub fn outbound_messages(&self) -> &HashMap<u32, Vec<u8>> {
 		&self.acc.outbound
 	}
+
+	/// Returns the messages in the dead-letter queue, see `SpreeIcmpAccumulator::dead_letters`.
+	pub fn dead_letters(&self) -> &[(u32, Vec<u8>)] {
+		self.acc.dead_letters()
+	}
+
+	/// Returns the messages dropped so far, see `SpreeIcmpAccumulator::dropped`.
+	pub fn dropped_messages(&self) -> &[(u32, Vec<u8>, DropReason)] {
+		self.acc.dropped()
+	}
+
+	/// Remove all entries from the storage of this module.
+	pub fn clear_storage(&mut self) {
+		self.storage.clear();
+	}
+
+	/// Replace the storage backend of this module.
+	pub fn with_storage(mut self, storage: impl SpreeStorage + 'static) -> Self {
+		self.storage = Box::new(storage);
+		self
+	}
+
+	/// Record the input of an invocation with the given arguments in the current state of this
+	/// module.
+	///
+	/// The record can be replayed later by `replay`. The inbound messages scheduled for the time
+	/// slice, if any, are recorded, since they replace the inbound messages upon the invocation.
+	pub fn record(&self, caller_para_id: u32, time_slice: u32, blob: &[u8]) -> InvokeRecord {
+		let mut inbound = match self.inbound_schedule.get(&(time_slice as u64)) {
+			Some(scheduled) => scheduled.iter().cloned().collect::<HashMap<_, _>>(),
+			None => self.acc.inbound.clone(),
+		}
+		.into_iter()
+		.collect::<Vec<_>>();
+		inbound.sort();
+		InvokeRecord {
+			caller_para_id,
+			time_slice,
+			blob: blob.to_vec(),
+			inbound,
+			storage: self.storage_snapshot().into_iter().collect(),
+			random_seed: self.random_seed.wrapping_add(self.invocations),
+			initialized: self.is_initialized(caller_para_id),
+			last_time_slice: self.last_time_slice,
+		}
+	}
+
+	/// Re-run the recorded invocation on a fresh instance of this module.
+	///
+	/// The state of this module is not affected.
+	pub fn replay(&self, record: &InvokeRecord) -> Result<InvokeOutcome, Error> {
+		let acc = SpreeIcmpAccumulator::with_inbound_msgs(record.inbound.iter().cloned())
+			.with_compression(self.acc.compression)
+			.with_reserved_para_ids(self.acc.reserved.iter().cloned())
+			.with_closed_channels(self.acc.closed.iter().cloned());
+		let mut spree_module = SpreeModule::new(self.wasm_path.clone(), acc)
+			.with_storage(record.storage.iter().cloned().collect::<HashMap<_, _>>())
+			.with_capabilities(self.capabilities)
+			.with_memory_export(self.memory_export.clone())
+			.with_random_seed(record.random_seed)
+			.with_storage_verification(self.verify_storage)
+			.with_per_caller_storage(self.per_caller_storage);
+		if record.initialized {
+			spree_module.mark_initialized(record.caller_para_id);
+		}
+		spree_module.last_time_slice = record.last_time_slice;
+		spree_module.gas_limit = self.gas_limit;
+		spree_module.max_host_calls = self.max_host_calls;
+		spree_module.max_inbound_bundle = self.max_inbound_bundle;
+		spree_module.max_outbound_msgs = self.max_outbound_msgs;
+		spree_module.max_call_depth = self.max_call_depth;
+		spree_module.strict_reads = self.strict_reads.clone();
+		spree_module.host_fns = self.host_fns.clone();
+		spree_module.signing_key = self.signing_key.clone();
+
+		spree_module.invoke(
+			record.caller_para_id,
+			record.time_slice,
+			record.blob.clone(),
+		)?;
+
+		Ok(InvokeOutcome {
+			storage: spree_module.storage_snapshot(),
+			outbound: spree_module.acc.outbound.into_iter().collect(),
+			scratch: spree_module.last_scratch,
+		})
+	}
+
+	/// Returns a copy of all entries of the storage of this module, sorted by the key.
+	pub fn storage_snapshot(&self) -> BTreeMap<Vec<u8>, Vec<u8>> {
+		self.storage.scan_prefix(&[]).into_iter().collect()
+	}
+
+	/// Returns the message queue of the lamport clock module, decoded from its storage.
+	///
+	/// With per-caller storage this is the queue of the given caller, otherwise the caller is
+	/// ignored. An absent queue is reported as empty, a queue that fails to decode as
+	/// `Error::StorageCorruption`.
+	pub fn queue_contents(&self, caller_para_id: u32) -> Result<Vec<TargetedMsg>, Error> {
+		let key = if self.per_caller_storage {
+			storage::caller_key(caller_para_id, QUEUE_KEY)
+		} else {
+			QUEUE_KEY.to_vec()
+		};
+		let raw = match self.storage.get(&key) {
+			Some(raw) => raw,
+			None => return Ok(Vec::new()),
+		};
+		let corrupted = || Error::StorageCorruption { key: key.clone() };
+		let raw = if self.verify_storage {
+			storage::unseal(&raw).ok_or_else(corrupted)?
+		} else {
+			raw
+		};
+		<Vec<TargetedMsg>>::decode(&mut &raw[..]).map_err(|_| corrupted())
+	}
+
+	/// Encode all entries of the storage of this module in a canonical form.
+	///
+	/// The entries are encoded as a SCALE `Vec<(Vec<u8>, Vec<u8>)>` sorted by the key, so equal
+	/// storages always produce identical bytes.
+	pub fn encode_state(&self) -> Vec<u8> {
+		self.storage_snapshot()
+			.into_iter()
+			.collect::<Vec<(Vec<u8>, Vec<u8>)>>()
+			.encode()
+	}
+
+	/// Returns the state of this module that persists across invocations.
+	pub fn save_state(&self) -> ModuleState {
+		let mut inbound = self
+			.acc
+			.inbound
+			.iter()
+			.map(|(sender, blob)| (*sender, blob.clone()))
+			.collect::<Vec<_>>();
+		inbound.sort();
+		let mut outbound = self
+			.acc
+			.outbound
+			.iter()
+			.map(|(recepient, blob)| (*recepient, blob.clone()))
+			.collect::<Vec<_>>();
+		outbound.sort();
+		ModuleState {
+			storage: self.encode_state(),
+			inbound,
+			outbound,
+			initialized: self.initialized,
+			initialized_callers: self.initialized_callers.iter().cloned().collect(),
+			invocations: self.invocations,
+			last_time_slice: self.last_time_slice,
+		}
+	}
+
+	/// Restore the state saved by `save_state`.
+	///
+	/// The instance, if any, is kept, so the state of the linear memory and globals is not
+	/// restored.
+	pub fn load_state(&mut self, state: ModuleState) -> Result<(), Error> {
+		self.apply_state(&state.storage)?;
+		self.acc.inbound = state.inbound.into_iter().collect();
+		self.acc.outbound = state.outbound.into_iter().collect();
+		self.initialized = state.initialized;
+		self.initialized_callers = state.initialized_callers.into_iter().collect();
+		self.invocations = state.invocations;
+		self.last_time_slice = state.last_time_slice;
+		Ok(())
+	}
+
+	/// Replace the storage of this module with the state produced by `encode_state`.
+	pub fn apply_state(&mut self, encoded: &[u8]) -> Result<(), Error> {
+		let entries = <Vec<(Vec<u8>, Vec<u8>)>>::decode(&mut &encoded[..])
+			.map_err(|e| Error::Msg(format!("malformed state: {}", e)))?;
+		self.storage.clear();
+		for (key, val) in entries {
+			self.storage.set(key, val);
+		}
+		Ok(())
+	}
+
+	/// Returns the coverage of the last invocation of this module, whether it succeeded or not.
+	///
+	/// Returns `None` unless enabled with `with_coverage`.
+	pub fn last_coverage(&self) -> Option<&Coverage> {
+		self.last_coverage.as_ref()
+	}
+
+	/// Returns the storage mutations made by all successful invocations of this module, in order.
+	///
+	/// Mutations made by failed invocations are discarded along with the mutations themselves.
+	/// Returns an empty slice unless enabled with `with_storage_history`.
+	pub fn storage_history(&self) -> &[StorageOp] {
+		self.storage_history.as_deref().unwrap_or(&[])
+	}
+
+	/// Returns the name of the host function with the given index, as reported in `Coverage`.
+	pub fn host_function_name(&self, index: usize) -> Option<&'static str> {
+		self.host_fns.get(index).map(|host_fn| host_fn.name)
+	}
+
+	/// Returns the metrics collected during the last invocation of this module, whether it
+	/// succeeded or not.
+	pub fn last_metrics(&self) -> &InvokeMetrics {
+		&self.last_metrics
+	}
+
+	/// Returns the storage of this module.
+	pub fn storage(&self) -> &dyn SpreeStorage {
+		&*self.storage
+	}
+
+	/// Returns the contents of the scratch buffer left by the module at the end of the last
+	/// successful invocation.
+	///
+	/// This is the way for a module to return data to the caller.
+	pub fn last_scratch(&self) -> &[u8] {
+		&self.last_scratch
+	}
+}
+
+impl fmt::Debug for SpreeModule {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.debug_struct("SpreeModule")
+			.field("wasm_path", &self.wasm_path)
+			.field("instantiated", &self.instance.is_some())
+			.field("acc", &self.acc)
+			.field("storage_keys", &self.storage.scan_prefix(&[]).len())
+			.field("capabilities", &format_args!("{:#x}", self.capabilities))
+			.field("invocations", &self.invocations)
+			.field("scheduled_slices", &self.inbound_schedule.len())
+			.finish()
+	}
+}
+
+/// Metrics collected during an invocation of a SPREE module.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct InvokeMetrics {
+	/// The number of bytes moved between the scratch buffer and the linear memory of the module,
+	/// including the bytes put into the scratch buffer by `poll`.
+	pub scratch_bytes: u64,
+	/// The number of times replacing the contents of the scratch buffer had to grow an
+	/// allocation.
+	pub scratch_allocs: u32,
+	/// The number of calls into the host.
+	pub host_calls: u32,
+	/// The number of calls to `send`, successful or not.
+	pub sends: u32,
+	/// The time spent in each host function by its name. Only collected if profiling is
+	/// enabled.
+	pub host_fn_time: BTreeMap<&'static str, Duration>,
+	/// The senders whose inbound bundles were not delivered by `poll` for exceeding the size
+	/// limit.
+	pub rejected_inbound: Vec<u32>,
+}
+
+/// A mutation of the storage of a SPREE module, see `SpreeModule::storage_history`.
+///
+/// Keys and values are as kept in the storage, i.e. keys carry the caller prefix if the storage
+/// is per caller and values carry the checksum if storage verification is enabled.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StorageOp {
+	pub key: Vec<u8>,
+	/// The value before the mutation, `None` if there was none.
+	pub old: Option<Vec<u8>>,
+	/// The value after the mutation, `None` if the value was deleted.
+	pub new: Option<Vec<u8>>,
+}
+
+/// The exports and host functions touched by an invocation of a SPREE module.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Coverage {
+	/// The exports invoked, in order. Includes `spree_init` if it was run.
+	pub exports: Vec<String>,
+	/// The sorted indexes of the host functions called. See `SpreeModule::host_function_name`.
+	pub host_fns: Vec<usize>,
+}
+
+/// The state of a SPREE module that persists across invocations, see `SpreeModule::save_state`.
+#[derive(Encode, Decode, Clone, Debug, PartialEq)]
+pub struct ModuleState {
+	/// The storage as produced by `SpreeModule::encode_state`.
+	pub storage: Vec<u8>,
+	/// Inbound messages as `(sender, blob)`, sorted by the sender.
+	pub inbound: Vec<(u32, Vec<u8>)>,
+	/// Outbound messages as `(recepient, blob)`, sorted by the recepient.
+	pub outbound: Vec<(u32, Vec<u8>)>,
+	/// Whether `spree_init` has been run.
+	pub initialized: bool,
+	/// The callers on behalf of which `spree_init` has been run, sorted, if the storage is
+	/// namespaced by the caller.
+	pub initialized_callers: Vec<u32>,
+	/// The number of invocations so far, which determines the seed of the next one.
+	pub invocations: u64,
+	/// The time slice of the last successful invocation, if any.
+	pub last_time_slice: Option<u32>,
+}
+
+/// The full input of an invocation of a SPREE module, sufficient for replaying it.
+#[derive(Encode, Decode, Clone, Debug, PartialEq)]
+pub struct InvokeRecord {
+	pub caller_para_id: u32,
+	pub time_slice: u32,
+	pub blob: Vec<u8>,
+	/// Inbound messages as `(sender, blob)`, sorted by the sender.
+	pub inbound: Vec<(u32, Vec<u8>)>,
+	/// Storage entries, sorted by the key.
+	pub storage: Vec<(Vec<u8>, Vec<u8>)>,
+	/// The seed of the source of randomness used for the invocation.
+	pub random_seed: u64,
+	/// Whether the module was initialized for the caller, i.e. `spree_init` is not going to be
+	/// run.
+	pub initialized: bool,
+	/// The time slice of the last successful invocation, if any.
+	pub last_time_slice: Option<u32>,
+}
+
+/// The outcome of a replayed invocation.
+#[derive(Debug, PartialEq)]
+pub struct InvokeOutcome {
+	pub outbound: BTreeMap<u32, Vec<u8>>,
+	pub scratch: Vec<u8>,
+	pub storage: BTreeMap<Vec<u8>, Vec<u8>>,
+}
+
+/// Deliver the messages sent by the given modules to their recepients.
+///
+/// `routing` maps parachain IDs to the indexes of the modules in `modules`. The outbound messages
+/// of every module are drained and become the inbound messages of the modules of the recepients,
+/// replacing whatever inbound messages they had before. Messages to the parachains that are not
+/// present in `routing` are dropped.
+pub fn route_messages(modules: &mut [&mut SpreeModule], routing: &HashMap<u32, usize>) {
+	route_messages_with_partitions(modules, routing, &mut Partitions::default());
+}
+
+/// Like `route_messages`, but holds back the messages crossing a partition.
+///
+/// The held messages are delivered by the first call after the partition heals in which the
+/// recepient doesn't receive a newer message from the same sender.
+pub fn route_messages_with_partitions(
+	modules: &mut [&mut SpreeModule],
+	routing: &HashMap<u32, usize>,
+	partitions: &mut Partitions,
+) {
+	let para_id_by_idx = routing
+		.iter()
+		.map(|(para_id, idx)| (*idx, *para_id))
+		.collect::<HashMap<_, _>>();
+
+	let mut inbound_by_idx = HashMap::<usize, HashMap<u32, Vec<u8>>>::new();
+	for (sender_idx, spree_module) in modules.iter_mut().enumerate() {
+		let outbound = mem::take(&mut spree_module.acc.outbound);
+		let sender = para_id_by_idx.get(&sender_idx);
+		for (recepient, blob) in outbound {
+			match (sender, routing.get(&recepient)) {
+				(Some(sender), Some(_)) if partitions.is_partitioned(*sender, recepient) => {
+					partitions.held.push((*sender, recepient, blob));
+				}
+				(Some(sender), Some(recepient_idx)) => {
+					inbound_by_idx
+						.entry(*recepient_idx)
+						.or_default()
+						.insert(*sender, blob);
+				}
+				_ => {
+					if let Some(ref mut dropped) = spree_module.acc.dropped {
+						dropped.push((recepient, blob, DropReason::Unroutable));
+					}
+				}
+			}
+		}
+	}
+
+	// Deliver the messages held back by the partitions that have healed since.
+	let held = mem::take(&mut partitions.held);
+	for (sender, recepient, blob) in held {
+		let recepient_idx = match routing.get(&recepient) {
+			Some(recepient_idx) if !partitions.is_partitioned(sender, recepient) => *recepient_idx,
+			_ => {
+				partitions.held.push((sender, recepient, blob));
+				continue;
+			}
+		};
+		match inbound_by_idx
+			.entry(recepient_idx)
+			.or_default()
+			.entry(sender)
+		{
+			// Deliver the newer message first and keep the older one for later.
+			Entry::Occupied(_) => partitions.held.push((sender, recepient, blob)),
+			Entry::Vacant(entry) => {
+				entry.insert(blob);
+			}
+		}
+	}
+
+	for (idx, spree_module) in modules.iter_mut().enumerate() {
+		if para_id_by_idx.contains_key(&idx) {
+			spree_module.acc.inbound = inbound_by_idx.remove(&idx).unwrap_or_default();
+		}
+	}
+}
+
+/// Pairs of parachains that can't exchange messages, for simulating network partitions.
+///
+/// See `route_messages_with_partitions`.
+#[derive(Debug, Default)]
+pub struct Partitions {
+	/// The partitioned pairs, each stored with the lower ID first.
+	partitioned: HashSet<(u32, u32)>,
+	/// The messages held back by the partitions as `(sender, recepient, blob)`.
+	held: Vec<(u32, u32, Vec<u8>)>,
+}
+
+impl Partitions {
+	/// Cut the link between the given parachains in both directions.
+	pub fn partition(&mut self, a: u32, b: u32) {
+		self.partitioned.insert(Self::pair(a, b));
+	}
+
+	/// Restore the link between the given parachains.
+	pub fn heal(&mut self, a: u32, b: u32) {
+		self.partitioned.remove(&Self::pair(a, b));
+	}
+
+	pub fn is_partitioned(&self, a: u32, b: u32) -> bool {
+		self.partitioned.contains(&Self::pair(a, b))
+	}
+
+	/// Returns the messages waiting for a partition to heal as `(sender, recepient, blob)`.
+	pub fn held(&self) -> &[(u32, u32, Vec<u8>)] {
+		&self.held
+	}
+
+	fn pair(a: u32, b: u32) -> (u32, u32) {
+		(a.min(b), a.max(b))
+	}
+}
+
+/// A handle by which a parachain refers to a SPREE module.
+pub type SpreeHandle = u32;
+
+/// A registry of SPREE modules accessible by a parachain.
+///
+/// Maps handles used by the parachain to the concrete SPREE modules. This decouples the handle
+/// namespace of the parachain from the order in which the modules are registered.
+///
+/// The registry can also limit the number of live instances of the registered modules. When the
+/// limit is exceeded the least recently used instance is evicted. An evicted module is instantiated
+/// again on demand. Storage of a module is preserved across evictions.
+///
+/// Optionally, modules with identical wasm binaries can share a single parsed module. They still
+/// get separate instances and storage.
+#[derive(Default)]
+pub struct SpreeRegistry<'a> {
+	modules: HashMap<SpreeHandle, &'a mut SpreeModule>,
+	/// Handles of the modules that have a live instance, ordered from the least recently used to
+	/// the most recently used.
+	instantiated: Vec<SpreeHandle>,
+	/// The maximum number of live instances. `None` means there is no limit.
+	max_instances: Option<usize>,
+	/// The parsed modules by the hash of their wasm binaries. `None` unless sharing is enabled
+	/// with `with_module_sharing`.
+	parsed_modules: Option<HashMap<Vec<u8>, Rc<Module>>>,
+}
+
+impl<'a> SpreeRegistry<'a> {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Create a registry that keeps at most `max_instances` live instances.
+	pub fn with_max_instances(max_instances: usize) -> Self {
+		Self {
+			max_instances: Some(max_instances),
+			..Self::default()
+		}
+	}
+
+	/// Share a single parsed module between the registered modules with identical wasm binaries.
+	///
+	/// With sharing enabled, `register` reads and parses the wasm binary immediately.
+	pub fn with_module_sharing(mut self) -> Self {
+		self.parsed_modules = Some(HashMap::new());
+		self
+	}
+
+	/// Register the given SPREE module under the given handle.
+	///
+	/// A module that is already instantiated, e.g. by a previous block, counts towards the limit
+	/// of live instances as more recently used than the modules registered before it. Hence,
+	/// modules should be registered from the least recently used.
+	///
+	/// Returns an error if the handle is already taken, or, if module sharing is enabled, if the
+	/// wasm binary of the module can't be read or parsed.
+	pub fn register(
+		&mut self,
+		handle: SpreeHandle,
+		spree_module: &'a mut SpreeModule,
+	) -> Result<(), Error> {
+		if self.modules.contains_key(&handle) {
+			return Err(Error::Msg(format!(
+				"handle `{}` is already registered",
+				handle
+			)));
+		}
+		if let Some(ref mut parsed_modules) = self.parsed_modules {
+			let wasm = std::fs::read(&spree_module.wasm_path)?;
+			let hash = Sha256::digest(&wasm).to_vec();
+			let module = match parsed_modules.get(&hash) {
+				Some(module) => module.clone(),
+				None => {
+					let module = match spree_module.module.take() {
+						Some(module) => module,
+						None => Rc::new(Module::from_buffer(&wasm)?),
+					};
+					parsed_modules.insert(hash, module.clone());
+					module
+				}
+			};
+			spree_module.module = Some(module);
+		}
+		if spree_module.is_instantiated() {
+			self.instantiated.push(handle);
+		}
+		self.modules.insert(handle, spree_module);
+		self.evict_excess();
+		Ok(())
+	}
+
+	/// Returns the handles of the modules that have a live instance, from the least recently used
+	/// to the most recently used.
+	pub fn instantiated(&self) -> &[SpreeHandle] {
+		&self.instantiated
+	}
+
+	/// Returns the number of distinct parsed modules shared by the registered modules.
+	///
+	/// Always 0 unless module sharing is enabled.
+	pub fn parsed_modules(&self) -> usize {
+		self.parsed_modules.as_ref().map_or(0, HashMap::len)
+	}
+
+	/// Resolve the given handle to a SPREE module.
+	pub fn get_mut(&mut self, handle: SpreeHandle) -> Option<&mut SpreeModule> {
+		self.modules
+			.get_mut(&handle)
+			.map(|spree_module| &mut **spree_module)
+	}
+
+	/// Invoke a SPREE module specified by the given handle on behalf of the parachain specified
+	/// by `caller_para_id`.
+	///
+	/// Evicts the least recently used instances if the limit of live instances is exceeded.
+	pub fn invoke(
+		&mut self,
+		handle: SpreeHandle,
+		caller_para_id: u32,
+		time_slice: u32,
+		blob: Vec<u8>,
+	) -> Result<(), Error> {
+		let result = self
+			.get_mut(handle)
+			.ok_or_else(|| Error::Msg(format!("handle `{}` doesn't exist", handle)))?
+			.invoke(caller_para_id, time_slice, blob);
+		self.touch(handle);
+		result
+	}
+
+	/// Mark the module specified by the given handle as the most recently used, evicting the least
+	/// recently used ones if needed.
+	fn touch(&mut self, handle: SpreeHandle) {
+		self.instantiated.retain(|used| *used != handle);
+		self.instantiated.push(handle);
+		self.evict_excess();
+	}
+
+	/// Evict the least recently used instances while the limit of live instances is exceeded.
+	fn evict_excess(&mut self) {
+		if let Some(max_instances) = self.max_instances {
+			while self.instantiated.len() > max_instances {
+				let lru = self.instantiated.remove(0);
+				if let Some(spree_module) = self.get_mut(lru) {
+					spree_module.evict_instance();
+				}
+			}
+		}
+	}
 }
 
 fn ensure_instance<'a>(
 	path: &str,
+	capabilities: u64,
+	host_fns: &[HostFunction],
+	memory_export: &str,
+	module: Option<&Module>,
 	instance_cache: &'a mut Option<ModuleRef>,
+	instantiation_time: &mut Option<Duration>,
 ) -> Result<&'a ModuleRef, Error> {
 	if let Some(ref instance) = *instance_cache {
 		return Ok(instance);
 	}
 
+	let started = Instant::now();
+
+	let resolver = SpreeModuleImportResolver {
+		capabilities,
+		host_fns,
+	};
 	let mut imports = ImportsBuilder::new();
-	imports.push_resolver("env", &SpreeModuleImportResolver);
+	imports.push_resolver("env", &resolver);
 
-	let module = crate::util::load_wasm_module(path)?;
-	let instance = ModuleInstance::new(&module, &imports)?.assert_no_start();
+	let loaded;
+	let module = match module {
+		Some(module) => module,
+		None => {
+			loaded = crate::util::load_wasm_module(path)?;
+			&loaded
+		}
+	};
+	let instance = crate::util::instantiate(path, module, &imports)?;
+
+	// Check the memory and the entrypoint eagerly to report a misbuilt module as early as
+	// possible.
+	crate::util::exported_memory(path, &instance, memory_export)?;
+	crate::util::check_entrypoint_signature(
+		path,
+		&instance,
+		"handle",
+		&Signature::new(&[ValueType::I32][..], None),
+	)?;
 	*instance_cache = Some(instance);
+	if instantiation_time.is_none() {
+		*instantiation_time = Some(started.elapsed());
+	}
 
 	// Option::unwrap is fine here since it is just assigned above.
 	let instance_ref = instance_cache.as_ref().unwrap();
@@ -0,0 +1,219 @@
+//! Storage backends for SPREE modules.
+
+use std::collections::{BTreeMap, HashMap};
+
+/// A backend that holds the storage of a SPREE module.
+pub trait SpreeStorage {
+	/// Returns the value stored under the given key.
+	fn get(&self, key: &[u8]) -> Option<Vec<u8>>;
+
+	/// Store the given value under the given key, overwriting the previous value if any.
+	fn set(&mut self, key: Vec<u8>, val: Vec<u8>);
+
+	/// Remove the value stored under the given key, if any.
+	fn delete(&mut self, key: &[u8]);
+
+	/// Returns all key-value pairs which keys start with the given prefix.
+	///
+	/// The order of the returned pairs is unspecified.
+	fn scan_prefix(&self, prefix: &[u8]) -> Vec<(Vec<u8>, Vec<u8>)>;
+
+	/// Remove all entries from the storage.
+	fn clear(&mut self) {
+		for (key, _) in self.scan_prefix(&[]) {
+			self.delete(&key);
+		}
+	}
+}
+
+/// The default, in-memory, storage backend.
+impl SpreeStorage for HashMap<Vec<u8>, Vec<u8>> {
+	fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+		HashMap::get(self, key).cloned()
+	}
+
+	fn set(&mut self, key: Vec<u8>, val: Vec<u8>) {
+		self.insert(key, val);
+	}
+
+	fn delete(&mut self, key: &[u8]) {
+		self.remove(key);
+	}
+
+	fn scan_prefix(&self, prefix: &[u8]) -> Vec<(Vec<u8>, Vec<u8>)> {
+		self.iter()
+			.filter(|(key, _)| key.starts_with(prefix))
+			.map(|(key, val)| (key.clone(), val.clone()))
+			.collect()
+	}
+
+	fn clear(&mut self) {
+		HashMap::clear(self);
+	}
+}
+
+/// Changes made to a storage that are not committed yet.
+///
+/// This allows to discard all storage changes made by an invocation that trapped.
+#[derive(Default)]
+pub struct StorageOverlay {
+	/// Changed values by the key. `None` means the value was deleted.
+	changes: HashMap<Vec<u8>, Option<Vec<u8>>>,
+	/// Whether the whole storage was cleared before the changes were made.
+	cleared: bool,
+}
+
+impl StorageOverlay {
+	/// Returns the value stored under the given key, taking the uncommitted changes into account.
+	pub fn get(&self, backend: &dyn SpreeStorage, key: &[u8]) -> Option<Vec<u8>> {
+		match self.changes.get(key) {
+			Some(change) => change.clone(),
+			None if self.cleared => None,
+			None => backend.get(key),
+		}
+	}
+
+	pub fn set(&mut self, key: Vec<u8>, val: Vec<u8>) {
+		self.changes.insert(key, Some(val));
+	}
+
+	pub fn clear(&mut self) {
+		self.changes.clear();
+		self.cleared = true;
+	}
+
+	/// Returns the entries which keys start with the given prefix, taking the uncommitted
+	/// changes into account. The entries are sorted by key.
+	pub fn scan_prefix(
+		&self,
+		backend: &dyn SpreeStorage,
+		prefix: &[u8],
+	) -> Vec<(Vec<u8>, Vec<u8>)> {
+		let mut entries = if self.cleared {
+			BTreeMap::new()
+		} else {
+			backend.scan_prefix(prefix).into_iter().collect()
+		};
+		for (key, change) in self
+			.changes
+			.iter()
+			.filter(|(key, _)| key.starts_with(prefix))
+		{
+			match change {
+				Some(val) => entries.insert(key.clone(), val.clone()),
+				None => entries.remove(key),
+			};
+		}
+		entries.into_iter().collect()
+	}
+
+	/// Remove all entries which keys start with the given prefix.
+	pub fn clear_prefix(&mut self, backend: &dyn SpreeStorage, prefix: &[u8]) {
+		if !self.cleared {
+			for (key, _) in backend.scan_prefix(prefix) {
+				self.changes.insert(key, None);
+			}
+		}
+		for (_, change) in self
+			.changes
+			.iter_mut()
+			.filter(|(key, _)| key.starts_with(prefix))
+		{
+			*change = None;
+		}
+	}
+
+	/// Apply the changes to the given backend.
+	pub fn commit(self, backend: &mut dyn SpreeStorage) {
+		if self.cleared {
+			backend.clear();
+		}
+		for (key, change) in self.changes {
+			match change {
+				Some(val) => backend.set(key, val),
+				None => backend.delete(&key),
+			}
+		}
+	}
+}
+
+/// Returns the prefix of the storage keys written on behalf of the given caller when the storage
+/// is namespaced per caller.
+pub fn caller_prefix(caller_para_id: u32) -> Vec<u8> {
+	caller_para_id.to_le_bytes().to_vec()
+}
+
+/// Returns the key under which the value written by the given caller under `key` is stored when
+/// the storage is namespaced per caller.
+pub fn caller_key(caller_para_id: u32, key: &[u8]) -> Vec<u8> {
+	let mut caller_key = caller_prefix(caller_para_id);
+	caller_key.extend_from_slice(key);
+	caller_key
+}
+
+/// A changed value as `(key, old, new)`. `old` is `None` if the key was absent.
+pub type ChangedValue = (Vec<u8>, Option<Vec<u8>>, Vec<u8>);
+
+/// Changes between two states of a storage.
+#[derive(Debug, Default, PartialEq)]
+pub struct StorageDiff {
+	/// Changed values, sorted by the key.
+	pub written: Vec<ChangedValue>,
+	/// Removed keys, sorted.
+	pub deleted: Vec<Vec<u8>>,
+}
+
+impl StorageDiff {
+	/// Compute the changes that turn `before` into `after`.
+	pub fn between(
+		before: &BTreeMap<Vec<u8>, Vec<u8>>,
+		after: &BTreeMap<Vec<u8>, Vec<u8>>,
+	) -> Self {
+		let written = after
+			.iter()
+			.filter(|(key, new)| before.get(*key) != Some(*new))
+			.map(|(key, new)| (key.clone(), before.get(key).cloned(), new.clone()))
+			.collect();
+		let deleted = before
+			.keys()
+			.filter(|key| !after.contains_key(*key))
+			.cloned()
+			.collect();
+		StorageDiff { written, deleted }
+	}
+}
+
+/// The length of the checksum prepended to the values by `seal`.
+const CHECKSUM_LEN: usize = 8;
+
+/// FNV-1a hash of the given bytes.
+fn checksum(data: &[u8]) -> u64 {
+	data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+		(hash ^ *byte as u64).wrapping_mul(0x0100_0000_01b3)
+	})
+}
+
+/// Prepend the checksum of the given value to it.
+pub fn seal(val: &[u8]) -> Vec<u8> {
+	let mut sealed = Vec::with_capacity(CHECKSUM_LEN + val.len());
+	sealed.extend_from_slice(&checksum(val).to_le_bytes());
+	sealed.extend_from_slice(val);
+	sealed
+}
+
+/// Verify and strip the checksum prepended by `seal`.
+///
+/// Returns `None` if the checksum doesn't match the value.
+pub fn unseal(sealed: &[u8]) -> Option<Vec<u8>> {
+	if sealed.len() < CHECKSUM_LEN {
+		return None;
+	}
+	let (sum, val) = sealed.split_at(CHECKSUM_LEN);
+	let mut expected = [0; CHECKSUM_LEN];
+	expected.copy_from_slice(sum);
+	if u64::from_le_bytes(expected) == checksum(val) {
+		Some(val.to_vec())
+	} else {
+		None
+	}
+}
@@ -1,5 +1,7 @@
 use crate::error::Error;
-use wasmi::Module;
+use wasmi::{
+	ImportsBuilder, MemoryRef, Module, ModuleInstance, ModuleRef, RuntimeArgs, Signature, ValueType,
+};
 
 pub fn load_wasm_module(path: &str) -> Result<Module, Error> {
 	use std::{fs::File, io::prelude::*};
@@ -10,3 +12,203 @@ pub fn load_wasm_module(path: &str) -> Result<Module, Error> {
 	let module = wasmi::Module::from_buffer(&wasm_buf)?;
 	Ok(module)
 }
+
+/// Instantiate the module loaded from `path`, classifying the failures.
+pub fn instantiate(
+	path: &str,
+	module: &Module,
+	imports: &ImportsBuilder,
+) -> Result<ModuleRef, Error> {
+	let not_started = ModuleInstance::new(module, imports).map_err(|e| match e {
+		wasmi::Error::Function(reason)
+		| wasmi::Error::Table(reason)
+		| wasmi::Error::Global(reason) => Error::BadImport {
+			path: path.to_string(),
+			reason,
+		},
+		wasmi::Error::Memory(reason) => Error::BadMemory {
+			path: path.to_string(),
+			reason,
+		},
+		e => Error::Interpreter(e),
+	})?;
+	if not_started.has_start() {
+		return Err(Error::StartFunction {
+			path: path.to_string(),
+		});
+	}
+	Ok(not_started.assert_no_start())
+}
+
+/// Returns the names of the functions the wasm binary at the given path imports from the given
+/// module, in the order of the import section.
+///
+/// Only the import section is parsed, so this works even for binaries whose code the interpreter
+/// doesn't support, e.g. to decide whether to admit a module in the first place.
+pub fn imported_functions(path: &str, module_name: &str) -> Result<Vec<String>, Error> {
+	use parity_wasm::elements::{Deserialize, External, ImportSection, VarUint32};
+	/// The id of the import section.
+	const IMPORT_SECTION_ID: u8 = 2;
+
+	let malformed =
+		|reason: String| Error::Msg(format!("{}: failed to parse wasm: {}", path, reason));
+	let wasm = std::fs::read(path)?;
+	if wasm.len() < 8 || &wasm[..4] != b"\0asm" {
+		return Err(malformed("not a wasm binary".to_string()));
+	}
+	// Skip the magic and the version and walk the sections up to the import section.
+	let mut sections = &wasm[8..];
+	while let Some((&id, rest)) = sections.split_first() {
+		// A section, as deserialized, starts with the size of its payload.
+		let section = rest;
+		sections = rest;
+		let size =
+			u32::from(VarUint32::deserialize(&mut sections).map_err(|e| malformed(e.to_string()))?)
+				as usize;
+		if size > sections.len() {
+			return Err(malformed("a section is truncated".to_string()));
+		}
+		let section_len = section.len() - sections.len() + size;
+		sections = &sections[size..];
+		if id != IMPORT_SECTION_ID {
+			continue;
+		}
+		let import_section =
+			parity_wasm::elements::deserialize_buffer::<ImportSection>(&section[..section_len])
+				.map_err(|e| malformed(e.to_string()))?;
+		return Ok(import_section
+			.entries()
+			.iter()
+			.filter(|entry| entry.module() == module_name)
+			.filter(|entry| matches!(entry.external(), External::Function(_)))
+			.map(|entry| entry.field().to_string())
+			.collect());
+	}
+	Ok(Vec::new())
+}
+
+/// Returns the payload of the custom section with the given name in the wasm binary at the given
+/// path, if any.
+pub fn custom_section(path: &str, name: &str) -> Result<Option<Vec<u8>>, Error> {
+	let module: parity_wasm::elements::Module = parity_wasm::deserialize_file(path)
+		.map_err(|e| Error::Msg(format!("{}: failed to parse wasm: {}", path, e)))?;
+	let payload = module
+		.custom_sections()
+		.find(|section| section.name() == name)
+		.map(|section| section.payload().to_vec());
+	Ok(payload)
+}
+
+/// The name under which modules conventionally export their linear memory.
+pub const DEFAULT_MEMORY_EXPORT: &str = "memory";
+
+/// Returns the linear memory exported by the given instance under the given name.
+pub fn exported_memory(
+	path: &str,
+	instance: &ModuleRef,
+	memory_export: &str,
+) -> Result<MemoryRef, Error> {
+	instance
+		.export_by_name(memory_export)
+		.ok_or_else(|| Error::BadMemory {
+			path: path.to_string(),
+			reason: format!("expected to have export called `{}`", memory_export),
+		})?
+		.as_memory()
+		.cloned()
+		.ok_or_else(|| Error::BadMemory {
+			path: path.to_string(),
+			reason: format!("`{}` should be a linear memory", memory_export),
+		})
+}
+
+/// Check that the function exported by the given instance under the given name, if any, has the
+/// expected signature.
+pub fn check_entrypoint_signature(
+	path: &str,
+	instance: &ModuleRef,
+	name: &str,
+	expected: &Signature,
+) -> Result<(), Error> {
+	let func = match instance
+		.export_by_name(name)
+		.and_then(|e| e.as_func().cloned())
+	{
+		Some(func) => func,
+		None => return Ok(()),
+	};
+	if func.signature() != expected {
+		return Err(Error::BadEntrypointSignature {
+			path: path.to_string(),
+			name: name.to_string(),
+			expected: format_signature(expected),
+			found: format_signature(func.signature()),
+		});
+	}
+	Ok(())
+}
+
+/// Format the signature in a human readable form, e.g. `(i32, i32) -> i64`.
+fn format_signature(signature: &Signature) -> String {
+	let format_type = |value_type: &ValueType| match value_type {
+		ValueType::I32 => "i32",
+		ValueType::I64 => "i64",
+		ValueType::F32 => "f32",
+		ValueType::F64 => "f64",
+	};
+	let params = signature
+		.params()
+		.iter()
+		.map(format_type)
+		.collect::<Vec<_>>()
+		.join(", ");
+	let ret = signature.return_type().as_ref().map_or("()", format_type);
+	format!("({}) -> {}", params, ret)
+}
+
+/// Read a UTF-8 string of `len` bytes at `ptr` from the given linear memory.
+///
+/// Unlike a lossy conversion, invalid UTF-8 is reported as `Error::InvalidUtf8`.
+pub fn read_utf8(memory: &MemoryRef, ptr: u32, len: u32) -> Result<String, Error> {
+	let buf = memory.get(ptr, len as usize)?;
+	Ok(String::from_utf8(buf)?)
+}
+
+/// A wrapper over the arguments of a host function call.
+///
+/// Unlike `RuntimeArgs::nth` it doesn't panic and produces errors that mention the host function
+/// and the argument.
+pub struct Args<'a> {
+	fn_name: &'static str,
+	args: RuntimeArgs<'a>,
+}
+
+impl<'a> Args<'a> {
+	/// Wrap the given arguments of the host function `fn_name`, checking that there are exactly
+	/// `arity` of them.
+	pub fn new(fn_name: &'static str, args: RuntimeArgs<'a>, arity: usize) -> Result<Self, Error> {
+		if args.len() != arity {
+			return Err(Error::Msg(format!(
+				"{}: expected {} arguments, got {}",
+				fn_name,
+				arity,
+				args.len()
+			)));
+		}
+		Ok(Self { fn_name, args })
+	}
+
+	/// Returns the argument at the given position as `u32`.
+	pub fn u32(&self, idx: usize, arg_name: &str) -> Result<u32, Error> {
+		self.args
+			.nth_value_checked(idx)
+			.ok()
+			.and_then(|value| value.try_into::<u32>())
+			.ok_or_else(|| {
+				Error::Msg(format!(
+					"{}: argument `{}` at position {} should be i32",
+					self.fn_name, arg_name, idx
+				))
+			})
+	}
+}
@@ -0,0 +1,303 @@
+//! Lowering of wasm binaries built by recent toolchains to what wasmi 0.6 accepts.
+//!
+//! The standard library for `wasm32-unknown-unknown` is prebuilt with bulk memory and sign
+//! extension operators, which wasmi 0.6 predates. These operators are rewritten in terms of the
+//! MVP ones: `memory.copy` and `memory.fill` become calls to functions appended to the module,
+//! sign extensions become pairs of shifts. Everything else is re-encoded as is, which also
+//! shortens the padded LEB128 immediates the linker leaves, e.g. in `call_indirect`.
+
+use std::borrow::Cow;
+use wasm_encoder::{
+	reencode::{self, Reencode},
+	BlockType, CodeSection, ElementSection, Elements, Function, FunctionSection, Instruction,
+	MemArg, Module, TypeSection, ValType,
+};
+use wasmparser::{ElementItems, ElementKind, FunctionBody, Operator, Parser, Payload};
+
+/// The section ID of the data count section, which only bulk memory operators need.
+const DATA_COUNT_SECTION: u8 = 12;
+
+struct Lowering {
+	/// The type of both helpers, `[i32 i32 i32] -> []`.
+	helper_type: u32,
+	/// The index of the function implementing `memory.copy`.
+	copy_fn: u32,
+	/// The index of the function implementing `memory.fill`.
+	fill_fn: u32,
+}
+
+impl Reencode for Lowering {
+	type Error = std::convert::Infallible;
+
+	fn parse_type_section(
+		&mut self,
+		types: &mut TypeSection,
+		section: wasmparser::TypeSectionReader<'_>,
+	) -> Result<(), reencode::Error> {
+		reencode::utils::parse_type_section(self, types, section)?;
+		types
+			.ty()
+			.function([ValType::I32, ValType::I32, ValType::I32], []);
+		Ok(())
+	}
+
+	fn parse_function_section(
+		&mut self,
+		functions: &mut FunctionSection,
+		section: wasmparser::FunctionSectionReader<'_>,
+	) -> Result<(), reencode::Error> {
+		reencode::utils::parse_function_section(self, functions, section)?;
+		functions.function(self.helper_type);
+		functions.function(self.helper_type);
+		Ok(())
+	}
+
+	fn parse_code_section(
+		&mut self,
+		code: &mut CodeSection,
+		section: wasmparser::CodeSectionReader<'_>,
+	) -> Result<(), reencode::Error> {
+		reencode::utils::parse_code_section(self, code, section)?;
+		code.function(&memory_copy());
+		code.function(&memory_fill());
+		Ok(())
+	}
+
+	fn parse_function_body(
+		&mut self,
+		code: &mut CodeSection,
+		func: FunctionBody<'_>,
+	) -> Result<(), reencode::Error> {
+		let mut f = self.new_function_with_parsed_locals(&func)?;
+		let mut reader = func.get_operators_reader()?;
+		while !reader.eof() {
+			let lowered = match reader.read()? {
+				Operator::MemoryCopy { .. } => vec![Instruction::Call(self.copy_fn)],
+				Operator::MemoryFill { .. } => vec![Instruction::Call(self.fill_fn)],
+				Operator::I32Extend8S => sign_extend_i32(24),
+				Operator::I32Extend16S => sign_extend_i32(16),
+				Operator::I64Extend8S => sign_extend_i64(56),
+				Operator::I64Extend16S => sign_extend_i64(48),
+				Operator::I64Extend32S => sign_extend_i64(32),
+				op => vec![self.instruction(op)?],
+			};
+			for instruction in &lowered {
+				f.instruction(instruction);
+			}
+		}
+		code.function(&f);
+		Ok(())
+	}
+
+	fn parse_element_section(
+		&mut self,
+		elements: &mut ElementSection,
+		section: wasmparser::ElementSectionReader<'_>,
+	) -> Result<(), reencode::Error> {
+		for element in section {
+			let element = element?;
+			match (element.kind, element.items) {
+				// Use the MVP encoding, which implies the table 0.
+				(
+					ElementKind::Active {
+						table_index: Some(0) | None,
+						offset_expr,
+					},
+					ElementItems::Functions(funcs),
+				) => {
+					let funcs = funcs.into_iter().collect::<Result<Vec<_>, _>>()?;
+					elements.active(
+						None,
+						&self.const_expr(offset_expr)?,
+						Elements::Functions(Cow::Owned(funcs)),
+					);
+				}
+				_ => panic!("only active segments of functions are supported"),
+			}
+		}
+		Ok(())
+	}
+}
+
+fn sign_extend_i32(shift: i32) -> Vec<Instruction<'static>> {
+	vec![
+		Instruction::I32Const(shift),
+		Instruction::I32Shl,
+		Instruction::I32Const(shift),
+		Instruction::I32ShrS,
+	]
+}
+
+fn sign_extend_i64(shift: i64) -> Vec<Instruction<'static>> {
+	vec![
+		Instruction::I64Const(shift),
+		Instruction::I64Shl,
+		Instruction::I64Const(shift),
+		Instruction::I64ShrS,
+	]
+}
+
+fn byte() -> MemArg {
+	MemArg {
+		offset: 0,
+		align: 0,
+		memory_index: 0,
+	}
+}
+
+/// `(dst, src, len)`: copy `len` bytes, forwards if `dst <= src` and backwards otherwise, so
+/// overlapping ranges are handled like `memory.copy` does.
+fn memory_copy() -> Function {
+	use Instruction::*;
+	let (dst, src, len) = (0, 1, 2);
+	let mut f = Function::new([]);
+	for instruction in &[
+		LocalGet(dst),
+		LocalGet(src),
+		I32LeU,
+		If(BlockType::Empty),
+		// Forwards: copy the byte at `src`, then advance both until `len` runs out.
+		Block(BlockType::Empty),
+		Loop(BlockType::Empty),
+		LocalGet(len),
+		I32Eqz,
+		BrIf(1),
+		LocalGet(dst),
+		LocalGet(src),
+		I32Load8U(byte()),
+		I32Store8(byte()),
+		LocalGet(dst),
+		I32Const(1),
+		I32Add,
+		LocalSet(dst),
+		LocalGet(src),
+		I32Const(1),
+		I32Add,
+		LocalSet(src),
+		LocalGet(len),
+		I32Const(1),
+		I32Sub,
+		LocalSet(len),
+		Br(0),
+		End,
+		End,
+		Else,
+		// Backwards: copy the byte at `src + len - 1` until `len` runs out.
+		Block(BlockType::Empty),
+		Loop(BlockType::Empty),
+		LocalGet(len),
+		I32Eqz,
+		BrIf(1),
+		LocalGet(len),
+		I32Const(1),
+		I32Sub,
+		LocalSet(len),
+		LocalGet(dst),
+		LocalGet(len),
+		I32Add,
+		LocalGet(src),
+		LocalGet(len),
+		I32Add,
+		I32Load8U(byte()),
+		I32Store8(byte()),
+		Br(0),
+		End,
+		End,
+		End,
+		End,
+	] {
+		f.instruction(instruction);
+	}
+	f
+}
+
+/// `(dst, val, len)`: set `len` bytes starting at `dst` to `val`.
+fn memory_fill() -> Function {
+	use Instruction::*;
+	let (dst, val, len) = (0, 1, 2);
+	let mut f = Function::new([]);
+	for instruction in &[
+		Block(BlockType::Empty),
+		Loop(BlockType::Empty),
+		LocalGet(len),
+		I32Eqz,
+		BrIf(1),
+		LocalGet(dst),
+		LocalGet(val),
+		I32Store8(byte()),
+		LocalGet(dst),
+		I32Const(1),
+		I32Add,
+		LocalSet(dst),
+		LocalGet(len),
+		I32Const(1),
+		I32Sub,
+		LocalSet(len),
+		Br(0),
+		End,
+		End,
+		End,
+	] {
+		f.instruction(instruction);
+	}
+	f
+}
+
+/// Remove the data count section, which wasmi 0.6 doesn't recognize.
+fn strip_data_count(binary: Vec<u8>) -> Vec<u8> {
+	let mut stripped = binary[..8].to_vec();
+	for payload in Parser::new(0).parse_all(&binary) {
+		let payload = payload.expect("the lowered module is valid");
+		if let Some((id, range)) = payload.as_section() {
+			if id != DATA_COUNT_SECTION {
+				// `range` only covers the contents, re-add the header.
+				let contents = &binary[range.start as usize..range.end as usize];
+				stripped.push(id);
+				leb128_u32(contents.len() as u32, &mut stripped);
+				stripped.extend_from_slice(contents);
+			}
+		}
+	}
+	stripped
+}
+
+fn leb128_u32(mut value: u32, out: &mut Vec<u8>) {
+	loop {
+		let byte = (value & 0x7f) as u8;
+		value >>= 7;
+		if value == 0 {
+			out.push(byte);
+			return;
+		}
+		out.push(byte | 0x80);
+	}
+}
+
+/// Lower the given wasm binary so that wasmi 0.6 can run it.
+pub fn lower_to_mvp(binary: &[u8]) -> Vec<u8> {
+	let (mut types, mut imported_fns, mut defined_fns) = (0, 0, 0);
+	for payload in Parser::new(0).parse_all(binary) {
+		match payload.expect("the module is valid") {
+			Payload::TypeSection(section) => types = section.count(),
+			Payload::ImportSection(section) => {
+				for import in section.into_imports() {
+					if let wasmparser::TypeRef::Func(_) = import.expect("the module is valid").ty {
+						imported_fns += 1;
+					}
+				}
+			}
+			Payload::FunctionSection(section) => defined_fns = section.count(),
+			_ => {}
+		}
+	}
+	let mut lowering = Lowering {
+		helper_type: types,
+		copy_fn: imported_fns + defined_fns,
+		fill_fn: imported_fns + defined_fns + 1,
+	};
+	let mut module = Module::new();
+	lowering
+		.parse_core_module(&mut module, Parser::new(0), binary)
+		.expect("the module can be re-encoded");
+	strip_data_count(module.finish())
+}
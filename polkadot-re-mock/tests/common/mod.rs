@@ -0,0 +1,126 @@
+//! Helpers shared by the integration tests.
+
+#![allow(dead_code)]
+
+mod lowering;
+
+use std::{io::Write, path::Path};
+use tempfile::TempPath;
+
+/// The lamport clock SPREE module, as built by `build.sh`.
+pub const LAMPORT_CLOCK_WASM: &str = concat!(
+	env!("CARGO_MANIFEST_DIR"),
+	"/../spree-lamport-clock/target/wasm32-unknown-unknown/debug/spree_lamport_clock.wasm"
+);
+
+/// The dummy parachain, as built by `build.sh`.
+pub const DUMMY_PARACHAIN_WASM: &str = concat!(
+	env!("CARGO_MANIFEST_DIR"),
+	"/../dummy-parachain/target/wasm32-unknown-unknown/debug/dummy_parachain.wasm"
+);
+
+/// Returns the path to the given prebuilt wasm binary, panicking if it is missing.
+pub fn prebuilt(path: &'static str) -> &'static str {
+	assert!(
+		Path::new(path).exists(),
+		"{} is missing, run ./build.sh first",
+		path
+	);
+	path
+}
+
+/// The lamport clock SPREE module, as built by `build.sh`, lowered so that wasmi can run it.
+pub fn lamport_clock() -> Wasm {
+	let binary = std::fs::read(prebuilt(LAMPORT_CLOCK_WASM)).expect("can read the prebuilt module");
+	temporary(&lowering::lower_to_mvp(&binary))
+}
+
+/// Write the given binary into a temporary file.
+fn temporary(binary: &[u8]) -> Wasm {
+	let mut file = tempfile::NamedTempFile::new().expect("can create a temporary file");
+	file.write_all(binary)
+		.expect("can write to a temporary file");
+	Wasm(file.into_temp_path())
+}
+
+/// A wasm binary compiled from the text format into a temporary file.
+///
+/// The file is removed when this is dropped.
+pub struct Wasm(TempPath);
+
+impl Wasm {
+	pub fn path(&self) -> &str {
+		self.0.to_str().expect("temporary paths are valid UTF-8")
+	}
+}
+
+/// Compile the given module in the text format into a temporary file.
+pub fn wat(source: &str) -> Wasm {
+	let binary = wat::parse_str(source).expect("the test module is valid");
+	temporary(&binary)
+}
+
+/// A SPREE module that leaves the given bytes in the scratch buffer upon every `handle`.
+pub fn replying_module(reply: &str) -> Wasm {
+	wat(&format!(
+		r#"(module
+			(import "env" "scratch_buf_write" (func $scratch_buf_write (param i32 i32)))
+			(memory (export "memory") 1)
+			(data (i32.const 0) "{reply}")
+			(func (export "handle") (param i32)
+				(call $scratch_buf_write (i32.const 0) (i32.const {len}))))"#,
+		reply = reply,
+		len = reply.len(),
+	))
+}
+
+/// A parachain that calls `call_spree` with the given handle and time slice and an empty blob.
+pub fn calling_parachain(handle: u32, time_slice: u32) -> Wasm {
+	wat(&format!(
+		r#"(module
+			(import "env" "call_spree" (func $call_spree (param i32 i32 i32 i32)))
+			(memory (export "memory") 1)
+			(func (export "validate_block")
+				(call $call_spree (i32.const {handle}) (i32.const {time_slice}) (i32.const 0) (i32.const 0))))"#,
+		handle = handle,
+		time_slice = time_slice,
+	))
+}
+
+/// A SPREE module that increments a counter in its storage upon every `handle` and leaves the
+/// new value in the scratch buffer as a little-endian `u64`.
+pub fn counter_module() -> Wasm {
+	wat(r#"(module
+		(import "env" "storage_increment" (func $storage_increment (param i32 i32 i64) (result i64)))
+		(import "env" "scratch_buf_write" (func $scratch_buf_write (param i32 i32)))
+		(memory (export "memory") 1)
+		(data (i32.const 0) "count")
+		(func (export "handle") (param i32)
+			(i64.store (i32.const 16)
+				(call $storage_increment (i32.const 0) (i32.const 5) (i64.const 1)))
+			(call $scratch_buf_write (i32.const 16) (i32.const 8))))"#)
+}
+
+/// Returns the little-endian `u64` left in the scratch buffer, e.g. by `counter_module`.
+pub fn scratch_u64(scratch: &[u8]) -> u64 {
+	let mut raw = [0; 8];
+	raw.copy_from_slice(scratch);
+	u64::from_le_bytes(raw)
+}
+
+/// A SPREE module that sends the blob it is invoked with to the parachain 1 and then polls,
+/// leaving the inbound bundles in the scratch buffer.
+pub fn echo_module() -> Wasm {
+	wat(r#"(module
+		(import "env" "scratch_buf_size" (func $scratch_buf_size (result i32)))
+		(import "env" "scratch_buf_read" (func $scratch_buf_read (param i32)))
+		(import "env" "send" (func $send (param i32 i32 i32) (result i32)))
+		(import "env" "poll" (func $poll))
+		(memory (export "memory") 2)
+		(func (export "handle") (param i32)
+			(local $size i32)
+			(local.set $size (call $scratch_buf_size))
+			(call $scratch_buf_read (i32.const 0))
+			(drop (call $send (i32.const 1) (i32.const 0) (local.get $size)))
+			(call $poll)))"#)
+}
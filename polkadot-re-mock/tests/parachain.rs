@@ -0,0 +1,467 @@
+mod common;
+
+use common::{calling_parachain, counter_module, replying_module, wat};
+use polkadot_re_mock::{
+	error::Error,
+	parachain::{self, ValidationConfig},
+	spree::{SpreeIcmpAccumulator, SpreeModule, SpreeRegistry},
+	util::DEFAULT_MEMORY_EXPORT,
+};
+use std::time::Duration;
+
+const PARA_ID: u32 = 100;
+
+fn new_module(wasm: &common::Wasm) -> SpreeModule {
+	SpreeModule::new(wasm.path(), SpreeIcmpAccumulator::with_inbound_msgs(vec![]))
+}
+
+#[test]
+fn handle_resolves_through_registry() {
+	let wasm_a = replying_module("a");
+	let wasm_b = replying_module("b");
+	let mut module_a = new_module(&wasm_a);
+	let mut module_b = new_module(&wasm_b);
+	let parachain = calling_parachain(42, 1000);
+
+	let mut spree_registry = SpreeRegistry::new();
+	spree_registry.register(7, &mut module_a).unwrap();
+	spree_registry.register(42, &mut module_b).unwrap();
+	let outcome = parachain::validate_block(
+		PARA_ID,
+		parachain.path(),
+		DEFAULT_MEMORY_EXPORT,
+		&mut spree_registry,
+		&mut ValidationConfig::default(),
+	)
+	.unwrap();
+	drop(spree_registry);
+
+	assert_eq!(outcome.spree_calls.len(), 1);
+	assert_eq!(outcome.spree_calls[0].handle, 42);
+	assert_eq!(module_b.last_scratch(), b"b");
+	assert!(!module_a.is_instantiated());
+	assert!(module_a.last_scratch().is_empty());
+}
+
+#[test]
+fn every_call_into_spree_is_recorded_with_its_duration() {
+	let wasm = replying_module("reply");
+	let mut spree_module = new_module(&wasm);
+	// Calls the module three times, with the blobs of 1, 2 and 3 bytes.
+	let parachain = wat(r#"(module
+		(import "env" "call_spree" (func $call_spree (param i32 i32 i32 i32)))
+		(memory (export "memory") 1)
+		(func (export "validate_block")
+			(call $call_spree (i32.const 0) (i32.const 1) (i32.const 0) (i32.const 1))
+			(call $call_spree (i32.const 0) (i32.const 2) (i32.const 0) (i32.const 2))
+			(call $call_spree (i32.const 0) (i32.const 3) (i32.const 0) (i32.const 3))))"#);
+
+	let mut spree_registry = SpreeRegistry::new();
+	spree_registry.register(0, &mut spree_module).unwrap();
+	let outcome = parachain::validate_block(
+		PARA_ID,
+		parachain.path(),
+		DEFAULT_MEMORY_EXPORT,
+		&mut spree_registry,
+		&mut ValidationConfig::default(),
+	)
+	.unwrap();
+
+	assert_eq!(
+		outcome
+			.spree_calls
+			.iter()
+			.map(|call| call.blob_len)
+			.collect::<Vec<_>>(),
+		vec![1, 2, 3]
+	);
+	for call in &outcome.spree_calls {
+		assert_eq!(call.handle, 0);
+		assert!(call.duration > Duration::from_secs(0), "{:?}", call);
+	}
+}
+
+/// A parachain that calls `call_spree` with the given handles in order.
+fn calling_handles(handles: &[u32]) -> common::Wasm {
+	let calls = handles
+		.iter()
+		.map(|handle| {
+			format!(
+				"(call $call_spree (i32.const {}) (i32.const 1) (i32.const 0) (i32.const 0))",
+				handle
+			)
+		})
+		.collect::<Vec<_>>()
+		.join("\n");
+	wat(&format!(
+		r#"(module
+			(import "env" "call_spree" (func $call_spree (param i32 i32 i32 i32)))
+			(memory (export "memory") 1)
+			(func (export "validate_block") {}))"#,
+		calls
+	))
+}
+
+#[test]
+fn distinct_spree_modules_per_block_are_capped() {
+	let wasm = replying_module("reply");
+	let mut modules = (0..3).map(|_| new_module(&wasm)).collect::<Vec<_>>();
+	let mut validate = |parachain: &common::Wasm| {
+		let mut spree_registry = SpreeRegistry::new();
+		for (handle, spree_module) in modules.iter_mut().enumerate() {
+			spree_registry
+				.register(handle as u32, spree_module)
+				.unwrap();
+		}
+		parachain::validate_block(
+			PARA_ID,
+			parachain.path(),
+			DEFAULT_MEMORY_EXPORT,
+			&mut spree_registry,
+			&mut ValidationConfig {
+				max_spree_modules: Some(2),
+				..ValidationConfig::default()
+			},
+		)
+	};
+
+	// Repeated calls to the same module don't count against the cap.
+	let outcome = validate(&calling_handles(&[0, 0, 1, 0, 1])).unwrap();
+	assert_eq!(outcome.spree_calls.len(), 5);
+
+	match validate(&calling_handles(&[0, 1, 0, 2])) {
+		Err(Error::SpreeModuleLimitExceeded { limit: 2 }) => {}
+		other => panic!("expected SpreeModuleLimitExceeded, got {:?}", other),
+	}
+}
+
+#[test]
+fn each_caller_reads_its_own_id() {
+	let wasm = wat(r#"(module
+		(import "env" "caller_para_id" (func $caller_para_id (result i32)))
+		(import "env" "scratch_buf_write" (func $scratch_buf_write (param i32 i32)))
+		(memory (export "memory") 1)
+		(func (export "handle") (param i32)
+			(i32.store (i32.const 0) (call $caller_para_id))
+			(call $scratch_buf_write (i32.const 0) (i32.const 4))))"#);
+	let mut spree_module = new_module(&wasm);
+	let parachain = calling_parachain(0, 1000);
+
+	for para_id in &[100u32, 200] {
+		let mut spree_registry = SpreeRegistry::new();
+		spree_registry.register(0, &mut spree_module).unwrap();
+		parachain::validate_block(
+			*para_id,
+			parachain.path(),
+			DEFAULT_MEMORY_EXPORT,
+			&mut spree_registry,
+			&mut ValidationConfig::default(),
+		)
+		.unwrap();
+		drop(spree_registry);
+		assert_eq!(spree_module.last_scratch(), &para_id.to_le_bytes()[..]);
+	}
+}
+
+#[test]
+fn parachain_can_import_memory() {
+	let wasm = wat(r#"(module
+		(import "env" "scratch_buf_size" (func $scratch_buf_size (result i32)))
+		(import "env" "scratch_buf_read" (func $scratch_buf_read (param i32)))
+		(import "env" "scratch_buf_write" (func $scratch_buf_write (param i32 i32)))
+		(memory (export "memory") 1)
+		(func (export "handle") (param i32)
+			(local $size i32)
+			(local.set $size (call $scratch_buf_size))
+			(call $scratch_buf_read (i32.const 0))
+			(call $scratch_buf_write (i32.const 0) (local.get $size))))"#);
+	let mut spree_module = new_module(&wasm);
+	let parachain = wat(r#"(module
+		(import "env" "memory" (memory 1))
+		(import "env" "call_spree" (func $call_spree (param i32 i32 i32 i32)))
+		(data (i32.const 0) "ping")
+		(func (export "validate_block")
+			(call $call_spree (i32.const 0) (i32.const 1000) (i32.const 0) (i32.const 4))))"#);
+
+	let mut spree_registry = SpreeRegistry::new();
+	spree_registry.register(0, &mut spree_module).unwrap();
+	let outcome = parachain::validate_block(
+		PARA_ID,
+		parachain.path(),
+		DEFAULT_MEMORY_EXPORT,
+		&mut spree_registry,
+		&mut ValidationConfig::default(),
+	)
+	.unwrap();
+	drop(spree_registry);
+
+	assert_eq!(outcome.spree_calls.len(), 1);
+	assert_eq!(spree_module.last_scratch(), b"ping");
+}
+
+#[test]
+fn memory_can_be_exported_under_a_configured_name() {
+	let wasm = wat(r#"(module
+		(import "env" "scratch_buf_size" (func $scratch_buf_size (result i32)))
+		(import "env" "scratch_buf_read" (func $scratch_buf_read (param i32)))
+		(import "env" "scratch_buf_write" (func $scratch_buf_write (param i32 i32)))
+		(memory (export "linear_mem") 1)
+		(func (export "handle") (param i32)
+			(local $size i32)
+			(local.set $size (call $scratch_buf_size))
+			(call $scratch_buf_read (i32.const 0))
+			(call $scratch_buf_write (i32.const 0) (local.get $size))))"#);
+	let parachain = wat(r#"(module
+		(import "env" "call_spree" (func $call_spree (param i32 i32 i32 i32)))
+		(memory (export "linear_mem") 1)
+		(data (i32.const 0) "ping")
+		(func (export "validate_block")
+			(call $call_spree (i32.const 0) (i32.const 1000) (i32.const 0) (i32.const 4))))"#);
+
+	// The module can't be instantiated unless the name is configured.
+	assert!(new_module(&wasm).invoke(PARA_ID, 1, vec![]).is_err());
+
+	let mut spree_module = new_module(&wasm).with_memory_export("linear_mem");
+	let mut spree_registry = SpreeRegistry::new();
+	spree_registry.register(0, &mut spree_module).unwrap();
+	parachain::validate_block(
+		PARA_ID,
+		parachain.path(),
+		"linear_mem",
+		&mut spree_registry,
+		&mut ValidationConfig::default(),
+	)
+	.unwrap();
+	drop(spree_registry);
+	assert_eq!(spree_module.last_scratch(), b"ping");
+}
+
+#[test]
+fn every_parachain_host_fn_is_served() {
+	let wasm = replying_module("ok");
+	let mut spree_module = new_module(&wasm);
+	let parachain = wat(r#"(module
+		(import "env" "call_spree" (func $call_spree (param i32 i32 i32 i32)))
+		(import "env" "storage_read" (func $storage_read (param i32 i32 i32 i32) (result i32)))
+		(import "env" "storage_write" (func $storage_write (param i32 i32 i32 i32)))
+		(import "env" "spree_result_read" (func $spree_result_read (param i32 i32 i32) (result i32)))
+		(import "env" "spree_has_inbound" (func $spree_has_inbound (param i32) (result i32)))
+		(memory (export "memory") 1)
+		(data (i32.const 0) "kvrn")
+		(func (export "validate_block")
+			(call $storage_write (i32.const 0) (i32.const 1) (i32.const 1) (i32.const 1))
+			(call $call_spree (i32.const 0) (i32.const 1000) (i32.const 0) (i32.const 0))
+			(i32.store8 (i32.const 16)
+				(call $spree_result_read (i32.const 0) (i32.const 32) (i32.const 8)))
+			(i32.store8 (i32.const 17)
+				(call $storage_read (i32.const 0) (i32.const 1) (i32.const 40) (i32.const 8)))
+			(i32.store8 (i32.const 18) (call $spree_has_inbound (i32.const 0)))
+			(call $storage_write (i32.const 2) (i32.const 1) (i32.const 32) (i32.const 2))
+			(call $storage_write (i32.const 3) (i32.const 1) (i32.const 16) (i32.const 3))))"#);
+
+	let mut spree_registry = SpreeRegistry::new();
+	spree_registry.register(0, &mut spree_module).unwrap();
+	let mut config = ValidationConfig::default();
+	parachain::validate_block(
+		PARA_ID,
+		parachain.path(),
+		DEFAULT_MEMORY_EXPORT,
+		&mut spree_registry,
+		&mut config,
+	)
+	.unwrap();
+
+	assert_eq!(config.storage[&b"k"[..]], b"v");
+	assert_eq!(config.storage[&b"r"[..]], b"ok");
+	// The length of the result, the length of the value and no inbound messages.
+	assert_eq!(config.storage[&b"n"[..]], [2, 1, 0]);
+}
+
+#[test]
+fn instance_is_reused_across_blocks() {
+	let wasm = replying_module("ok");
+	let mut spree_module = new_module(&wasm);
+	// Counts the blocks in a global and stores the count under "n" after every block.
+	let parachain = wat(r#"(module
+		(import "env" "call_spree" (func $call_spree (param i32 i32 i32 i32)))
+		(import "env" "storage_write" (func $storage_write (param i32 i32 i32 i32)))
+		(memory (export "memory") 1)
+		(global $blocks (mut i32) (i32.const 0))
+		(data (i32.const 0) "n")
+		(func (export "validate_block")
+			(global.set $blocks (i32.add (global.get $blocks) (i32.const 1)))
+			(i32.store (i32.const 8) (global.get $blocks))
+			(call $call_spree (i32.const 0) (i32.const 1000) (i32.const 0) (i32.const 0))
+			(call $storage_write (i32.const 0) (i32.const 1) (i32.const 8) (i32.const 4))))"#);
+	let instance = parachain::instantiate(parachain.path(), DEFAULT_MEMORY_EXPORT).unwrap();
+	// The binary is not needed anymore.
+	drop(parachain);
+
+	let mut config = ValidationConfig::default();
+	for block in 1..=3u32 {
+		let mut spree_registry = SpreeRegistry::new();
+		spree_registry.register(0, &mut spree_module).unwrap();
+		let outcome = parachain::validate_block_with_instance(
+			PARA_ID,
+			&instance,
+			&mut spree_registry,
+			&mut config,
+		)
+		.unwrap();
+		assert_eq!(outcome.spree_calls.len(), 1);
+		assert_eq!(config.storage[&b"n"[..]], block.to_le_bytes());
+	}
+}
+
+#[test]
+fn parachain_storage_persists_across_blocks() {
+	// Reads the counter under "c", treating an absent one as zero, and writes it back incremented.
+	let parachain = wat(r#"(module
+		(import "env" "storage_read" (func $storage_read (param i32 i32 i32 i32) (result i32)))
+		(import "env" "storage_write" (func $storage_write (param i32 i32 i32 i32)))
+		(memory (export "memory") 1)
+		(data (i32.const 0) "c")
+		(func (export "validate_block")
+			(drop (call $storage_read (i32.const 0) (i32.const 1) (i32.const 8) (i32.const 4)))
+			(i32.store (i32.const 8) (i32.add (i32.load (i32.const 8)) (i32.const 1)))
+			(call $storage_write (i32.const 0) (i32.const 1) (i32.const 8) (i32.const 4))))"#);
+
+	let mut config = ValidationConfig::default();
+	for block in 1..=2u32 {
+		// Every block runs on a fresh instance, so the count survives only in the storage.
+		let mut spree_registry = SpreeRegistry::new();
+		parachain::validate_block(
+			PARA_ID,
+			parachain.path(),
+			DEFAULT_MEMORY_EXPORT,
+			&mut spree_registry,
+			&mut config,
+		)
+		.unwrap();
+		assert_eq!(config.storage[&b"c"[..]], block.to_le_bytes());
+	}
+}
+
+#[test]
+fn validate_block_with_a_wrong_signature_is_rejected() {
+	let parachain = wat(r#"(module
+		(memory (export "memory") 1)
+		(func (export "validate_block") (param i32)))"#);
+	match parachain::instantiate(parachain.path(), DEFAULT_MEMORY_EXPORT) {
+		Err(Error::BadEntrypointSignature {
+			name,
+			expected,
+			found,
+			..
+		}) => {
+			assert_eq!(name, "validate_block");
+			assert_eq!(expected, "() -> ()");
+			assert_eq!(found, "(i32) -> ()");
+		}
+		result => panic!(
+			"expected BadEntrypointSignature, got {:?}",
+			result.map(|_| ())
+		),
+	}
+}
+
+#[test]
+fn polling_an_empty_spree_module_is_skipped() {
+	let wasm = replying_module("reply");
+	let mut empty = new_module(&wasm);
+	let mut pending = SpreeModule::new(
+		wasm.path(),
+		SpreeIcmpAccumulator::with_inbound_msgs(vec![(1, b"foo".to_vec())]),
+	);
+	// Calls each of the modules 0 and 1 only if it has inbound messages, like the dummy parachain.
+	let parachain = wat(r#"(module
+		(import "env" "call_spree" (func $call_spree (param i32 i32 i32 i32)))
+		(import "env" "spree_has_inbound" (func $spree_has_inbound (param i32) (result i32)))
+		(memory (export "memory") 1)
+		(func $poll_if_pending (param $handle i32)
+			(if (i32.eq (call $spree_has_inbound (local.get $handle)) (i32.const 1))
+				(then (call $call_spree (local.get $handle) (i32.const 1) (i32.const 0) (i32.const 0)))))
+		(func (export "validate_block")
+			(call $poll_if_pending (i32.const 0))
+			(call $poll_if_pending (i32.const 1))))"#);
+
+	let mut spree_registry = SpreeRegistry::new();
+	spree_registry.register(0, &mut empty).unwrap();
+	spree_registry.register(1, &mut pending).unwrap();
+	let outcome = parachain::validate_block(
+		PARA_ID,
+		parachain.path(),
+		DEFAULT_MEMORY_EXPORT,
+		&mut spree_registry,
+		&mut ValidationConfig::default(),
+	)
+	.unwrap();
+	drop(spree_registry);
+
+	assert_eq!(
+		outcome
+			.spree_calls
+			.iter()
+			.map(|call| call.handle)
+			.collect::<Vec<_>>(),
+		vec![1]
+	);
+	assert!(!empty.is_instantiated());
+	assert_eq!(pending.last_scratch(), b"reply");
+}
+
+#[test]
+fn error_of_a_spree_module_is_recovered_through_the_parachain() {
+	let wasm = counter_module();
+	// The counter makes two host calls, the second one runs out of gas.
+	let mut spree_module = new_module(&wasm).with_gas_limit(1);
+	let parachain = calling_parachain(0, 1);
+
+	let mut spree_registry = SpreeRegistry::new();
+	spree_registry.register(0, &mut spree_module).unwrap();
+	match parachain::validate_block(
+		PARA_ID,
+		parachain.path(),
+		DEFAULT_MEMORY_EXPORT,
+		&mut spree_registry,
+		&mut ValidationConfig::default(),
+	) {
+		Err(Error::OutOfGas) => {}
+		other => panic!("expected OutOfGas, got {:?}", other.map(|_| ())),
+	}
+}
+
+#[test]
+fn identical_binaries_share_the_parsed_module() {
+	let counter = counter_module();
+	let replying = replying_module("reply");
+	let mut module_a = new_module(&counter);
+	let mut module_b = new_module(&counter);
+	let mut module_c = new_module(&replying);
+
+	let mut spree_registry = SpreeRegistry::new().with_module_sharing();
+	spree_registry.register(0, &mut module_a).unwrap();
+	spree_registry.register(1, &mut module_b).unwrap();
+	spree_registry.register(2, &mut module_c).unwrap();
+	assert_eq!(spree_registry.parsed_modules(), 2);
+	spree_registry.invoke(0, PARA_ID, 1, vec![]).unwrap();
+	spree_registry.invoke(0, PARA_ID, 2, vec![]).unwrap();
+	spree_registry.invoke(1, PARA_ID, 1, vec![]).unwrap();
+	drop(spree_registry);
+
+	assert!(module_a.shares_parsed_module(&module_b));
+	assert!(!module_a.shares_parsed_module(&module_c));
+	// The instances and the storage are still separate.
+	assert_eq!(common::scratch_u64(module_a.last_scratch()), 2);
+	assert_eq!(common::scratch_u64(module_b.last_scratch()), 1);
+
+	// Without sharing every module parses the binary on its own.
+	let mut module_d = new_module(&counter);
+	let mut module_e = new_module(&counter);
+	let mut spree_registry = SpreeRegistry::new();
+	spree_registry.register(0, &mut module_d).unwrap();
+	spree_registry.register(1, &mut module_e).unwrap();
+	assert_eq!(spree_registry.parsed_modules(), 0);
+	drop(spree_registry);
+	assert!(!module_d.shares_parsed_module(&module_e));
+}
@@ -0,0 +1,132 @@
+mod common;
+
+use common::{counter_module, replying_module, wat};
+use polkadot_re_mock::scenario::{ModuleSpec, Scenario, ScenarioRun};
+
+/// A parachain that alternates between calling the modules under handles 0 and 1, starting with
+/// 0, keeping the handle to call next in its storage.
+const ALTERNATING_PARACHAIN: &str = r#"(module
+	(import "env" "call_spree" (func $call_spree (param i32 i32 i32 i32)))
+	(import "env" "storage_read" (func $storage_read (param i32 i32 i32 i32) (result i32)))
+	(import "env" "storage_write" (func $storage_write (param i32 i32 i32 i32)))
+	(memory (export "memory") 1)
+	(data (i32.const 0) "next")
+	(func (export "validate_block")
+		(drop (call $storage_read (i32.const 0) (i32.const 4) (i32.const 8) (i32.const 4)))
+		(call $call_spree (i32.load (i32.const 8)) (i32.const 1) (i32.const 0) (i32.const 0))
+		(i32.store (i32.const 8) (i32.xor (i32.load (i32.const 8)) (i32.const 1)))
+		(call $storage_write (i32.const 0) (i32.const 4) (i32.const 8) (i32.const 4))))"#;
+
+fn module_spec(name: &str, wasm: &common::Wasm) -> ModuleSpec {
+	ModuleSpec {
+		name: name.to_string(),
+		path: wasm.path().to_string(),
+		inbound: vec![],
+	}
+}
+
+#[test]
+fn instance_limit_applies_across_blocks() {
+	let module = counter_module();
+	let parachain = wat(ALTERNATING_PARACHAIN);
+	let scenario = Scenario {
+		parachain: parachain.path().to_string(),
+		modules: vec![module_spec("a", &module), module_spec("b", &module)],
+		max_instances: Some(1),
+		..Scenario::default()
+	};
+
+	let mut run = scenario.run().unwrap();
+	assert!(run.modules["a"].is_instantiated());
+
+	run.run_block(&scenario).unwrap();
+	assert!(run.modules["b"].is_instantiated());
+	assert!(!run.modules["a"].is_instantiated());
+
+	// The evicted module is instantiated again on demand with its storage intact.
+	run.run_block(&scenario).unwrap();
+	assert!(!run.modules["b"].is_instantiated());
+	assert_eq!(common::scratch_u64(run.modules["a"].last_scratch()), 2);
+}
+
+#[test]
+fn two_module_scenario_runs_programmatically() {
+	let replying = replying_module("a");
+	let counter = counter_module();
+	// Calls the modules under handles 0 and 1 within the same block.
+	let parachain = wat(r#"(module
+		(import "env" "call_spree" (func $call_spree (param i32 i32 i32 i32)))
+		(memory (export "memory") 1)
+		(func (export "validate_block")
+			(call $call_spree (i32.const 0) (i32.const 1) (i32.const 0) (i32.const 0))
+			(call $call_spree (i32.const 1) (i32.const 1) (i32.const 0) (i32.const 0))))"#);
+	let scenario = Scenario {
+		para_id: 100,
+		parachain: parachain.path().to_string(),
+		modules: vec![
+			module_spec("replying", &replying),
+			ModuleSpec {
+				inbound: vec![(7, b"hi".to_vec())],
+				..module_spec("counter", &counter)
+			},
+		],
+		..Scenario::default()
+	};
+
+	let run = scenario.run().unwrap();
+	assert_eq!(run.block_number, 1);
+	assert_eq!(
+		run.outcome
+			.spree_calls
+			.iter()
+			.map(|call| call.handle)
+			.collect::<Vec<_>>(),
+		vec![0, 1]
+	);
+	assert_eq!(run.modules["replying"].last_scratch(), b"a");
+	assert_eq!(
+		common::scratch_u64(run.modules["counter"].last_scratch()),
+		1
+	);
+	// The inbound messages from the spec are left for the module to poll.
+	assert!(run.modules["counter"].has_inbound());
+	assert!(!run.modules["replying"].has_inbound());
+}
+
+#[test]
+fn restoring_a_checkpoint_resumes_as_a_continuous_run() {
+	let module = counter_module();
+	let parachain = wat(ALTERNATING_PARACHAIN);
+	let scenario = Scenario {
+		parachain: parachain.path().to_string(),
+		modules: vec![module_spec("a", &module), module_spec("b", &module)],
+		..Scenario::default()
+	};
+	let run_blocks = |run: &mut ScenarioRun, n| {
+		for _ in 0..n {
+			run.run_block(&scenario).unwrap();
+		}
+	};
+
+	let mut continuous = scenario.run().unwrap();
+	run_blocks(&mut continuous, 4);
+
+	let mut resumed = scenario.run().unwrap();
+	run_blocks(&mut resumed, 1);
+	let checkpoint = resumed.checkpoint();
+	// Diverge from the continuous run, then go back to the checkpoint.
+	run_blocks(&mut resumed, 3);
+	resumed.restore(&checkpoint).unwrap();
+	assert_eq!(resumed.checkpoint(), checkpoint);
+	run_blocks(&mut resumed, 3);
+
+	assert_eq!(resumed.block_number, 5);
+	assert_eq!(resumed.checkpoint(), continuous.checkpoint());
+
+	// A fresh run picks up from the checkpoint just as well.
+	let mut fresh = scenario.run().unwrap();
+	fresh.restore(&checkpoint).unwrap();
+	run_blocks(&mut fresh, 3);
+	assert_eq!(fresh.checkpoint(), continuous.checkpoint());
+	assert_eq!(common::scratch_u64(fresh.modules["a"].last_scratch()), 3);
+}
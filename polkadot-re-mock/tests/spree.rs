@@ -0,0 +1,1550 @@
+mod common;
+
+use codec::{Decode, Encode};
+use common::{
+	counter_module, echo_module, lamport_clock, prebuilt, replying_module, scratch_u64, wat,
+	LAMPORT_CLOCK_WASM,
+};
+use polkadot_re_mock::{
+	error::Error,
+	failure::{FailureInjector, InjectedFailure},
+	spree::{
+		route_messages, route_messages_with_partitions, Compression, DropReason, HostFunction,
+		HostResult, InvokeOutcome, InvokeRecord, Partitions, SpreeIcmpAccumulator, SpreeModule,
+		SpreeModuleHostEnv, SpreeRegistry, BUILTIN_HOST_FNS,
+	},
+};
+use spree_lamport_clock_primitives::{
+	capabilities, Req, SendError, StatusCode, TargetedMsg, TimestampedMsg,
+};
+use std::collections::{BTreeMap, HashMap};
+use wasmi::{RuntimeValue, ValueType};
+
+const CALLER: u32 = 100;
+
+fn new_module(wasm: &common::Wasm) -> SpreeModule {
+	SpreeModule::new(wasm.path(), SpreeIcmpAccumulator::with_inbound_msgs(vec![]))
+}
+
+/// Decode the little-endian `u64`s the module left in the scratch buffer.
+fn scratch_u64s(spree_module: &SpreeModule) -> Vec<u64> {
+	spree_module
+		.last_scratch()
+		.chunks(8)
+		.map(scratch_u64)
+		.collect()
+}
+
+/// Calls `gas_left` three times and returns the results in the scratch buffer.
+const GAS_LEFT_WAT: &str = r#"(module
+	(import "env" "gas_left" (func $gas_left (result i64)))
+	(import "env" "scratch_buf_write" (func $scratch_buf_write (param i32 i32)))
+	(memory (export "memory") 1)
+	(func (export "handle") (param i32)
+		(i64.store (i32.const 0) (call $gas_left))
+		(i64.store (i32.const 8) (call $gas_left))
+		(i64.store (i32.const 16) (call $gas_left))
+		(call $scratch_buf_write (i32.const 0) (i32.const 24))))"#;
+
+#[test]
+fn gas_left_decreases_across_host_calls() {
+	let wasm = wat(GAS_LEFT_WAT);
+	let mut spree_module = new_module(&wasm).with_gas_limit(100);
+	spree_module.invoke(CALLER, 1, vec![]).unwrap();
+	// Every host call, including `gas_left` itself, is charged before it runs.
+	assert_eq!(scratch_u64s(&spree_module), vec![99, 98, 97]);
+}
+
+#[test]
+fn memory_size_pages_reports_the_declared_memory() {
+	let wasm = wat(r#"(module
+			(import "env" "memory_size_pages" (func $memory_size_pages (result i32)))
+			(import "env" "memory_grow" (func $memory_grow (param i32) (result i32)))
+			(import "env" "scratch_buf_write" (func $scratch_buf_write (param i32 i32)))
+			(memory (export "memory") 3 6)
+			(func (export "handle") (param i32)
+				(i32.store (i32.const 0) (call $memory_size_pages))
+				(i32.store (i32.const 4) (call $memory_grow (i32.const 2)))
+				(i32.store (i32.const 8) (call $memory_size_pages))
+				;; Growing past the declared maximum fails and leaves the memory as is.
+				(i32.store (i32.const 12) (call $memory_grow (i32.const 2)))
+				(i32.store (i32.const 16) (memory.size))
+				(call $scratch_buf_write (i32.const 0) (i32.const 20))))"#);
+	let mut spree_module = new_module(&wasm);
+	spree_module.invoke(CALLER, 1, vec![]).unwrap();
+	let pages = spree_module
+		.last_scratch()
+		.chunks(4)
+		.map(|chunk| i32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]))
+		.collect::<Vec<_>>();
+	assert_eq!(pages, vec![3, 3, 5, -1, 5]);
+}
+
+#[test]
+fn gas_is_unlimited_by_default() {
+	let wasm = wat(GAS_LEFT_WAT);
+	let mut spree_module = new_module(&wasm);
+	// The time slice has nothing to do with the gas.
+	spree_module.invoke(CALLER, 1, vec![]).unwrap();
+	assert_eq!(scratch_u64s(&spree_module), vec![u64::MAX; 3]);
+}
+
+#[test]
+fn running_out_of_gas_fails_the_invocation() {
+	let wasm = wat(GAS_LEFT_WAT);
+	let mut spree_module = new_module(&wasm).with_gas_limit(2);
+	match spree_module.invoke(CALLER, 1, vec![]) {
+		Err(polkadot_re_mock::error::Error::OutOfGas) => {}
+		result => panic!("expected OutOfGas, got {:?}", result),
+	}
+}
+
+#[test]
+fn last_time_slice_is_kept_by_the_host() {
+	let wasm = wat(r#"(module
+		(import "env" "last_time_slice" (func $last_time_slice (result i64)))
+		(import "env" "scratch_buf_write" (func $scratch_buf_write (param i32 i32)))
+		(memory (export "memory") 1)
+		(func (export "handle") (param i32)
+			(i64.store (i32.const 0) (call $last_time_slice))
+			(call $scratch_buf_write (i32.const 0) (i32.const 8))))"#);
+	// The time slice is kept by the host, so no storage access is needed to read it.
+	let mut spree_module =
+		new_module(&wasm).with_capabilities(capabilities::ALL & !capabilities::STORAGE);
+	assert_eq!(spree_module.last_time_slice(), None);
+
+	spree_module.invoke(CALLER, 1, vec![]).unwrap();
+	assert_eq!(scratch_u64(spree_module.last_scratch()) as i64, -1);
+	spree_module.invoke(CALLER, 5, vec![]).unwrap();
+	assert_eq!(scratch_u64(spree_module.last_scratch()), 1);
+	assert_eq!(spree_module.last_time_slice(), Some(5));
+
+	// Nothing is written to the storage of the module.
+	assert!(spree_module.storage_snapshot().is_empty());
+}
+
+/// Writes the storage ten times per invocation.
+const WRITE_LOOP_WAT: &str = r#"(module
+	(import "env" "storage_write" (func $storage_write (param i32 i32 i32 i32)))
+	(memory (export "memory") 1)
+	(data (i32.const 0) "key")
+	(func (export "handle") (param i32)
+		(local $i i32)
+		(loop $again
+			(call $storage_write (i32.const 0) (i32.const 3) (local.get $i) (i32.const 1))
+			(local.set $i (i32.add (local.get $i) (i32.const 1)))
+			(br_if $again (i32.lt_u (local.get $i) (i32.const 10))))))"#;
+
+#[test]
+fn exceeding_the_host_call_cap_traps() {
+	let wasm = wat(WRITE_LOOP_WAT);
+	let mut spree_module = new_module(&wasm).with_max_host_calls(5);
+	match spree_module.invoke(CALLER, 1, vec![]) {
+		Err(Error::HostCallLimitExceeded { limit: 5 }) => {}
+		result => panic!("expected HostCallLimitExceeded, got {:?}", result),
+	}
+	// The writes made before hitting the cap are rolled back.
+	assert!(spree_module.storage_snapshot().is_empty());
+
+	// Exactly as many calls as allowed is fine.
+	let mut spree_module = new_module(&wasm).with_max_host_calls(10);
+	spree_module.invoke(CALLER, 1, vec![]).unwrap();
+	assert_eq!(spree_module.storage_snapshot().len(), 1);
+}
+
+#[test]
+fn storage_increment_produces_a_sequence_with_a_host_call_each() {
+	let wasm = wat(r#"(module
+		(import "env" "storage_increment" (func $storage_increment (param i32 i32 i64) (result i64)))
+		(import "env" "scratch_buf_write" (func $scratch_buf_write (param i32 i32)))
+		(memory (export "memory") 1)
+		(data (i32.const 0) "count")
+		(func (export "handle") (param i32)
+			(i64.store (i32.const 16)
+				(call $storage_increment (i32.const 0) (i32.const 5) (i64.const 2)))
+			(i64.store (i32.const 24)
+				(call $storage_increment (i32.const 0) (i32.const 5) (i64.const 2)))
+			(i64.store (i32.const 32)
+				(call $storage_increment (i32.const 0) (i32.const 5) (i64.const 2)))
+			(call $scratch_buf_write (i32.const 16) (i32.const 24))))"#);
+	let mut spree_module = new_module(&wasm);
+	spree_module.invoke(CALLER, 1, vec![]).unwrap();
+	// The absent counter starts from 0.
+	assert_eq!(scratch_u64s(&spree_module), vec![2, 4, 6]);
+	// The three increments and the write of the scratch buffer.
+	assert_eq!(spree_module.last_metrics().host_calls, 4);
+
+	spree_module.invoke(CALLER, 2, vec![]).unwrap();
+	assert_eq!(scratch_u64s(&spree_module), vec![8, 10, 12]);
+}
+
+#[test]
+fn instantiation_time_is_recorded_once() {
+	let wasm = counter_module();
+	let mut spree_module = new_module(&wasm);
+	assert_eq!(spree_module.instantiation_time(), None);
+
+	spree_module.invoke(CALLER, 1, vec![]).unwrap();
+	let recorded = spree_module
+		.instantiation_time()
+		.expect("the module was instantiated");
+	spree_module.invoke(CALLER, 2, vec![]).unwrap();
+	assert_eq!(spree_module.instantiation_time(), Some(recorded));
+
+	// Not even instantiating the module again updates it.
+	spree_module.evict_instance();
+	spree_module.invoke(CALLER, 3, vec![]).unwrap();
+	assert_eq!(spree_module.instantiation_time(), Some(recorded));
+}
+
+#[test]
+fn validated_construction_fails_immediately() {
+	let acc = || SpreeIcmpAccumulator::with_inbound_msgs(vec![]);
+	match SpreeModule::new_validated("/nonexistent/module.wasm", acc()) {
+		Err(Error::Io(e)) => assert_eq!(e.kind(), std::io::ErrorKind::NotFound),
+		result => panic!("expected an I/O error, got {:?}", result.map(|_| ())),
+	}
+
+	// An invalid binary is rejected as well, still without instantiating anything.
+	let mut file = tempfile::NamedTempFile::new().unwrap();
+	std::io::Write::write_all(&mut file, b"not wasm").unwrap();
+	let path = file.path().to_str().unwrap();
+	match SpreeModule::new_validated(path, acc()) {
+		Err(Error::Interpreter(_)) => {}
+		result => panic!(
+			"expected an interpreter error, got {:?}",
+			result.map(|_| ())
+		),
+	}
+
+	// The plain constructor defers the failure to the first invocation.
+	let mut spree_module = SpreeModule::new("/nonexistent/module.wasm", acc());
+	assert!(spree_module.invoke(CALLER, 1, vec![]).is_err());
+}
+
+#[test]
+fn handle_with_a_wrong_signature_is_rejected() {
+	let wasm = wat(r#"(module
+		(memory (export "memory") 1)
+		(func (export "handle") (result i64) (i64.const 0)))"#);
+	let mut spree_module = new_module(&wasm);
+	match spree_module.invoke(CALLER, 1, vec![]) {
+		Err(Error::BadEntrypointSignature {
+			name,
+			expected,
+			found,
+			..
+		}) => {
+			assert_eq!(name, "handle");
+			assert_eq!(expected, "(i32) -> ()");
+			assert_eq!(found, "() -> i64");
+		}
+		result => panic!("expected BadEntrypointSignature, got {:?}", result),
+	}
+}
+
+#[test]
+fn last_scratch_holds_what_the_module_left() {
+	let wasm = replying_module("hello");
+	let mut spree_module = new_module(&wasm);
+	assert!(spree_module.last_scratch().is_empty());
+	spree_module.invoke(CALLER, 1, b"request".to_vec()).unwrap();
+	assert_eq!(spree_module.last_scratch(), b"hello");
+
+	// A module that doesn't touch the scratch buffer leaves the input in place.
+	let wasm = wat(r#"(module
+		(memory (export "memory") 1)
+		(func (export "handle") (param i32)))"#);
+	let mut spree_module = new_module(&wasm);
+	spree_module.invoke(CALLER, 1, b"request".to_vec()).unwrap();
+	assert_eq!(spree_module.last_scratch(), b"request");
+}
+
+#[test]
+fn registry_evicts_least_recently_used_instances() {
+	let wasm = counter_module();
+	let mut modules = [new_module(&wasm), new_module(&wasm), new_module(&wasm)];
+	let mut spree_registry = SpreeRegistry::with_max_instances(2);
+	for (handle, spree_module) in modules.iter_mut().enumerate() {
+		spree_registry
+			.register(handle as u32, spree_module)
+			.unwrap();
+	}
+
+	for handle in 0..3 {
+		spree_registry.invoke(handle, CALLER, 1, vec![]).unwrap();
+	}
+	assert_eq!(spree_registry.instantiated(), &[1, 2]);
+	assert!(!spree_registry.get_mut(0).unwrap().is_instantiated());
+
+	// The evicted module is instantiated again with its storage intact.
+	spree_registry.invoke(0, CALLER, 1, vec![]).unwrap();
+	assert_eq!(spree_registry.instantiated(), &[2, 0]);
+	assert!(!spree_registry.get_mut(1).unwrap().is_instantiated());
+	assert_eq!(
+		scratch_u64(spree_registry.get_mut(0).unwrap().last_scratch()),
+		2
+	);
+}
+
+#[test]
+fn registry_accounts_for_instances_left_by_previous_registries() {
+	let wasm = counter_module();
+	let mut module_a = new_module(&wasm);
+	let mut module_b = new_module(&wasm);
+	module_a.invoke(CALLER, 1, vec![]).unwrap();
+
+	let mut spree_registry = SpreeRegistry::with_max_instances(1);
+	spree_registry.register(0, &mut module_a).unwrap();
+	spree_registry.register(1, &mut module_b).unwrap();
+	spree_registry.invoke(1, CALLER, 1, vec![]).unwrap();
+	assert_eq!(spree_registry.instantiated(), &[1]);
+	drop(spree_registry);
+	assert!(!module_a.is_instantiated());
+}
+
+#[test]
+fn lz4_compression_is_transparent_to_the_module() {
+	let payload = vec![b'a'; 4096];
+	let wasm = echo_module();
+	let acc = SpreeIcmpAccumulator::with_inbound_msgs(vec![(
+		2,
+		lz4_flex::compress_prepend_size(&payload),
+	)])
+	.with_compression(Compression::Lz4);
+	let mut spree_module = SpreeModule::new(wasm.path(), acc);
+	spree_module.invoke(CALLER, 1, payload.clone()).unwrap();
+
+	let on_wire = &spree_module.outbound_messages()[&1];
+	assert!(on_wire.len() < payload.len());
+	assert_eq!(
+		lz4_flex::decompress_size_prepended(on_wire).unwrap(),
+		payload
+	);
+	let polled = <Vec<(u32, Vec<u8>)>>::decode(&mut spree_module.last_scratch()).unwrap();
+	assert_eq!(polled, vec![(2, payload)]);
+}
+
+#[test]
+fn oversized_inbound_bundle_is_rejected_at_poll() {
+	let wasm = echo_module();
+	let acc = SpreeIcmpAccumulator::with_inbound_msgs(vec![(2, vec![1; 16]), (3, vec![2; 17])]);
+	let mut spree_module = SpreeModule::new(wasm.path(), acc).with_max_inbound_bundle(16);
+	spree_module.invoke(CALLER, 1, vec![]).unwrap();
+
+	let polled = <Vec<(u32, Vec<u8>)>>::decode(&mut spree_module.last_scratch()).unwrap();
+	assert_eq!(polled, vec![(2, vec![1; 16])]);
+	assert_eq!(spree_module.last_metrics().rejected_inbound, vec![3]);
+}
+
+#[test]
+fn globals_persist_while_the_instance_is_cached() {
+	let wasm = wat(r#"(module
+		(memory (export "memory") 1)
+		(global $invocations (mut i32) (i32.const 0))
+		(global (export "version") i32 (i32.const 7))
+		(func (export "handle") (param i32)
+			(global.set $invocations (i32.add (global.get $invocations) (i32.const 1)))))"#);
+	let mut spree_module = new_module(&wasm);
+	assert_eq!(
+		spree_module.read_global("version").unwrap(),
+		RuntimeValue::I32(7)
+	);
+	assert!(spree_module.read_global("missing").is_err());
+
+	for _ in 0..3 {
+		spree_module.invoke(CALLER, 1, vec![]).unwrap();
+	}
+	assert_eq!(
+		spree_module.read_global_at(0).unwrap(),
+		RuntimeValue::I32(3)
+	);
+
+	spree_module.evict_instance();
+	assert_eq!(
+		spree_module.read_global_at(0).unwrap(),
+		RuntimeValue::I32(0)
+	);
+	assert!(spree_module.read_global_at(2).is_err());
+}
+
+#[test]
+fn host_can_clear_the_storage() {
+	let wasm = counter_module();
+	let mut spree_module = new_module(&wasm);
+	spree_module.invoke(CALLER, 1, vec![]).unwrap();
+	spree_module.invoke(CALLER, 2, vec![]).unwrap();
+	assert_eq!(scratch_u64(spree_module.last_scratch()), 2);
+
+	spree_module.clear_storage();
+	assert!(spree_module.storage_snapshot().is_empty());
+	spree_module.invoke(CALLER, 3, vec![]).unwrap();
+	assert_eq!(scratch_u64(spree_module.last_scratch()), 1);
+}
+
+/// Increments the counter under "count", but wipes the storage when invoked in the time slice 0.
+const CLEARING_WAT: &str = r#"(module
+	(import "env" "storage_increment" (func $storage_increment (param i32 i32 i64) (result i64)))
+	(import "env" "storage_clear" (func $storage_clear))
+	(memory (export "memory") 1)
+	(data (i32.const 0) "count")
+	(func (export "handle") (param $time_slice i32)
+		(if (i32.eqz (local.get $time_slice))
+			(then (call $storage_clear))
+			(else (drop (call $storage_increment (i32.const 0) (i32.const 5) (i64.const 1)))))))"#;
+
+#[test]
+fn storage_clear_requires_the_capability() {
+	let wasm = wat(CLEARING_WAT);
+	let mut spree_module = new_module(&wasm);
+	// The module imports `storage_clear`, so it can't even be instantiated.
+	assert!(spree_module.invoke(CALLER, 1, vec![]).is_err());
+	assert!(spree_module.storage_snapshot().is_empty());
+
+	spree_module.set_allow_storage_clear(true);
+	spree_module.invoke(CALLER, 1, vec![]).unwrap();
+	assert!(spree_module.storage_snapshot().contains_key(&b"count"[..]));
+	spree_module.invoke(CALLER, 0, vec![]).unwrap();
+	assert!(!spree_module.storage_snapshot().contains_key(&b"count"[..]));
+
+	// Revoking the capability applies to the already instantiated module as well.
+	spree_module.set_allow_storage_clear(false);
+	assert!(spree_module.invoke(CALLER, 0, vec![]).is_err());
+}
+
+#[test]
+fn zero_length_send_is_rejected() {
+	let wasm = wat(r#"(module
+		(import "env" "send" (func $send (param i32 i32 i32) (result i32)))
+		(import "env" "scratch_buf_write" (func $scratch_buf_write (param i32 i32)))
+		(memory (export "memory") 1)
+		(func (export "handle") (param i32)
+			(i32.store (i32.const 0) (call $send (i32.const 1) (i32.const 0) (i32.const 0)))
+			(call $scratch_buf_write (i32.const 0) (i32.const 4))))"#);
+	let mut spree_module = new_module(&wasm);
+	spree_module.invoke(CALLER, 1, vec![]).unwrap();
+	assert_eq!(
+		spree_module.last_scratch(),
+		&StatusCode::EmptyBlob.as_i32().to_le_bytes()[..]
+	);
+	assert!(spree_module.outbound_messages().is_empty());
+}
+
+#[test]
+fn send_to_a_reserved_para_id_is_rejected() {
+	let wasm = wat(r#"(module
+		(import "env" "send" (func $send (param i32 i32 i32) (result i32)))
+		(import "env" "scratch_buf_write" (func $scratch_buf_write (param i32 i32)))
+		(memory (export "memory") 1)
+		(data (i32.const 0) "x")
+		(func (export "handle") (param i32)
+			(i32.store (i32.const 16) (call $send (i32.const 0) (i32.const 0) (i32.const 1)))
+			(i32.store (i32.const 20) (call $send (i32.const 5) (i32.const 0) (i32.const 1)))
+			(call $scratch_buf_write (i32.const 16) (i32.const 8))))"#);
+	let mut spree_module = SpreeModule::new(
+		wasm.path(),
+		SpreeIcmpAccumulator::with_inbound_msgs(vec![]).with_reserved_para_ids(vec![0]),
+	);
+	spree_module.invoke(CALLER, 1, vec![]).unwrap();
+
+	let statuses = spree_module
+		.last_scratch()
+		.chunks(4)
+		.map(|raw| i32::from_le_bytes([raw[0], raw[1], raw[2], raw[3]]))
+		.collect::<Vec<_>>();
+	assert_eq!(
+		statuses,
+		vec![
+			StatusCode::ReservedRecepient.as_i32(),
+			StatusCode::Ok.as_i32()
+		]
+	);
+	assert_eq!(
+		spree_module.outbound_messages().keys().collect::<Vec<_>>(),
+		vec![&5]
+	);
+}
+
+#[test]
+fn scratch_buffer_can_be_read_in_chunks() {
+	// Reads the request in two chunks, the second one clamped, and leaves the reassembled
+	// request along with the byte counts in the scratch buffer.
+	let wasm = wat(r#"(module
+		(import "env" "scratch_buf_read_at" (func $read_at (param i32 i32 i32) (result i32)))
+		(import "env" "scratch_buf_write" (func $scratch_buf_write (param i32 i32)))
+		(memory (export "memory") 1)
+		(func (export "handle") (param i32)
+			(local $first i32)
+			(local $second i32)
+			(local.set $first (call $read_at (i32.const 8) (i32.const 0) (i32.const 600)))
+			(local.set $second
+				(call $read_at (i32.add (i32.const 8) (local.get $first)) (local.get $first) (i32.const 600)))
+			(i32.store (i32.const 0) (local.get $first))
+			(i32.store (i32.const 4) (local.get $second))
+			(call $scratch_buf_write
+				(i32.const 0)
+				(i32.add (i32.const 8) (i32.add (local.get $first) (local.get $second))))))"#);
+	let mut spree_module = new_module(&wasm);
+	let request = (0..1000).map(|n| n as u8).collect::<Vec<_>>();
+	spree_module.invoke(CALLER, 1, request.clone()).unwrap();
+
+	let scratch = spree_module.last_scratch();
+	assert_eq!(scratch[..4], 600u32.to_le_bytes());
+	assert_eq!(scratch[4..8], 400u32.to_le_bytes());
+	assert_eq!(&scratch[8..], &request[..]);
+}
+
+#[test]
+fn module_requesting_a_disabled_capability_fails_to_instantiate() {
+	let wasm = counter_module();
+	let mut spree_module =
+		new_module(&wasm).with_capabilities(capabilities::ALL & !capabilities::STORAGE);
+	let err = spree_module.invoke(CALLER, 1, vec![]).unwrap_err();
+	assert!(
+		format!("{:?}", err).contains("storage_increment requires a capability"),
+		"unexpected error: {:?}",
+		err
+	);
+	assert!(!spree_module.is_instantiated());
+}
+
+#[test]
+fn host_capabilities_reports_the_enabled_set() {
+	let wasm = wat(r#"(module
+		(import "env" "host_capabilities" (func $host_capabilities (result i64)))
+		(import "env" "scratch_buf_write" (func $scratch_buf_write (param i32 i32)))
+		(memory (export "memory") 1)
+		(func (export "handle") (param i32)
+			(i64.store (i32.const 0) (call $host_capabilities))
+			(call $scratch_buf_write (i32.const 0) (i32.const 8))))"#);
+	let enabled = capabilities::ICMP | capabilities::GAS;
+	let mut spree_module = new_module(&wasm).with_capabilities(enabled);
+	spree_module.invoke(CALLER, 1, vec![]).unwrap();
+	assert_eq!(scratch_u64(spree_module.last_scratch()), enabled);
+}
+
+#[test]
+fn instantiation_failures_are_classified() {
+	let bad_import = wat(r#"(module
+		(import "env" "no_such_function" (func))
+		(memory (export "memory") 1)
+		(func (export "handle") (param i32)))"#);
+	match new_module(&bad_import).invoke(CALLER, 1, vec![]) {
+		Err(Error::BadImport { path, reason }) => {
+			assert_eq!(path, bad_import.path());
+			assert!(reason.contains("no_such_function"), "{}", reason);
+		}
+		other => panic!("unexpected result: {:?}", other),
+	}
+
+	let missing_memory = wat(r#"(module (func (export "handle") (param i32)))"#);
+	match new_module(&missing_memory).invoke(CALLER, 1, vec![]) {
+		Err(Error::BadMemory { path, .. }) => assert_eq!(path, missing_memory.path()),
+		other => panic!("unexpected result: {:?}", other),
+	}
+}
+
+#[test]
+fn randomness_is_determined_by_the_seed() {
+	let wasm = wat(r#"(module
+		(import "env" "random" (func $random (param i32 i32)))
+		(import "env" "scratch_buf_write" (func $scratch_buf_write (param i32 i32)))
+		(memory (export "memory") 1)
+		(func (export "handle") (param i32)
+			(call $random (i32.const 0) (i32.const 32))
+			(call $scratch_buf_write (i32.const 0) (i32.const 32))))"#);
+	let random_with_seed = |seed| {
+		let mut spree_module = new_module(&wasm).with_random_seed(seed);
+		spree_module.invoke(CALLER, 1, vec![]).unwrap();
+		spree_module.last_scratch().to_vec()
+	};
+	assert_eq!(random_with_seed(1), random_with_seed(1));
+	assert_ne!(random_with_seed(1), random_with_seed(2));
+}
+
+#[test]
+fn storage_changes_of_a_trapped_invocation_are_discarded() {
+	let wasm = counter_module();
+	let mut spree_module = new_module(&wasm);
+	spree_module.invoke(CALLER, 1, vec![]).unwrap();
+	let snapshot = spree_module.storage_snapshot();
+
+	// The counter is incremented, but the invocation traps right after.
+	spree_module.set_failure_injector(FailureInjector::default().fail_nth(
+		"scratch_buf_write",
+		1,
+		InjectedFailure::Trap,
+	));
+	assert!(spree_module.invoke(CALLER, 2, vec![]).is_err());
+	assert_eq!(spree_module.storage_snapshot(), snapshot);
+
+	spree_module.invoke(CALLER, 3, vec![]).unwrap();
+	assert_eq!(scratch_u64(spree_module.last_scratch()), 2);
+}
+
+#[test]
+fn injected_storage_write_failure_fails_the_invocation() {
+	let wasm = wat(WRITE_LOOP_WAT);
+	let mut spree_module = new_module(&wasm);
+	spree_module.set_failure_injector(FailureInjector::default().fail_nth(
+		"storage_write",
+		2,
+		InjectedFailure::Trap,
+	));
+	match spree_module.invoke(CALLER, 1, vec![]) {
+		Err(Error::InjectedFailure { fn_name }) => assert_eq!(fn_name, "storage_write"),
+		result => panic!("expected InjectedFailure, got {:?}", result),
+	}
+	// The first write, which went through, is rolled back along with the rest.
+	assert!(spree_module.storage_snapshot().is_empty());
+
+	// The calls are counted across invocations, so the next one is not affected.
+	spree_module.invoke(CALLER, 2, vec![]).unwrap();
+	assert_eq!(spree_module.storage_snapshot().len(), 1);
+}
+
+#[test]
+fn routed_messages_reach_the_recepient() {
+	let wasm = echo_module();
+	let mut sender = new_module(&wasm);
+	let mut recepient = new_module(&wasm);
+	// The echo module always sends to the parachain 1.
+	let routing = vec![(2, 0), (1, 1)].into_iter().collect::<HashMap<_, _>>();
+
+	sender.invoke(CALLER, 1, b"hi".to_vec()).unwrap();
+	route_messages(&mut [&mut sender, &mut recepient], &routing);
+	assert!(sender.outbound_messages().is_empty());
+
+	recepient.invoke(CALLER, 2, vec![]).unwrap();
+	let polled = <Vec<(u32, Vec<u8>)>>::decode(&mut recepient.last_scratch()).unwrap();
+	assert_eq!(polled, vec![(2, b"hi".to_vec())]);
+}
+
+#[test]
+fn messages_held_by_a_partition_are_delivered_after_it_heals() {
+	let wasm = echo_module();
+	let mut sender = new_module(&wasm);
+	let mut recepient = new_module(&wasm);
+	// The echo module always sends to the parachain 1.
+	let routing = vec![(2, 0), (1, 1)].into_iter().collect::<HashMap<_, _>>();
+	let mut partitions = Partitions::default();
+	partitions.partition(1, 2);
+
+	sender.invoke(CALLER, 1, b"hi".to_vec()).unwrap();
+	for _ in 0..2 {
+		route_messages_with_partitions(
+			&mut [&mut sender, &mut recepient],
+			&routing,
+			&mut partitions,
+		);
+		assert!(!recepient.has_inbound());
+		assert_eq!(partitions.held(), &[(2, 1, b"hi".to_vec())]);
+	}
+
+	partitions.heal(2, 1);
+	route_messages_with_partitions(
+		&mut [&mut sender, &mut recepient],
+		&routing,
+		&mut partitions,
+	);
+	assert!(partitions.held().is_empty());
+	recepient.invoke(CALLER, 2, vec![]).unwrap();
+	let polled = <Vec<(u32, Vec<u8>)>>::decode(&mut recepient.last_scratch()).unwrap();
+	assert_eq!(polled, vec![(2, b"hi".to_vec())]);
+}
+
+#[test]
+fn signed_bundles_round_trip_and_tampering_is_detected() {
+	let wasm = echo_module();
+	let signed = |inbound: Vec<(u32, Vec<u8>)>| {
+		SpreeModule::new(
+			wasm.path(),
+			SpreeIcmpAccumulator::with_inbound_msgs(inbound),
+		)
+		.with_signing_key(&b"doppelganger key"[..])
+	};
+	let mut sender = signed(vec![]);
+	let mut recepient = signed(vec![]);
+	let routing = vec![(2, 0), (1, 1)].into_iter().collect::<HashMap<_, _>>();
+
+	sender.invoke(CALLER, 1, b"hi".to_vec()).unwrap();
+	// The bundle goes over the wire with the tag prepended.
+	let wire = sender.outbound_messages()[&1].clone();
+	assert_eq!(wire.len(), 32 + 2);
+	assert_eq!(&wire[32..], b"hi");
+	route_messages(&mut [&mut sender, &mut recepient], &routing);
+	recepient.invoke(CALLER, 2, vec![]).unwrap();
+	let polled = <Vec<(u32, Vec<u8>)>>::decode(&mut recepient.last_scratch()).unwrap();
+	assert_eq!(polled, vec![(2, b"hi".to_vec())]);
+
+	let mut tampered = wire;
+	*tampered.last_mut().unwrap() ^= 1;
+	let mut recepient = signed(vec![(2, tampered)]);
+	match recepient.invoke(CALLER, 2, vec![]) {
+		Err(Error::BadSignature { sender: 2 }) => {}
+		result => panic!("expected BadSignature, got {:?}", result),
+	}
+}
+
+#[test]
+fn poll_drains_the_inbound_messages_only_upon_success() {
+	// Records the size of the scratch buffer after two peeks and two polls, and traps afterwards
+	// when invoked in the time slice 0.
+	let wasm = wat(r#"(module
+		(import "env" "poll" (func $poll))
+		(import "env" "poll_peek" (func $poll_peek))
+		(import "env" "scratch_buf_size" (func $scratch_buf_size (result i32)))
+		(import "env" "scratch_buf_write" (func $scratch_buf_write (param i32 i32)))
+		(memory (export "memory") 1)
+		(func (export "handle") (param $time_slice i32)
+			(call $poll_peek)
+			(i32.store (i32.const 0) (call $scratch_buf_size))
+			(call $poll_peek)
+			(i32.store (i32.const 4) (call $scratch_buf_size))
+			(call $poll)
+			(i32.store (i32.const 8) (call $scratch_buf_size))
+			(call $poll)
+			(i32.store (i32.const 12) (call $scratch_buf_size))
+			(if (i32.eqz (local.get $time_slice)) (then unreachable))
+			(call $scratch_buf_write (i32.const 0) (i32.const 16))))"#);
+	let inbound = vec![(7, b"foo".to_vec())];
+	let bundle_size = inbound.encode().len() as u32;
+	let mut spree_module = SpreeModule::new(
+		wasm.path(),
+		SpreeIcmpAccumulator::with_inbound_msgs(inbound),
+	);
+
+	// The failed invocation doesn't consume the inbound messages.
+	assert!(spree_module.invoke(CALLER, 0, vec![]).is_err());
+	assert!(spree_module.has_inbound());
+
+	spree_module.invoke(CALLER, 1, vec![]).unwrap();
+	let sizes = spree_module
+		.last_scratch()
+		.chunks(4)
+		.map(|raw| u32::decode(&mut &raw[..]).unwrap())
+		.collect::<Vec<_>>();
+	// An empty list of bundles is encoded as a single byte.
+	assert_eq!(sizes, vec![bundle_size, bundle_size, bundle_size, 1]);
+	assert!(!spree_module.has_inbound());
+}
+
+#[test]
+fn enqueue_touches_the_storage_while_ping_touches_none() {
+	let wasm = lamport_clock();
+	let mut spree_module = new_module(&wasm).with_coverage(true);
+	let touched = |spree_module: &SpreeModule| {
+		let coverage = spree_module.last_coverage().unwrap();
+		assert_eq!(coverage.exports, vec!["handle".to_string()]);
+		coverage
+			.host_fns
+			.iter()
+			.map(|index| spree_module.host_function_name(*index).unwrap())
+			.filter(|name| name.starts_with("storage_"))
+			.collect::<Vec<_>>()
+	};
+
+	let enqueue = Req::Enqueue {
+		recepient: 1,
+		payload: b"foo".to_vec(),
+	};
+	spree_module.invoke(CALLER, 1, enqueue.encode()).unwrap();
+	assert_eq!(
+		touched(&spree_module),
+		vec!["storage_read", "storage_write", "storage_increment"]
+	);
+
+	spree_module
+		.invoke(CALLER, 2, Req::Ping { nonce: 42 }.encode())
+		.unwrap();
+	assert!(touched(&spree_module).is_empty());
+	assert_eq!(spree_module.last_scratch(), &42u64.encode()[..]);
+}
+
+#[test]
+fn debug_output_shows_recepients_and_blob_lengths() {
+	let acc = SpreeIcmpAccumulator::with_inbound_msgs(vec![(7, b"secret".to_vec())]);
+	let wasm = echo_module();
+	let mut spree_module = SpreeModule::new(wasm.path(), acc);
+	let debug = format!("{:?}", spree_module);
+	assert!(debug.contains("inbound: {7: 6}"), "{}", debug);
+	assert!(!debug.contains("secret"), "{}", debug);
+	spree_module.invoke(CALLER, 1, b"hush".to_vec()).unwrap();
+
+	let debug = format!("{:?}", spree_module);
+	// The echo module polls, which consumes the inbound messages.
+	assert!(debug.contains("inbound: {}"), "{}", debug);
+	assert!(debug.contains("outbound: {1: 4}"), "{}", debug);
+	assert!(debug.contains("instantiated: true"), "{}", debug);
+	assert!(
+		!debug.contains("secret") && !debug.contains("hush"),
+		"{}",
+		debug
+	);
+}
+
+#[test]
+fn replaying_a_record_reproduces_the_invocation() {
+	let wasm = echo_module();
+	let storage = vec![(b"key".to_vec(), b"value".to_vec())]
+		.into_iter()
+		.collect::<HashMap<_, _>>();
+	let mut spree_module = SpreeModule::new(
+		wasm.path(),
+		SpreeIcmpAccumulator::with_inbound_msgs(vec![(3, b"inbound".to_vec())]),
+	)
+	.with_storage(storage);
+
+	let record = spree_module.record(CALLER, 1, b"hi");
+	let record = InvokeRecord::decode(&mut &record.encode()[..]).unwrap();
+	spree_module.invoke(CALLER, 1, b"hi".to_vec()).unwrap();
+	let live = InvokeOutcome {
+		outbound: spree_module
+			.outbound_messages()
+			.iter()
+			.map(|(recepient, blob)| (*recepient, blob.clone()))
+			.collect(),
+		scratch: spree_module.last_scratch().to_vec(),
+		storage: spree_module.storage_snapshot(),
+	};
+
+	assert_eq!(spree_module.replay(&record).unwrap(), live);
+}
+
+/// Returns the name of the given value type in the text format.
+fn wat_type(value_type: ValueType) -> &'static str {
+	match value_type {
+		ValueType::I32 => "i32",
+		ValueType::I64 => "i64",
+		ValueType::F32 => "f32",
+		ValueType::F64 => "f64",
+	}
+}
+
+/// A module importing the given host function with the given signature.
+fn importing_module(name: &str, params: &[ValueType], ret: Option<ValueType>) -> common::Wasm {
+	let params = params
+		.iter()
+		.map(|param| format!("(param {})", wat_type(*param)))
+		.collect::<String>();
+	let result = ret
+		.map(|ret| format!("(result {})", wat_type(ret)))
+		.unwrap_or_default();
+	wat(&format!(
+		r#"(module
+			(import "env" "{}" (func {} {}))
+			(memory (export "memory") 1)
+			(func (export "handle") (param i32)))"#,
+		name, params, result
+	))
+}
+
+/// Doubles its argument and appends the result to the scratch buffer.
+fn double(env: &mut SpreeModuleHostEnv, args: wasmi::RuntimeArgs) -> HostResult {
+	let value: i32 = args.nth_checked(0)?;
+	env.scratch_buf_mut().push((value * 2) as u8);
+	Ok(Some(RuntimeValue::I32(value * 2)))
+}
+
+#[test]
+fn custom_host_function_is_callable_from_the_module() {
+	let wasm = wat(r#"(module
+		(import "env" "double" (func $double (param i32) (result i32)))
+		(memory (export "memory") 1)
+		(func (export "handle") (param i32)
+			(drop (call $double (call $double (i32.const 3))))))"#);
+	let mut spree_module = new_module(&wasm).with_host_function(HostFunction {
+		name: "double",
+		params: &[ValueType::I32],
+		ret: Some(ValueType::I32),
+		capability: 0,
+		handler: double,
+	});
+	spree_module.invoke(CALLER, 1, vec![]).unwrap();
+	// The result of the first call is passed to the second one.
+	assert_eq!(spree_module.last_scratch(), &[6, 12]);
+
+	// Without the registration the import doesn't resolve.
+	match new_module(&wasm).invoke(CALLER, 1, vec![]) {
+		Err(Error::BadImport { .. }) => {}
+		other => panic!("expected BadImport, got {:?}", other),
+	}
+}
+
+/// Traps with the error of the given sender's signature being invalid.
+fn reject_signature(_env: &mut SpreeModuleHostEnv, args: wasmi::RuntimeArgs) -> HostResult {
+	let sender: u32 = args.nth_checked(0)?;
+	Err(Error::BadSignature { sender }.into())
+}
+
+#[test]
+fn host_error_is_recovered_as_the_precise_variant() {
+	let wasm = wat(r#"(module
+		(import "env" "reject_signature" (func $reject_signature (param i32)))
+		(memory (export "memory") 1)
+		(func (export "handle") (param i32)
+			(call $reject_signature (i32.const 7))))"#);
+	let mut spree_module = new_module(&wasm).with_host_function(HostFunction {
+		name: "reject_signature",
+		params: &[ValueType::I32],
+		ret: None,
+		capability: 0,
+		handler: reject_signature,
+	});
+	match spree_module.invoke(CALLER, 1, vec![]) {
+		Err(Error::BadSignature { sender: 7 }) => {}
+		other => panic!("expected BadSignature from 7, got {:?}", other),
+	}
+}
+
+#[test]
+fn both_scratch_buf_read_signatures_resolve_against_the_same_host() {
+	// Imports `scratch_buf_read` with both the old and the new signature. The blob is read with
+	// the old one to offset 0 and with the new one to offset 16, the length returned by the
+	// latter is used for echoing the second copy back.
+	let wasm = wat(r#"(module
+		(import "env" "scratch_buf_read" (func $read_old (param i32)))
+		(import "env" "scratch_buf_read" (func $read_new (param i32) (result i32)))
+		(import "env" "scratch_buf_write" (func $scratch_buf_write (param i32 i32)))
+		(memory (export "memory") 1)
+		(func (export "handle") (param i32)
+			(local $len i32)
+			(call $read_old (i32.const 0))
+			(local.set $len (call $read_new (i32.const 16)))
+			(if (i32.ne (i32.load8_u (i32.const 0)) (i32.load8_u (i32.const 16)))
+				(then unreachable))
+			(call $scratch_buf_write (i32.const 16) (local.get $len))))"#);
+	let mut spree_module = new_module(&wasm);
+	spree_module.invoke(CALLER, 1, b"hello".to_vec()).unwrap();
+	assert_eq!(spree_module.last_scratch(), b"hello");
+}
+
+#[test]
+fn every_builtin_host_fn_resolves_with_exactly_its_signature() {
+	for host_fn in BUILTIN_HOST_FNS {
+		let wasm = importing_module(host_fn.name, host_fn.params, host_fn.ret);
+		new_module(&wasm)
+			.with_capabilities(capabilities::ALL)
+			.invoke(CALLER, 1, vec![])
+			.unwrap_or_else(|err| panic!("{:?} doesn't resolve: {:?}", host_fn, err));
+
+		let mut params = host_fn.params.to_vec();
+		params.push(ValueType::F64);
+		let wasm = importing_module(host_fn.name, &params, host_fn.ret);
+		match new_module(&wasm)
+			.with_capabilities(capabilities::ALL)
+			.invoke(CALLER, 1, vec![])
+		{
+			Err(Error::BadImport { .. }) => {}
+			other => panic!("{:?} resolved with a wrong signature: {:?}", host_fn, other),
+		}
+	}
+}
+
+#[test]
+fn abort_surfaces_the_code_and_the_message() {
+	let wasm = wat(r#"(module
+		(import "env" "abort" (func $abort (param i32 i32 i32)))
+		(memory (export "memory") 1)
+		(data (i32.const 0) "out of stock")
+		(func (export "handle") (param i32)
+			(call $abort (i32.const 42) (i32.const 0) (i32.const 12))))"#);
+	match new_module(&wasm).invoke(CALLER, 1, vec![]) {
+		Err(Error::ModuleAbort { code, message }) => {
+			assert_eq!(code, 42);
+			assert_eq!(message, "out of stock");
+		}
+		other => panic!("unexpected result: {:?}", other),
+	}
+}
+
+/// Sends a blob of `MAX_MESSAGE_LEN + 1` bytes when invoked in the time slice 0, and a single byte
+/// otherwise. Leaves the scratch buffer to `send`.
+const SENDING_WAT: &str = r#"(module
+	(import "env" "send" (func $send (param i32 i32 i32) (result i32)))
+	(memory (export "memory") 2)
+	(func (export "handle") (param $time_slice i32)
+		(drop (call $send
+			(i32.const 1)
+			(i32.const 0)
+			(select (i32.const 65537) (i32.const 1) (i32.eqz (local.get $time_slice)))))))"#;
+
+#[test]
+fn exceeding_the_outbound_cap_traps() {
+	// Sends a byte to each of the parachains 1 to 5.
+	let wasm = wat(r#"(module
+		(import "env" "send" (func $send (param i32 i32 i32) (result i32)))
+		(memory (export "memory") 1)
+		(func (export "handle") (param i32)
+			(local $recepient i32)
+			(local.set $recepient (i32.const 1))
+			(loop $again
+				(drop (call $send (local.get $recepient) (i32.const 0) (i32.const 1)))
+				(local.set $recepient (i32.add (local.get $recepient) (i32.const 1)))
+				(br_if $again (i32.le_u (local.get $recepient) (i32.const 5))))))"#);
+	let mut spree_module = new_module(&wasm).with_max_outbound_msgs(3);
+	match spree_module.invoke(CALLER, 1, vec![]) {
+		Err(Error::OutboundLimitExceeded { limit: 3 }) => {}
+		result => panic!("expected OutboundLimitExceeded, got {:?}", result),
+	}
+	// The sends made before hitting the cap are not retracted.
+	let mut recepients = spree_module
+		.outbound_messages()
+		.keys()
+		.cloned()
+		.collect::<Vec<_>>();
+	recepients.sort();
+	assert_eq!(recepients, vec![1, 2, 3]);
+
+	new_module(&wasm)
+		.with_max_outbound_msgs(5)
+		.invoke(CALLER, 1, vec![])
+		.unwrap();
+}
+
+#[test]
+fn second_send_overwrites_and_the_first_message_is_recorded_as_dropped() {
+	let wasm = echo_module();
+	let acc = SpreeIcmpAccumulator::with_inbound_msgs(vec![]).with_drop_recording();
+	let mut spree_module = SpreeModule::new(wasm.path(), acc);
+	// The echo module sends the blob it is invoked with to the parachain 1.
+	spree_module.invoke(CALLER, 1, b"first".to_vec()).unwrap();
+	spree_module.invoke(CALLER, 2, b"second".to_vec()).unwrap();
+
+	assert_eq!(spree_module.outbound_messages()[&1], b"second");
+	assert_eq!(
+		spree_module.dropped_messages(),
+		&[(1, b"first".to_vec(), DropReason::Overwritten)][..]
+	);
+}
+
+#[test]
+fn scratch_allocation_is_reused_across_writes() {
+	// Writes 64 bytes to the scratch buffer a hundred times, the first byte being the iteration.
+	let wasm = wat(r#"(module
+		(import "env" "scratch_buf_write" (func $scratch_buf_write (param i32 i32)))
+		(memory (export "memory") 1)
+		(func (export "handle") (param i32)
+			(local $i i32)
+			(loop $again
+				(i32.store8 (i32.const 0) (local.get $i))
+				(call $scratch_buf_write (i32.const 0) (i32.const 64))
+				(local.set $i (i32.add (local.get $i) (i32.const 1)))
+				(br_if $again (i32.lt_u (local.get $i) (i32.const 100))))))"#);
+
+	let mut spree_module = new_module(&wasm);
+	spree_module.invoke(CALLER, 1, vec![]).unwrap();
+	let mut expected = [0; 64];
+	expected[0] = 99;
+	assert_eq!(spree_module.last_scratch(), &expected[..]);
+	// Each of the two halves of the buffer grows once and is reused afterwards.
+	assert!(spree_module.last_metrics().scratch_allocs <= 2);
+
+	let mut spree_module = new_module(&wasm).with_scratch_capacity(64);
+	spree_module.invoke(CALLER, 1, vec![]).unwrap();
+	assert_eq!(spree_module.last_scratch(), &expected[..]);
+	assert_eq!(spree_module.last_metrics().scratch_allocs, 0);
+}
+
+#[test]
+fn lamport_clock_imports_the_messaging_and_storage_functions() {
+	let spree_module = SpreeModule::new(
+		prebuilt(LAMPORT_CLOCK_WASM),
+		SpreeIcmpAccumulator::with_inbound_msgs(vec![]),
+	);
+	let imports = spree_module.imported_host_functions().unwrap();
+	for name in &["storage_read", "storage_write", "send", "poll"] {
+		assert!(imports.iter().any(|import| import == name), "{:?}", imports);
+	}
+	// Only the host functions are listed, not e.g. the memory.
+	assert!(imports.iter().all(|import| import != "memory"));
+}
+
+#[test]
+fn custom_section_is_read_back() {
+	let wasm = wat(r#"(module
+		(@custom "spree-version" "1.2")
+		(memory (export "memory") 1)
+		(func (export "handle") (param i32)))"#);
+	let spree_module = new_module(&wasm);
+	assert_eq!(
+		spree_module.custom_section("spree-version").unwrap(),
+		Some(b"1.2".to_vec())
+	);
+	assert_eq!(spree_module.custom_section("spree-abi").unwrap(), None);
+}
+
+#[test]
+fn send_to_a_closed_channel_lands_in_the_dead_letter_queue() {
+	let wasm = echo_module();
+	let acc = SpreeIcmpAccumulator::with_inbound_msgs(vec![])
+		.with_closed_channels(vec![1])
+		.with_dead_letters()
+		.with_drop_recording();
+	let mut spree_module = SpreeModule::new(wasm.path(), acc);
+	// The echo module sends the blob it is invoked with to the parachain 1.
+	spree_module.invoke(CALLER, 1, b"lost".to_vec()).unwrap();
+
+	assert!(spree_module.outbound_messages().is_empty());
+	assert_eq!(spree_module.dead_letters(), &[(1, b"lost".to_vec())][..]);
+	assert_eq!(
+		spree_module.dropped_messages(),
+		&[(
+			1,
+			b"lost".to_vec(),
+			DropReason::Rejected(StatusCode::ChannelClosed)
+		)][..]
+	);
+}
+
+#[test]
+fn failed_send_leaves_the_details_in_the_scratch_buffer() {
+	let wasm = wat(SENDING_WAT);
+	let mut spree_module = new_module(&wasm);
+	spree_module.invoke(CALLER, 0, b"request".to_vec()).unwrap();
+	let send_error = SendError::decode(&mut spree_module.last_scratch()).unwrap();
+	assert_eq!(send_error.code, StatusCode::TooLarge.as_i32());
+	assert_eq!(send_error.recepient, 1);
+	assert_eq!(
+		send_error.reason,
+		"the message is 65537 bytes long, while at most 65536 bytes are allowed"
+	);
+	assert!(spree_module.outbound_messages().is_empty());
+
+	// A successful send doesn't touch the scratch buffer.
+	spree_module.invoke(CALLER, 1, b"request".to_vec()).unwrap();
+	assert_eq!(spree_module.last_scratch(), b"request");
+	assert_eq!(spree_module.outbound_messages().len(), 1);
+}
+
+#[test]
+fn scheduled_inbound_is_polled_and_recorded() {
+	let wasm = echo_module();
+	let schedule = vec![
+		(1, vec![(2, b"a".to_vec())]),
+		(2, vec![(3, b"b".to_vec()), (4, b"c".to_vec())]),
+		(3, vec![]),
+	]
+	.into_iter()
+	.collect::<BTreeMap<_, _>>();
+	let mut spree_module = new_module(&wasm).with_inbound_schedule(schedule.clone());
+
+	for (time_slice, scheduled) in &schedule {
+		let time_slice = *time_slice as u32;
+		let record = spree_module.record(CALLER, time_slice, b"hi");
+		assert_eq!(&record.inbound, scheduled);
+
+		spree_module
+			.invoke(CALLER, time_slice, b"hi".to_vec())
+			.unwrap();
+		let mut polled = <Vec<(u32, Vec<u8>)>>::decode(&mut spree_module.last_scratch()).unwrap();
+		polled.sort();
+		assert_eq!(&polled, scheduled);
+	}
+}
+
+#[test]
+fn profile_has_entries_for_the_called_host_functions() {
+	// `gas_left` is only called upon a fan out, never upon an enqueue.
+	let wasm = lamport_clock();
+	let enqueue = Req::Enqueue {
+		recepient: 1,
+		payload: b"foo".to_vec(),
+	}
+	.encode();
+
+	let mut spree_module = new_module(&wasm).with_profiling(true);
+	spree_module.invoke(CALLER, 1, enqueue.clone()).unwrap();
+	assert_eq!(
+		spree_module
+			.last_metrics()
+			.host_fn_time
+			.keys()
+			.collect::<Vec<_>>(),
+		vec![
+			&"scratch_buf_read",
+			&"scratch_buf_size",
+			&"storage_increment",
+			&"storage_read",
+			&"storage_write"
+		]
+	);
+
+	// Nothing is measured unless enabled.
+	let mut spree_module = new_module(&wasm);
+	spree_module.invoke(CALLER, 1, enqueue).unwrap();
+	assert!(spree_module.last_metrics().host_fn_time.is_empty());
+}
+
+/// Writes "value" under "key" when invoked in the time slice 0, otherwise reads the value back
+/// into the scratch buffer.
+const READ_WRITE_WAT: &str = r#"(module
+	(import "env" "storage_read" (func $storage_read (param i32 i32) (result i32)))
+	(import "env" "storage_write" (func $storage_write (param i32 i32 i32 i32)))
+	(memory (export "memory") 1)
+	(data (i32.const 0) "keyvalue")
+	(func (export "handle") (param $time_slice i32)
+		(if (i32.eqz (local.get $time_slice))
+			(then (call $storage_write (i32.const 0) (i32.const 3) (i32.const 3) (i32.const 5)))
+			(else (drop (call $storage_read (i32.const 0) (i32.const 3)))))))"#;
+
+#[test]
+fn strict_storage_reads_trap_on_an_unexpected_absent_key() {
+	let wasm = wat(READ_WRITE_WAT);
+	match new_module(&wasm)
+		.with_strict_storage_reads(vec![])
+		.invoke(CALLER, 1, vec![])
+	{
+		Err(Error::UnknownStorageKey { key }) => assert_eq!(key, b"key"),
+		other => panic!("expected UnknownStorageKey, got {:?}", other),
+	}
+
+	// A whitelisted key may be absent.
+	new_module(&wasm)
+		.with_strict_storage_reads(vec![b"key".to_vec()])
+		.invoke(CALLER, 1, vec![])
+		.unwrap();
+
+	// Reading a written key is fine regardless of the whitelist.
+	let mut spree_module = new_module(&wasm).with_strict_storage_reads(vec![]);
+	spree_module.invoke(CALLER, 0, vec![]).unwrap();
+	spree_module.invoke(CALLER, 1, vec![]).unwrap();
+	assert_eq!(spree_module.last_scratch(), b"value");
+}
+
+#[test]
+fn corrupted_value_is_detected_on_read() {
+	let wasm = wat(READ_WRITE_WAT);
+	let mut spree_module = new_module(&wasm).with_storage_verification(true);
+	spree_module.invoke(CALLER, 0, vec![]).unwrap();
+	spree_module.invoke(CALLER, 1, vec![]).unwrap();
+	assert_eq!(spree_module.last_scratch(), b"value");
+
+	// Flip a bit of the stored value, keeping the checksum.
+	let mut storage = spree_module
+		.storage_snapshot()
+		.into_iter()
+		.collect::<HashMap<_, _>>();
+	let stored = storage.get_mut(&b"key"[..]).unwrap();
+	assert_ne!(
+		&stored[..],
+		b"value",
+		"the checksum is stored along with the value"
+	);
+	*stored.last_mut().unwrap() ^= 1;
+
+	let mut spree_module = new_module(&wasm)
+		.with_storage_verification(true)
+		.with_storage(storage);
+	match spree_module.invoke(CALLER, 1, vec![]) {
+		Err(Error::StorageCorruption { key }) => assert_eq!(key, b"key"),
+		other => panic!("unexpected result: {:?}", other),
+	}
+}
+
+#[test]
+fn alternating_polls_and_storage_reads_see_their_own_results() {
+	// Takes the contents of the scratch buffer after every poll and storage read, appending them
+	// to the output prefixed by their length.
+	let wasm = wat(r#"(module
+		(import "env" "scratch_buf_size" (func $scratch_buf_size (result i32)))
+		(import "env" "scratch_buf_read" (func $scratch_buf_read (param i32)))
+		(import "env" "scratch_buf_write" (func $scratch_buf_write (param i32 i32)))
+		(import "env" "poll" (func $poll))
+		(import "env" "poll_peek" (func $poll_peek))
+		(import "env" "storage_read" (func $storage_read (param i32 i32) (result i32)))
+		(import "env" "storage_write" (func $storage_write (param i32 i32 i32 i32)))
+		(memory (export "memory") 1)
+		(data (i32.const 0) "keyvalue")
+		(global $out (mut i32) (i32.const 1024))
+		(func $take
+			(local $size i32)
+			(local.set $size (call $scratch_buf_size))
+			(i32.store (global.get $out) (local.get $size))
+			(call $scratch_buf_read (i32.add (global.get $out) (i32.const 4)))
+			(global.set $out (i32.add (global.get $out) (i32.add (local.get $size) (i32.const 4)))))
+		(func $read_value
+			(drop (call $storage_read (i32.const 0) (i32.const 3)))
+			(call $take))
+		(func (export "handle") (param i32)
+			(call $storage_write (i32.const 0) (i32.const 3) (i32.const 3) (i32.const 5))
+			(call $poll_peek)
+			(call $take)
+			(call $read_value)
+			(call $poll)
+			(call $take)
+			(call $read_value)
+			(call $poll)
+			(call $take)
+			(call $scratch_buf_write (i32.const 1024) (i32.sub (global.get $out) (i32.const 1024)))))"#);
+	let inbound = vec![(3, b"inbound".to_vec())];
+	let mut spree_module = SpreeModule::new(
+		wasm.path(),
+		SpreeIcmpAccumulator::with_inbound_msgs(inbound.clone()),
+	);
+	spree_module.invoke(CALLER, 1, vec![]).unwrap();
+
+	let mut output = spree_module.last_scratch();
+	let mut taken = Vec::new();
+	while !output.is_empty() {
+		let len = u32::from_le_bytes([output[0], output[1], output[2], output[3]]) as usize;
+		taken.push(output[4..4 + len].to_vec());
+		output = &output[4 + len..];
+	}
+	let drained = Vec::<(u32, Vec<u8>)>::new().encode();
+	assert_eq!(
+		taken,
+		vec![
+			inbound.encode(),
+			b"value".to_vec(),
+			inbound.encode(),
+			b"value".to_vec(),
+			drained,
+		]
+	);
+	// The buffers are reused rather than allocated anew for every result.
+	assert!(
+		spree_module.last_metrics().scratch_allocs < taken.len() as u32,
+		"{:?}",
+		spree_module.last_metrics()
+	);
+}
+
+#[test]
+fn scratch_bytes_count_the_request_and_the_inbound() {
+	let inbound = vec![(3, b"inbound".to_vec())];
+	let wasm = echo_module();
+	let mut spree_module = SpreeModule::new(
+		wasm.path(),
+		SpreeIcmpAccumulator::with_inbound_msgs(inbound.clone()),
+	);
+	spree_module.invoke(CALLER, 1, b"hi".to_vec()).unwrap();
+	// The request read by the module and the inbound bundles put into the scratch buffer by `poll`.
+	assert_eq!(
+		spree_module.last_metrics().scratch_bytes,
+		2 + inbound.encode().len() as u64
+	);
+}
+
+/// Like `counter_module`, but the counter starts at the genesis value of 100.
+const SEEDED_COUNTER_WAT: &str = r#"(module
+	(import "env" "storage_increment" (func $storage_increment (param i32 i32 i64) (result i64)))
+	(import "env" "storage_write" (func $storage_write (param i32 i32 i32 i32)))
+	(import "env" "scratch_buf_write" (func $scratch_buf_write (param i32 i32)))
+	(memory (export "memory") 1)
+	(data (i32.const 0) "count")
+	(func (export "spree_init")
+		(i64.store (i32.const 16) (i64.const 100))
+		(call $storage_write (i32.const 0) (i32.const 5) (i32.const 16) (i32.const 8)))
+	(func (export "handle") (param i32)
+		(i64.store (i32.const 16)
+			(call $storage_increment (i32.const 0) (i32.const 5) (i64.const 1)))
+		(call $scratch_buf_write (i32.const 16) (i32.const 8))))"#;
+
+#[test]
+fn spree_init_seeds_the_genesis_state_once() {
+	let wasm = wat(SEEDED_COUNTER_WAT);
+	let mut spree_module = new_module(&wasm);
+	spree_module.invoke(CALLER, 1, vec![]).unwrap();
+	assert_eq!(scratch_u64(spree_module.last_scratch()), 101);
+
+	// Neither another invocation nor a new instance runs `spree_init` again.
+	spree_module.invoke(CALLER, 2, vec![]).unwrap();
+	assert_eq!(scratch_u64(spree_module.last_scratch()), 102);
+	spree_module.evict_instance();
+	spree_module.invoke(CALLER, 3, vec![]).unwrap();
+	assert_eq!(scratch_u64(spree_module.last_scratch()), 103);
+}
+
+#[test]
+fn spree_init_runs_once_per_caller_namespace() {
+	let wasm = wat(SEEDED_COUNTER_WAT);
+	let mut spree_module = new_module(&wasm).with_per_caller_storage(true);
+	spree_module.invoke(1, 1, vec![]).unwrap();
+	spree_module.invoke(1, 2, vec![]).unwrap();
+	assert_eq!(scratch_u64(spree_module.last_scratch()), 102);
+
+	// The second parachain starts from the genesis state, unaffected by the first one.
+	spree_module.invoke(2, 3, vec![]).unwrap();
+	assert_eq!(scratch_u64(spree_module.last_scratch()), 101);
+	spree_module.invoke(1, 4, vec![]).unwrap();
+	assert_eq!(scratch_u64(spree_module.last_scratch()), 103);
+
+	let state = spree_module.save_state();
+	assert_eq!(state.initialized_callers, vec![1, 2]);
+	assert!(!state.initialized);
+}
+
+#[test]
+fn restored_state_re_encodes_to_identical_bytes() {
+	// Writes three keys, out of order.
+	let wasm = wat(r#"(module
+		(import "env" "storage_write" (func $storage_write (param i32 i32 i32 i32)))
+		(memory (export "memory") 1)
+		(data (i32.const 0) "zetaalphamid")
+		(func (export "handle") (param i32)
+			(call $storage_write (i32.const 0) (i32.const 4) (i32.const 4) (i32.const 5))
+			(call $storage_write (i32.const 4) (i32.const 5) (i32.const 9) (i32.const 3))
+			(call $storage_write (i32.const 9) (i32.const 3) (i32.const 0) (i32.const 4))))"#);
+	let mut spree_module = new_module(&wasm);
+	spree_module.invoke(CALLER, 1, vec![]).unwrap();
+	let encoded = spree_module.encode_state();
+	let entries = <Vec<(Vec<u8>, Vec<u8>)>>::decode(&mut &encoded[..]).unwrap();
+	let keys = entries.iter().map(|(key, _)| &key[..]).collect::<Vec<_>>();
+	assert_eq!(keys, vec![&b"alpha"[..], b"mid", b"zeta"]);
+
+	let mut restored = new_module(&wasm);
+	restored.apply_state(&encoded).unwrap();
+	assert_eq!(restored.encode_state(), encoded);
+	assert_eq!(restored.storage_snapshot(), spree_module.storage_snapshot());
+}
+
+#[test]
+fn export_returns_two_i32s_through_its_memory() {
+	let wasm = wat(r#"(module
+		(memory (export "memory") 1)
+		(func (export "handle") (param i32))
+		(func (export "divmod") (param $a i32) (param $b i32) (result i32)
+			(i32.store (i32.const 16) (i32.div_u (local.get $a) (local.get $b)))
+			(i32.store (i32.const 20) (i32.rem_u (local.get $a) (local.get $b)))
+			(i32.const 16)))"#);
+	let mut spree_module = new_module(&wasm);
+	let results = spree_module
+		.call_export(
+			CALLER,
+			1,
+			"divmod",
+			&[RuntimeValue::I32(17), RuntimeValue::I32(5)],
+			vec![],
+			&[ValueType::I32, ValueType::I32],
+		)
+		.unwrap();
+	assert_eq!(results, vec![RuntimeValue::I32(3), RuntimeValue::I32(2)]);
+
+	// `handle` doesn't return a pointer to any results.
+	match spree_module.call_export(CALLER, 2, "handle", &[RuntimeValue::I32(2)], vec![], &[]) {
+		Err(Error::Msg(_)) => {}
+		other => panic!("expected an error, got {:?}", other),
+	}
+}
+
+#[test]
+fn invalid_utf8_is_reported_cleanly() {
+	let wasm = wat(r#"(module
+		(import "env" "abort" (func $abort (param i32 i32 i32)))
+		(memory (export "memory") 1)
+		(data (i32.const 0) "\ff\fe")
+		(func (export "handle") (param i32)
+			(call $abort (i32.const 1) (i32.const 0) (i32.const 2))))"#);
+	match new_module(&wasm).invoke(CALLER, 1, vec![]) {
+		Err(Error::InvalidUtf8(err)) => assert_eq!(err.as_bytes(), &[0xff, 0xfe]),
+		other => panic!("unexpected result: {:?}", other),
+	}
+}
+
+/// Stores the request under the key of the lamport clock queue.
+const QUEUE_WRITING_WAT: &str = r#"(module
+	(import "env" "scratch_buf_size" (func $scratch_buf_size (result i32)))
+	(import "env" "scratch_buf_read" (func $scratch_buf_read (param i32)))
+	(import "env" "storage_write" (func $storage_write (param i32 i32 i32 i32)))
+	(memory (export "memory") 1)
+	(data (i32.const 0) ":stack")
+	(func (export "handle") (param i32)
+		(call $scratch_buf_read (i32.const 64))
+		(call $storage_write (i32.const 0) (i32.const 6) (i32.const 64) (call $scratch_buf_size))))"#;
+
+#[test]
+fn queue_contents_are_read_from_the_namespace_of_the_caller() {
+	let targeted = |recepient, at, payload: &[u8]| TargetedMsg {
+		recepient,
+		msg: TimestampedMsg {
+			at,
+			payload: payload.to_vec(),
+			nonce: at,
+		},
+	};
+	let wasm = wat(QUEUE_WRITING_WAT);
+	let mut spree_module = new_module(&wasm).with_per_caller_storage(true);
+	let queue = vec![targeted(1, 1, b"foo"), targeted(2, 2, b"bar")];
+	spree_module.invoke(CALLER, 1, queue.encode()).unwrap();
+
+	let contents = spree_module.queue_contents(CALLER).unwrap();
+	assert_eq!(contents.encode(), queue.encode());
+	assert_eq!(
+		contents.iter().map(|t| t.recepient).collect::<Vec<_>>(),
+		vec![1, 2]
+	);
+	// Other callers have a queue of their own, which is still absent.
+	assert!(spree_module.queue_contents(CALLER + 1).unwrap().is_empty());
+
+	spree_module
+		.invoke(CALLER + 1, 2, b"garbage".to_vec())
+		.unwrap();
+	match spree_module.queue_contents(CALLER + 1) {
+		Err(Error::StorageCorruption { .. }) => {}
+		other => panic!(
+			"expected a corrupted queue, got {:?}",
+			other.map(|q| q.len())
+		),
+	}
+	assert_eq!(spree_module.queue_contents(CALLER).unwrap().len(), 2);
+}
+
+#[test]
+fn storage_history_shows_two_enqueues_in_order() {
+	let wasm = lamport_clock();
+	let mut spree_module = new_module(&wasm).with_storage_history(true);
+	for payload in &[b"foo", b"bar"] {
+		let enqueue = Req::Enqueue {
+			recepient: 1,
+			payload: payload.to_vec(),
+		};
+		spree_module.invoke(CALLER, 1, enqueue.encode()).unwrap();
+	}
+
+	let values = |key: &[u8]| {
+		spree_module
+			.storage_history()
+			.iter()
+			.filter(|op| op.key == key)
+			.map(|op| (op.old.clone(), op.new.clone()))
+			.collect::<Vec<_>>()
+	};
+	assert_eq!(
+		values(b":current_timestamp"),
+		vec![
+			(None, Some(1u64.encode())),
+			(Some(1u64.encode()), Some(2u64.encode()))
+		]
+	);
+	let stack = |raw: Option<Vec<u8>>| {
+		raw.map(|raw| {
+			Vec::<TargetedMsg>::decode(&mut &raw[..])
+				.unwrap()
+				.into_iter()
+				.map(|targeted| (targeted.recepient, targeted.msg.at, targeted.msg.payload))
+				.collect::<Vec<_>>()
+		})
+	};
+	let stack_ops = values(b":stack")
+		.into_iter()
+		.map(|(old, new)| (stack(old), stack(new)))
+		.collect::<Vec<_>>();
+	let first = vec![(1, 1, b"foo".to_vec())];
+	let second = vec![(1, 1, b"foo".to_vec()), (1, 2, b"bar".to_vec())];
+	assert_eq!(
+		stack_ops,
+		vec![(None, Some(first.clone())), (Some(first), Some(second))]
+	);
+
+	// The mutations of a failed invocation don't make it into the log: running out of host calls
+	// interrupts an enqueue after it advances the clock.
+	let history = spree_module.storage_history().to_vec();
+	let mut spree_module = spree_module.with_max_host_calls(3);
+	let enqueue = Req::Enqueue {
+		recepient: 1,
+		payload: b"baz".to_vec(),
+	};
+	assert!(spree_module.invoke(CALLER, 1, enqueue.encode()).is_err());
+	assert_eq!(spree_module.storage_history(), &history[..]);
+}
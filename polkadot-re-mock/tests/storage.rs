@@ -0,0 +1,108 @@
+mod common;
+
+use common::{counter_module, scratch_u64};
+use polkadot_re_mock::{
+	spree::{SpreeIcmpAccumulator, SpreeModule},
+	storage::SpreeStorage,
+};
+use std::{cell::RefCell, collections::HashMap, rc::Rc};
+
+/// The number of calls to each of the methods of `SpreeStorage`.
+#[derive(Default)]
+struct Accesses {
+	gets: usize,
+	sets: usize,
+	deletes: usize,
+	scans: usize,
+}
+
+/// An in-memory backend that counts the accesses to it.
+struct CountingStorage {
+	entries: HashMap<Vec<u8>, Vec<u8>>,
+	accesses: Rc<RefCell<Accesses>>,
+}
+
+impl SpreeStorage for CountingStorage {
+	fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+		self.accesses.borrow_mut().gets += 1;
+		self.entries.get(key).cloned()
+	}
+
+	fn set(&mut self, key: Vec<u8>, val: Vec<u8>) {
+		self.accesses.borrow_mut().sets += 1;
+		self.entries.insert(key, val);
+	}
+
+	fn delete(&mut self, key: &[u8]) {
+		self.accesses.borrow_mut().deletes += 1;
+		self.entries.remove(key);
+	}
+
+	fn scan_prefix(&self, prefix: &[u8]) -> Vec<(Vec<u8>, Vec<u8>)> {
+		self.accesses.borrow_mut().scans += 1;
+		self.entries
+			.iter()
+			.filter(|(key, _)| key.starts_with(prefix))
+			.map(|(key, val)| (key.clone(), val.clone()))
+			.collect()
+	}
+}
+
+#[test]
+fn host_goes_through_the_storage_backend() {
+	let accesses = Rc::new(RefCell::new(Accesses::default()));
+	let wasm = counter_module();
+	let mut spree_module =
+		SpreeModule::new(wasm.path(), SpreeIcmpAccumulator::with_inbound_msgs(vec![]))
+			.with_storage(CountingStorage {
+				entries: HashMap::new(),
+				accesses: accesses.clone(),
+			});
+
+	spree_module.invoke(100, 1, vec![]).unwrap();
+	let after_first = accesses.borrow().gets;
+	assert!(after_first > 0);
+	assert!(accesses.borrow().sets > 0);
+
+	spree_module.invoke(100, 2, vec![]).unwrap();
+	assert_eq!(scratch_u64(spree_module.last_scratch()), 2);
+	assert!(accesses.borrow().gets > after_first);
+	assert_eq!(
+		spree_module.storage().get(b"count"),
+		Some(2u64.to_le_bytes().to_vec())
+	);
+
+	spree_module.clear_storage();
+	assert_eq!(accesses.borrow().scans, 1);
+	assert!(accesses.borrow().deletes > 0);
+	assert_eq!(spree_module.storage().get(b"count"), None);
+}
+
+#[test]
+fn diff_reports_old_and_new_values() {
+	let wasm = counter_module();
+	let mut spree_module =
+		SpreeModule::new(wasm.path(), SpreeIcmpAccumulator::with_inbound_msgs(vec![]))
+			.with_storage(
+				vec![(b"other".to_vec(), b"x".to_vec())]
+					.into_iter()
+					.collect::<HashMap<_, _>>(),
+			);
+
+	let diff = spree_module.invoke_with_diff(0, 1, vec![]).unwrap();
+	assert_eq!(
+		diff.written,
+		vec![(b"count".to_vec(), None, 1u64.to_le_bytes().to_vec())]
+	);
+	assert!(diff.deleted.is_empty());
+
+	let diff = spree_module.invoke_with_diff(0, 2, vec![]).unwrap();
+	assert_eq!(
+		diff.written,
+		vec![(
+			b"count".to_vec(),
+			Some(1u64.to_le_bytes().to_vec()),
+			2u64.to_le_bytes().to_vec()
+		)]
+	);
+}
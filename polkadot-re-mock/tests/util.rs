@@ -0,0 +1,25 @@
+use polkadot_re_mock::{error::Error, util::Args};
+use wasmi::{RuntimeArgs, RuntimeValue};
+
+#[test]
+fn mis_arity_call_is_reported() {
+	let values = [RuntimeValue::I32(1), RuntimeValue::I32(2)];
+	match Args::new("call_spree", RuntimeArgs::from(&values[..]), 4) {
+		Err(Error::Msg(msg)) => assert_eq!(msg, "call_spree: expected 4 arguments, got 2"),
+		_ => panic!("the arity mismatch should be reported"),
+	}
+}
+
+#[test]
+fn argument_of_a_wrong_type_is_reported() {
+	let values = [RuntimeValue::I32(7), RuntimeValue::I64(1)];
+	let args = Args::new("call_spree", RuntimeArgs::from(&values[..]), 2).unwrap();
+	assert_eq!(args.u32(0, "handle").unwrap(), 7);
+	match args.u32(1, "time_slice") {
+		Err(Error::Msg(msg)) => assert_eq!(
+			msg,
+			"call_spree: argument `time_slice` at position 1 should be i32"
+		),
+		_ => panic!("the type mismatch should be reported"),
+	}
+}
@@ -1,32 +1,691 @@
-use codec::{Decode, Encode};
+use codec::{Compact, Decode, DecodeLimit, Encode, EncodeLike, Input, Output};
 
 pub type ParaId = u32;
 pub type Timestamp = u64;
 
+/// The way a `ParaId` is represented on the wire.
+pub trait ParaIdCodec {
+    fn encode_para_id<W: Output>(para_id: ParaId, dest: &mut W);
+    fn decode_para_id<I: Input>(input: &mut I) -> Result<ParaId, codec::Error>;
+}
+
+/// The default representation: SCALE, i.e. fixed 4 bytes little-endian.
+pub enum ScaleParaId {}
+
+impl ParaIdCodec for ScaleParaId {
+    fn encode_para_id<W: Output>(para_id: ParaId, dest: &mut W) {
+        para_id.encode_to(dest);
+    }
+
+    fn decode_para_id<I: Input>(input: &mut I) -> Result<ParaId, codec::Error> {
+        ParaId::decode(input)
+    }
+}
+
+/// The SCALE compact representation, which takes less space for small IDs.
+pub enum CompactParaId {}
+
+impl ParaIdCodec for CompactParaId {
+    fn encode_para_id<W: Output>(para_id: ParaId, dest: &mut W) {
+        Compact(para_id).encode_to(dest);
+    }
+
+    fn decode_para_id<I: Input>(input: &mut I) -> Result<ParaId, codec::Error> {
+        Ok(<Compact<ParaId>>::decode(input)?.0)
+    }
+}
+
+/// The representation of `ParaId`s in `TargetedMsg` and `Resp`.
+///
+/// Selected by the `compact-para-id` feature. Note that this changes the wire format.
+#[cfg(not(feature = "compact-para-id"))]
+pub type WireParaId = ScaleParaId;
+#[cfg(feature = "compact-para-id")]
+pub type WireParaId = CompactParaId;
+
+/// Encode the inbound bundles, as left by `poll`, out of `(sender, blob)`.
+pub fn encode_inbound<C: ParaIdCodec, W: Output>(inbound: &[(ParaId, Vec<u8>)], dest: &mut W) {
+    Compact(inbound.len() as u32).encode_to(dest);
+    for (sender, blob) in inbound {
+        C::encode_para_id(*sender, dest);
+        blob.encode_to(dest);
+    }
+}
+
+/// Decode the inbound bundles, as left by `poll`, into `(sender, blob)`.
+pub fn decode_inbound<C: ParaIdCodec, I: Input>(
+    input: &mut I,
+) -> Result<Vec<(ParaId, Vec<u8>)>, codec::Error> {
+    let len = <Compact<u32>>::decode(input)?.0;
+    // Don't trust the length for preallocation, the input might be shorter.
+    let mut inbound = Vec::new();
+    for _ in 0..len {
+        let sender = C::decode_para_id(input)?;
+        inbound.push((sender, Vec::<u8>::decode(input)?));
+    }
+    Ok(inbound)
+}
+
+/// The encoding of structured payloads, such as `Envelope`.
+///
+/// This only concerns the contents of the payloads, which the lamport clock treats opaquely. The
+/// framing of requests and messages is always SCALE.
+pub trait PayloadCodec {
+    type Error;
+
+    fn encode_envelope(envelope: &Envelope) -> Vec<u8>;
+    fn decode_envelope(payload: &[u8]) -> Result<Envelope, Self::Error>;
+}
+
+/// The default encoding: SCALE.
+pub enum ScaleCodec {}
+
+impl PayloadCodec for ScaleCodec {
+    type Error = codec::Error;
+
+    fn encode_envelope(envelope: &Envelope) -> Vec<u8> {
+        envelope.encode()
+    }
+
+    fn decode_envelope(payload: &[u8]) -> Result<Envelope, codec::Error> {
+        decode_with_depth(payload, MAX_DECODE_DEPTH)
+    }
+}
+
+/// JSON, for interop with systems that don't speak SCALE.
+#[cfg(feature = "json")]
+pub enum JsonCodec {}
+
+#[cfg(feature = "json")]
+impl PayloadCodec for JsonCodec {
+    type Error = serde_json::Error;
+
+    fn encode_envelope(envelope: &Envelope) -> Vec<u8> {
+        serde_json::to_vec(envelope).expect("envelope only contains serializable fields; qed")
+    }
+
+    fn decode_envelope(payload: &[u8]) -> Result<Envelope, serde_json::Error> {
+        serde_json::from_slice(payload)
+    }
+}
+
+/// The default maximum depth of nested structures that is accepted by the decode paths.
+pub const MAX_DECODE_DEPTH: u32 = 32;
+
+/// Decode a value of type `T` from the given bytes, refusing encodings which nest deeper than
+/// `max_depth`.
+pub fn decode_with_depth<T: Decode>(bytes: &[u8], max_depth: u32) -> Result<T, codec::Error> {
+    T::decode_with_depth_limit(max_depth, bytes)
+}
+
+/// Bits of the set of host capabilities.
+///
+/// Each capability grants access to a group of host functions. The scratch buffer, memory and
+/// ephemeral storage functions, `host_capabilities`, `abort` and `last_time_slice` are always
+/// available.
+pub mod capabilities {
+    /// `send`, `poll` and `poll_peek`.
+    pub const ICMP: u64 = 1 << 0;
+    /// `storage_read`, `storage_write` and `storage_increment`.
+    pub const STORAGE: u64 = 1 << 1;
+    /// `storage_clear`.
+    pub const STORAGE_CLEAR: u64 = 1 << 2;
+    /// `gas_left`.
+    pub const GAS: u64 = 1 << 3;
+    /// `caller_para_id`.
+    pub const CALLER: u64 = 1 << 4;
+    /// `random`.
+    pub const RANDOM: u64 = 1 << 5;
+
+    /// All capabilities.
+    pub const ALL: u64 = ICMP | STORAGE | STORAGE_CLEAR | GAS | CALLER | RANDOM;
+}
+
+/// Status codes returned by the host functions to a SPREE module.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(i32)]
+pub enum StatusCode {
+    /// The operation succeeded.
+    Ok = 0,
+    /// The requested item doesn't exist.
+    NotFound = 1,
+    /// The channel to the recepient is closed.
+    ChannelClosed = 2,
+    /// A message was already sent to the recepient.
+    AlreadySent = 3,
+    /// An empty blob was passed where a non-empty one is expected.
+    EmptyBlob = 4,
+    /// The recepient is reserved and can't receive messages.
+    ReservedRecepient = 5,
+    /// The blob is larger than `MAX_MESSAGE_LEN`.
+    TooLarge = 6,
+    /// The host can't accept the request right now, but might be able to later.
+    Busy = 7,
+    /// The data failed to decode.
+    Malformed = 8,
+}
+
+impl StatusCode {
+    /// Convert a raw status code into a `StatusCode`.
+    ///
+    /// Returns `None` if the code is unknown.
+    pub fn from_i32(code: i32) -> Option<Self> {
+        match code {
+            0 => Some(StatusCode::Ok),
+            1 => Some(StatusCode::NotFound),
+            2 => Some(StatusCode::ChannelClosed),
+            3 => Some(StatusCode::AlreadySent),
+            4 => Some(StatusCode::EmptyBlob),
+            5 => Some(StatusCode::ReservedRecepient),
+            6 => Some(StatusCode::TooLarge),
+            7 => Some(StatusCode::Busy),
+            8 => Some(StatusCode::Malformed),
+            _ => None,
+        }
+    }
+
+    /// Returns `true` if the failure is temporary and the request can be retried.
+    pub fn is_transient(self) -> bool {
+        matches!(self, StatusCode::Busy)
+    }
+
+    /// Returns the raw representation of this status code.
+    pub fn as_i32(self) -> i32 {
+        self as i32
+    }
+}
+
+/// The maximum length of a message blob accepted by `send`.
+pub const MAX_MESSAGE_LEN: u32 = 64 * 1024;
+
+/// Details of a failed `send`, left in the scratch buffer for diagnostics.
+#[derive(Encode, Decode)]
+pub struct SendError {
+    /// The raw `StatusCode` returned by `send`.
+    pub code: i32,
+    pub recepient: ParaId,
+    /// A human readable description of the failure.
+    pub reason: String,
+}
+
+/// A structured payload carrying headers, e.g. for routing metadata, along with the body.
+///
+/// The lamport clock treats payloads opaquely, so protocols layered on top are free to use this
+/// as the payload of their messages.
+#[derive(Encode, Decode, Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "json", derive(serde::Serialize, serde::Deserialize))]
+pub struct Envelope {
+    /// The headers as `(name, value)`. A name might appear more than once.
+    pub headers: Vec<(String, Vec<u8>)>,
+    pub body: Vec<u8>,
+}
+
+impl Envelope {
+    pub fn new(body: Vec<u8>) -> Self {
+        Envelope {
+            headers: Vec::new(),
+            body,
+        }
+    }
+
+    /// Append a header with the given name and value.
+    pub fn with_header(mut self, name: impl Into<String>, value: Vec<u8>) -> Self {
+        self.headers.push((name.into(), value));
+        self
+    }
+
+    /// Returns the value of the first header with the given name, if any.
+    pub fn header(&self, name: &str) -> Option<&[u8]> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header == name)
+            .map(|(_, value)| &value[..])
+    }
+
+    /// Encode the envelope for use as a message payload.
+    pub fn to_payload(&self) -> Vec<u8> {
+        self.to_payload_with::<ScaleCodec>()
+    }
+
+    /// Parse an envelope out of a message payload.
+    pub fn from_payload(payload: &[u8]) -> Result<Self, codec::Error> {
+        Self::from_payload_with::<ScaleCodec>(payload)
+    }
+
+    /// Like `to_payload`, but with the given encoding.
+    pub fn to_payload_with<C: PayloadCodec>(&self) -> Vec<u8> {
+        C::encode_envelope(self)
+    }
+
+    /// Like `from_payload`, but with the given encoding.
+    pub fn from_payload_with<C: PayloadCodec>(payload: &[u8]) -> Result<Self, C::Error> {
+        C::decode_envelope(payload)
+    }
+}
+
 #[derive(Encode, Decode)]
 pub struct TimestampedMsg {
     pub at: Timestamp,
     pub payload: Vec<u8>,
+    /// The number of ticks after `at` the message stays valid. `None` means the message never
+    /// expires.
+    #[cfg(feature = "ttl")]
+    pub ttl: Option<Timestamp>,
+    /// A number unique for every message sent by a SPREE module. Nonces are increasing
+    /// monotonically, so the receiver can use them to detect duplicates.
+    pub nonce: u64,
+    /// Messages with higher priority are sent first.
+    #[cfg(feature = "priority")]
+    pub priority: u8,
+}
+
+impl TimestampedMsg {
+    /// Returns `true` if the message has expired by the time `now`.
+    #[cfg(feature = "ttl")]
+    pub fn is_expired(&self, now: Timestamp) -> bool {
+        match self.ttl {
+            Some(ttl) => self.at.saturating_add(ttl) < now,
+            None => false,
+        }
+    }
+
+    /// Returns `true` if the message has expired by the time `now`.
+    ///
+    /// Without the `ttl` feature messages never expire.
+    #[cfg(not(feature = "ttl"))]
+    pub fn is_expired(&self, _now: Timestamp) -> bool {
+        false
+    }
 }
 
-#[derive(Decode, Encode)]
 pub struct TargetedMsg {
     pub recepient: ParaId,
     pub msg: TimestampedMsg,
 }
 
+impl Encode for TargetedMsg {
+    fn encode_to<W: Output>(&self, dest: &mut W) {
+        WireParaId::encode_para_id(self.recepient, dest);
+        self.msg.encode_to(dest);
+    }
+}
+
+impl EncodeLike for TargetedMsg {}
+
+impl Decode for TargetedMsg {
+    fn decode<I: Input>(input: &mut I) -> Result<Self, codec::Error> {
+        Ok(TargetedMsg {
+            recepient: WireParaId::decode_para_id(input)?,
+            msg: TimestampedMsg::decode(input)?,
+        })
+    }
+}
+
 #[derive(Encode, Decode)]
 pub enum Req {
     /// Enqueue a message.
-    Enqueue { recepient: ParaId, payload: Vec<u8> },
+    ///
+    /// If `ttl` is specified, the message is dropped instead of being sent if it has expired.
+    Enqueue {
+        recepient: ParaId,
+        payload: Vec<u8>,
+        #[cfg(feature = "ttl")]
+        ttl: Option<Timestamp>,
+    },
     /// Receive all timestamped messages.
     Poll,
     /// Send all enqueued messages.
     FanOut,
+    /// Return the number of enqueued messages, encoded as `u32`.
+    QueueLen,
+    /// Remove the first enqueued message with the given recepient and payload.
+    ///
+    /// Returns whether a message was removed, encoded as `bool`.
+    Retract { recepient: ParaId, payload: Vec<u8> },
+    /// Group all enqueued messages by the recepient and stash them without sending.
+    Prepare,
+    /// Send all message bundles stashed by `Prepare`.
+    ///
+    /// The bundles that fail to send stay stashed until the next `Commit`.
+    Commit,
+    /// Enqueue several messages at once.
+    ///
+    /// This is equivalent to a series of `Enqueue` but is cheaper.
+    EnqueueBatch { messages: Vec<(ParaId, Vec<u8>)> },
+    /// Send all enqueued messages, but only if there are at least `min_messages` of them.
+    ///
+    /// Returns whether the messages were sent, encoded as `bool`.
+    FanOutIfReady { min_messages: u32 },
+    /// Return the time slice of the last successful invocation, encoded as `Option<u32>`.
+    LastTimeSlice,
+    /// Do nothing but echo the given nonce, encoded as `u64`.
+    ///
+    /// This is useful for checking that the module is loadable and responsive.
+    Ping { nonce: u64 },
+    /// Return the state of the module, encoded as `StateSnapshot`.
+    ExportState,
+    /// Overwrite the state of the module with the given snapshot.
+    ImportState { snapshot: StateSnapshot },
+    /// Return the size of the encoded queue in bytes, encoded as `u32`.
+    QueueBytes,
+    /// Acknowledge the messages from `sender` stamped up to and including `up_to_timestamp`.
+    ///
+    /// Acknowledged messages are no longer delivered by `Poll`. Acknowledging an earlier
+    /// timestamp than before has no effect.
+    Ack {
+        sender: ParaId,
+        up_to_timestamp: Timestamp,
+    },
 }
 
+/// The number of `Req` variants known to this version of the primitives.
+///
+/// Must be kept in sync with the definition of `Req`.
+const REQ_VARIANT_COUNT: u8 = 15;
+
+/// A request decoded by `decode_req_lenient`.
+pub enum LenientReq {
+    /// A request of a variant known to this version.
+    Known(Req),
+    /// A request of a variant introduced by a newer version.
+    ///
+    /// `raw` holds the encoded body that followed the variant index.
+    Unknown { index: u8, raw: Vec<u8> },
+}
+
+/// Decode a `Req`, tolerating variants that are not known to this version.
+///
+/// This allows callers built against newer primitives to talk to older modules. Malformed
+/// encodings of the known variants are still reported as errors.
+pub fn decode_req_lenient(bytes: &[u8], max_depth: u32) -> Result<LenientReq, codec::Error> {
+    let index = *bytes.first().ok_or("empty request")?;
+    if index >= REQ_VARIANT_COUNT {
+        return Ok(LenientReq::Unknown {
+            index,
+            raw: bytes[1..].to_vec(),
+        });
+    }
+    decode_with_depth(bytes, max_depth).map(LenientReq::Known)
+}
+
+/// The full state of the lamport clock module, used for syncing doppelgangers.
 #[derive(Encode, Decode)]
+pub struct StateSnapshot {
+    pub timestamp: Timestamp,
+    /// The last assigned nonce.
+    pub nonce: u64,
+    pub queue: Vec<TargetedMsg>,
+    pub prepared: Vec<(ParaId, Vec<TimestampedMsg>)>,
+    /// The acknowledged timestamps as `(sender, up_to_timestamp)`, sorted by sender.
+    pub acks: Vec<(ParaId, Timestamp)>,
+    /// The last recepient served by an interrupted fan out, if any.
+    pub fan_out_cursor: Option<ParaId>,
+}
+
 pub struct Resp {
-    // (sender, msg)
+    /// The inbound messages as `(sender, msgs)`, sorted by sender.
     pub inbound: Vec<(ParaId, Vec<TimestampedMsg>)>,
 }
+
+impl Encode for Resp {
+    fn encode_to<W: Output>(&self, dest: &mut W) {
+        Compact(self.inbound.len() as u32).encode_to(dest);
+        for (sender, msgs) in &self.inbound {
+            WireParaId::encode_para_id(*sender, dest);
+            msgs.encode_to(dest);
+        }
+    }
+}
+
+impl EncodeLike for Resp {}
+
+impl Decode for Resp {
+    fn decode<I: Input>(input: &mut I) -> Result<Self, codec::Error> {
+        let len = <Compact<u32>>::decode(input)?.0;
+        // Don't trust the length for preallocation, the input might be shorter.
+        let mut inbound = Vec::new();
+        for _ in 0..len {
+            let sender = WireParaId::decode_para_id(input)?;
+            inbound.push((sender, Vec::<TimestampedMsg>::decode(input)?));
+        }
+        Ok(Resp { inbound })
+    }
+}
+
+impl Resp {
+    /// Build a response out of the raw result of `poll`, decoding the bundle from every sender.
+    pub fn from_poll(raw_poll: Vec<(ParaId, Vec<u8>)>) -> Result<Resp, codec::Error> {
+        let inbound = raw_poll
+            .into_iter()
+            .map(|(sender, raw)| Ok((sender, decode_poll_bundle(&raw)?)))
+            .collect::<Result<_, codec::Error>>()?;
+        Ok(Resp { inbound })
+    }
+}
+
+/// The result of a `Poll` request, left in the scratch buffer.
+///
+/// The status allows to tell a failure from a response that happens to be empty.
+#[derive(Encode, Decode)]
+pub struct RespEnvelope {
+    /// The raw `StatusCode` of the request.
+    pub status: i32,
+    /// The response, present only if the status is `StatusCode::Ok`.
+    pub body: Option<Resp>,
+}
+
+impl RespEnvelope {
+    pub fn ok(resp: Resp) -> Self {
+        RespEnvelope {
+            status: StatusCode::Ok.as_i32(),
+            body: Some(resp),
+        }
+    }
+
+    pub fn err(status: StatusCode) -> Self {
+        RespEnvelope {
+            status: status.as_i32(),
+            body: None,
+        }
+    }
+
+    /// Returns the status of the request, or `None` if the status code is unknown.
+    pub fn status(&self) -> Option<StatusCode> {
+        StatusCode::from_i32(self.status)
+    }
+}
+
+/// Decode a bundle of timestamped messages sent by a doppelganger.
+pub fn decode_poll_bundle(raw: &[u8]) -> Result<Vec<TimestampedMsg>, codec::Error> {
+    decode_with_depth(raw, MAX_DECODE_DEPTH)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inbound_round_trips_under_either_para_id_codec() {
+        let inbound = vec![
+            (1, b"foo".to_vec()),
+            (100_000, vec![]),
+            (ParaId::MAX, b"bar".to_vec()),
+        ];
+
+        let mut scale = Vec::new();
+        encode_inbound::<ScaleParaId, _>(&inbound, &mut scale);
+        // The default representation is the plain SCALE encoding of the pairs.
+        assert_eq!(scale, inbound.encode());
+        assert_eq!(
+            decode_inbound::<ScaleParaId, _>(&mut &scale[..]).unwrap(),
+            inbound
+        );
+
+        let mut compact = Vec::new();
+        encode_inbound::<CompactParaId, _>(&inbound, &mut compact);
+        assert_ne!(compact, scale);
+        assert_eq!(
+            decode_inbound::<CompactParaId, _>(&mut &compact[..]).unwrap(),
+            inbound
+        );
+    }
+
+    fn msg(at: Timestamp, payload: &[u8]) -> TimestampedMsg {
+        TimestampedMsg {
+            at,
+            payload: payload.to_vec(),
+            #[cfg(feature = "ttl")]
+            ttl: None,
+            nonce: at,
+            #[cfg(feature = "priority")]
+            priority: 0,
+        }
+    }
+
+    #[test]
+    fn poll_bundles_decode_fallibly() {
+        let bundle = vec![msg(1, b"foo"), msg(2, b"bar")].encode();
+        let msgs = decode_poll_bundle(&bundle).unwrap();
+        assert_eq!(
+            msgs.iter()
+                .map(|msg| (msg.at, &msg.payload[..]))
+                .collect::<Vec<_>>(),
+            vec![(1, &b"foo"[..]), (2, &b"bar"[..])]
+        );
+
+        // A truncated bundle, and garbage claiming more messages than there are.
+        assert!(decode_poll_bundle(&bundle[..bundle.len() - 1]).is_err());
+        assert!(decode_poll_bundle(&[0xff, 0xff]).is_err());
+    }
+
+    #[test]
+    fn resp_from_poll_fails_on_any_malformed_bundle() {
+        let resp = Resp::from_poll(vec![
+            (1, vec![msg(1, b"foo")].encode()),
+            (2, Vec::<TimestampedMsg>::new().encode()),
+        ])
+        .unwrap();
+        assert_eq!(
+            resp.inbound
+                .iter()
+                .map(|(sender, msgs)| (*sender, msgs.len()))
+                .collect::<Vec<_>>(),
+            vec![(1, 1), (2, 0)]
+        );
+
+        assert!(Resp::from_poll(vec![
+            (1, vec![msg(1, b"foo")].encode()),
+            (2, b"garbage".to_vec()),
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn resp_envelopes_round_trip() {
+        let ok = RespEnvelope::ok(Resp {
+            inbound: vec![(1, vec![msg(1, b"foo"), msg(2, b"bar")])],
+        });
+        let decoded = RespEnvelope::decode(&mut &ok.encode()[..]).unwrap();
+        assert_eq!(decoded.status(), Some(StatusCode::Ok));
+        let body = decoded.body.expect("a successful response has a body");
+        assert_eq!(body.inbound.len(), 1);
+        assert_eq!(body.inbound[0].0, 1);
+        assert_eq!(
+            body.inbound[0].1.encode(),
+            ok.body.unwrap().inbound[0].1.encode()
+        );
+
+        let err = RespEnvelope::err(StatusCode::Malformed);
+        let decoded = RespEnvelope::decode(&mut &err.encode()[..]).unwrap();
+        assert_eq!(decoded.status(), Some(StatusCode::Malformed));
+        assert!(decoded.body.is_none());
+
+        // An empty response is not confused with a failure.
+        let empty = RespEnvelope::ok(Resp { inbound: vec![] });
+        let decoded = RespEnvelope::decode(&mut &empty.encode()[..]).unwrap();
+        assert_eq!(decoded.status(), Some(StatusCode::Ok));
+        assert!(decoded.body.unwrap().inbound.is_empty());
+    }
+
+    fn sample_envelope() -> Envelope {
+        Envelope::new(b"body".to_vec())
+            .with_header("content-type", b"text/plain".to_vec())
+            .with_header("trace", vec![0, 1, 2])
+            .with_header("trace", vec![3])
+    }
+
+    #[test]
+    fn envelope_round_trips_as_scale() {
+        let envelope = sample_envelope();
+        let payload = envelope.to_payload_with::<ScaleCodec>();
+        assert_eq!(payload, envelope.encode());
+        assert_eq!(envelope.to_payload(), payload);
+        let parsed = Envelope::from_payload_with::<ScaleCodec>(&payload).unwrap();
+        assert_eq!(parsed, envelope);
+        assert_eq!(parsed.header("trace"), Some(&[0, 1, 2][..]));
+        assert!(Envelope::from_payload_with::<ScaleCodec>(&payload[..payload.len() - 1]).is_err());
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn envelope_round_trips_as_json() {
+        let envelope = sample_envelope();
+        let payload = envelope.to_payload_with::<JsonCodec>();
+        let value: serde_json::Value = serde_json::from_slice(&payload).unwrap();
+        assert_eq!(value["body"], serde_json::json!([98, 111, 100, 121]));
+        assert_eq!(
+            Envelope::from_payload_with::<JsonCodec>(&payload).unwrap(),
+            envelope
+        );
+        // The encodings are not interchangeable.
+        assert!(Envelope::from_payload_with::<JsonCodec>(&envelope.encode()).is_err());
+    }
+
+    #[test]
+    fn decode_refuses_encodings_deeper_than_the_limit() {
+        // Every vector of non-byte items counts as a level, while a byte vector is a leaf.
+        let nested = vec![vec![vec![1u8]]].encode();
+        assert!(decode_with_depth::<Vec<Vec<Vec<u8>>>>(&nested, 2).is_ok());
+        assert!(decode_with_depth::<Vec<Vec<Vec<u8>>>>(&nested, 1).is_err());
+    }
+
+    #[test]
+    fn status_codes_round_trip_through_i32() {
+        for code in 0..=8 {
+            let status = StatusCode::from_i32(code).expect("the code is known");
+            assert_eq!(status.as_i32(), code);
+        }
+        assert_eq!(StatusCode::from_i32(0), Some(StatusCode::Ok));
+        assert_eq!(StatusCode::from_i32(2), Some(StatusCode::ChannelClosed));
+        assert_eq!(StatusCode::from_i32(9), None);
+        assert_eq!(StatusCode::from_i32(-1), None);
+    }
+
+    #[test]
+    fn lenient_decode_falls_back_for_unknown_variants() {
+        match decode_req_lenient(&[REQ_VARIANT_COUNT, 1, 2], MAX_DECODE_DEPTH) {
+            Ok(LenientReq::Unknown { index, raw }) => {
+                assert_eq!(index, REQ_VARIANT_COUNT);
+                assert_eq!(raw, vec![1, 2]);
+            }
+            _ => panic!("an unknown variant should be tolerated"),
+        }
+        match decode_req_lenient(&Req::QueueLen.encode(), MAX_DECODE_DEPTH) {
+            Ok(LenientReq::Known(Req::QueueLen)) => {}
+            _ => panic!("a known variant should be decoded"),
+        }
+        // Malformed known variants are still errors.
+        assert!(decode_req_lenient(&[0], MAX_DECODE_DEPTH).is_err());
+        assert!(decode_req_lenient(&[], MAX_DECODE_DEPTH).is_err());
+    }
+
+    #[test]
+    fn req_variant_count_is_in_sync_with_req() {
+        // `Ack` is the last variant.
+        let ack = Req::Ack {
+            sender: 1,
+            up_to_timestamp: 1,
+        };
+        assert_eq!(ack.encode()[0], REQ_VARIANT_COUNT - 1);
+    }
+}
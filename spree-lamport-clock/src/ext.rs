@@ -1,8 +1,9 @@
 //! Bindings to the SPREE host API.
 
 use codec::Decode;
-use primitives::ParaId;
+use primitives::{ParaId, SendError, StatusCode, WireParaId};
 
+#[cfg(not(test))]
 mod ffi {
 	use super::ParaId;
 
@@ -48,24 +49,50 @@ mod ffi {
 		/// scratch buffer.
 		pub fn scratch_buf_read(out_ptr: *const u8);
 
+		/// Copy the region of the scratch buffer, specified by `offset` and `len`, into the memory
+		/// of this instance at `out_ptr`.
+		///
+		/// The region is clamped to the bounds of the scratch buffer. Returns the number of bytes
+		/// copied.
+		pub fn scratch_buf_read_at(out_ptr: *const u8, offset: usize, len: usize) -> usize;
+
+		/// Replace the contents of the scratch buffer with the blob specified by `blob_ptr` and
+		/// `blob_len`.
+		///
+		/// This is used for returning data to the caller.
+		pub fn scratch_buf_write(blob_ptr: *const u8, blob_len: usize);
+
 		/// Send a message blob, specified by `blob_ptr` and `blob_len` to the SPREE module's
 		/// doppelganger on the opposite side of the ICMP channel specified by `para_id`.
 		///
-		/// Returns 0 on success or non-0 otherwise.
-		pub fn send(para_id: ParaId, blob_ptr: *const u8, blob_len: usize) -> usize;
+		/// Returns a `StatusCode`. Empty blobs are rejected with `StatusCode::EmptyBlob`, blobs
+		/// longer than `MAX_MESSAGE_LEN` with `StatusCode::TooLarge` and messages to reserved
+		/// parachains with `StatusCode::ReservedRecepient`. A message to a parachain that was
+		/// already sent a message replaces it, returning `StatusCode::AlreadySent`.
+		///
+		/// If the send doesn't return `StatusCode::Ok`, the scratch buffer is filled with
+		/// `SendError` describing the failure. Otherwise, the scratch buffer is left intact.
+		pub fn send(para_id: ParaId, blob_ptr: *const u8, blob_len: usize) -> i32;
 
-		/// Fill the scratch buffer with all inbound messages.
+		/// Fill the scratch buffer with all inbound messages and consume them.
 		///
 		/// All messages are encoded as Vec<(sender: ParaId, blob: [u8])>
+		///
+		/// A subsequent `poll` within the same invocation finds no messages. The messages are gone
+		/// for good only if the invocation succeeds.
 		pub fn poll();
 
+		/// Like `poll`, but guaranteed to leave the inbound messages in place, so a subsequent
+		/// `poll` observes the same messages.
+		pub fn poll_peek();
+
 		/// Reads storage by a given key.
 		///
 		/// The key is passed in a buffer, represented by `key_ptr` and `key_len`.
 		///
-		/// Returns 0 if the key found or non-zero otherwise.
+		/// Returns `StatusCode::Ok` if the key found or `StatusCode::NotFound` otherwise.
 		/// The result blob of the read is stored in the scratch buffer.
-		pub fn storage_read(key_ptr: *const u8, key_len: usize) -> usize;
+		pub fn storage_read(key_ptr: *const u8, key_len: usize) -> i32;
 
 		/// Writes a storage value by a given key.
 		///
@@ -77,26 +104,170 @@ mod ffi {
 			val_ptr: *const u8,
 			val_len: usize,
 		);
+
+		/// Returns the current size of the linear memory in wasm pages.
+		pub fn memory_size_pages() -> u32;
+
+		/// Grows the linear memory by the given number of wasm pages.
+		///
+		/// Returns the previous size in pages, or -1 if the memory can't grow.
+		pub fn memory_grow(pages: u32) -> i32;
+
+		/// Reads a value from the ephemeral storage by a given key.
+		///
+		/// The ephemeral storage is separate from the persistent one and is discarded at the end
+		/// of every invocation. Returns `StatusCode::Ok` if the key found or
+		/// `StatusCode::NotFound` otherwise. The value is stored in the scratch buffer.
+		pub fn ephemeral_read(key_ptr: *const u8, key_len: usize) -> i32;
+
+		/// Writes a value to the ephemeral storage by a given key.
+		pub fn ephemeral_write(
+			key_ptr: *const u8,
+			key_len: usize,
+			val_ptr: *const u8,
+			val_len: usize,
+		);
+
+		/// Adds `delta` to the `u64` counter stored under the given key, treating an absent counter
+		/// as 0.
+		///
+		/// The key is passed in a buffer represented by `key_ptr` and `key_len`. Returns the new
+		/// value of the counter.
+		pub fn storage_increment(key_ptr: *const u8, key_len: usize, delta: u64) -> u64;
+
+		/// Removes all entries from the storage of this module.
+		///
+		/// This is a privileged operation: the host traps unless the module is allowed to do this.
+		pub fn storage_clear();
+
+		/// Returns the set of capabilities enabled for this module.
+		///
+		/// See `primitives::capabilities` for the meaning of the bits.
+		pub fn host_capabilities() -> u64;
+
+		/// Abort the execution with the given code and the message specified by `msg_ptr` and
+		/// `msg_len`.
+		///
+		/// Unlike a trap, this allows the host to tell a deliberate failure from an accidental one.
+		/// Never returns.
+		pub fn abort(code: i32, msg_ptr: *const u8, msg_len: usize);
+
+		/// Fill the buffer specified by `out_ptr` and `len` with random bytes.
+		///
+		/// The randomness is deterministic and is not suitable for anything security related.
+		pub fn random(out_ptr: *const u8, len: usize);
+
+		/// Returns the amount of gas left for the current invocation, or `u64::MAX` if the
+		/// invocation is not metered.
+		pub fn gas_left() -> u64;
+
+		/// Returns the ID of the parachain that called into this SPREE module.
+		pub fn caller_para_id() -> ParaId;
+
+		/// Returns the time slice of the last successful invocation of this SPREE module, or `-1`
+		/// if there were none.
+		///
+		/// The value is updated after the current invocation succeeds.
+		pub fn last_time_slice() -> i64;
+	}
+}
+
+#[cfg(test)]
+pub(crate) mod mock;
+#[cfg(test)]
+use self::mock as ffi;
+
+/// Convert a raw status code returned by the host into a result.
+fn to_result(code: i32) -> Result<(), StatusCode> {
+	match StatusCode::from_i32(code) {
+		Some(StatusCode::Ok) => Ok(()),
+		Some(status) => Err(status),
+		None => panic!("host returned an unknown status code {}", code),
 	}
 }
 
 pub fn storage_read(key: &[u8]) -> Option<Vec<u8>> {
 	unsafe {
-		if ffi::storage_read(key.as_ptr(), key.len()) == 0 {
-			let output = scratch_buf_read();
-			Some(output)
-		} else {
-			None
+		match to_result(ffi::storage_read(key.as_ptr(), key.len())) {
+			Ok(()) => Some(scratch_buf_read()),
+			Err(StatusCode::NotFound) => None,
+			Err(status) => panic!("unexpected status from storage_read: {:?}", status),
 		}
 	}
 }
 
+pub fn memory_size_pages() -> u32 {
+	unsafe { ffi::memory_size_pages() }
+}
+
+/// Grow the linear memory by the given number of pages, returning the previous size in pages or
+/// `None` if the memory can't grow.
+pub fn memory_grow(pages: u32) -> Option<u32> {
+	match unsafe { ffi::memory_grow(pages) } {
+		-1 => None,
+		prev_pages => Some(prev_pages as u32),
+	}
+}
+
+pub fn ephemeral_read(key: &[u8]) -> Option<Vec<u8>> {
+	unsafe {
+		match to_result(ffi::ephemeral_read(key.as_ptr(), key.len())) {
+			Ok(()) => Some(scratch_buf_read()),
+			Err(StatusCode::NotFound) => None,
+			Err(status) => panic!("unexpected status from ephemeral_read: {:?}", status),
+		}
+	}
+}
+
+pub fn ephemeral_write(key: &[u8], val: &[u8]) {
+	unsafe {
+		ffi::ephemeral_write(key.as_ptr(), key.len(), val.as_ptr(), val.len());
+	}
+}
+
+/// Atomically add `delta` to the counter stored under `key`, returning the new value.
+pub fn storage_increment(key: &[u8], delta: u64) -> u64 {
+	unsafe { ffi::storage_increment(key.as_ptr(), key.len(), delta) }
+}
+
+/// Read and decode the value stored under `key`, returning `default` if there is no value.
+///
+/// Unlike `storage_read_or`, a value that fails to decode is reported as an error.
+pub fn try_storage_read_or<T: Decode>(key: &[u8], default: T) -> Result<T, codec::Error> {
+	match storage_read(key) {
+		Some(raw) => T::decode(&mut &raw[..]),
+		None => Ok(default),
+	}
+}
+
+/// Read and decode the value stored under `key`.
+///
+/// Returns `default` if there is no value or if it fails to decode.
+pub fn storage_read_or<T: Decode>(key: &[u8], default: T) -> T {
+	storage_read(key)
+		.and_then(|raw| T::decode(&mut &raw[..]).ok())
+		.unwrap_or(default)
+}
+
 pub fn storage_write(key: &[u8], val: &[u8]) {
 	unsafe {
 		ffi::storage_write(key.as_ptr(), key.len(), val.as_ptr(), val.len());
 	}
 }
 
+pub fn storage_clear() {
+	unsafe {
+		ffi::storage_clear();
+	}
+}
+
+pub fn abort(code: i32, msg: &str) -> ! {
+	unsafe {
+		ffi::abort(code, msg.as_ptr(), msg.len());
+	}
+	unreachable!("abort never returns")
+}
+
 pub fn scratch_buf_read() -> Vec<u8> {
 	unsafe {
 		let size = ffi::scratch_buf_size();
@@ -110,18 +281,251 @@ pub fn scratch_buf_read() -> Vec<u8> {
 	}
 }
 
-pub fn send(recepient: ParaId, blob: &[u8]) {
+/// Read the scratch buffer in chunks of at most `chunk_size` bytes.
+pub fn scratch_buf_read_chunked(chunk_size: usize) -> Vec<u8> {
+	assert!(chunk_size > 0, "chunk size should be non-zero");
+	unsafe {
+		let size = ffi::scratch_buf_size();
+		let mut output = Vec::with_capacity(size);
+		while output.len() < size {
+			let mut chunk = Vec::with_capacity(chunk_size);
+			let copied = ffi::scratch_buf_read_at(chunk.as_mut_ptr(), output.len(), chunk_size);
+			chunk.set_len(copied);
+			output.extend_from_slice(&chunk);
+		}
+		output
+	}
+}
+
+pub fn scratch_buf_write(blob: &[u8]) {
 	unsafe {
-		ffi::send(recepient, blob.as_ptr(), blob.len());
+		ffi::scratch_buf_write(blob.as_ptr(), blob.len());
 	}
 }
 
+pub fn send(recepient: ParaId, blob: &[u8]) -> Result<(), StatusCode> {
+	unsafe { to_result(ffi::send(recepient, blob.as_ptr(), blob.len())) }
+}
+
+/// Like `send`, but re-attempts the send up to `max_attempts` times in total while it fails with
+/// a transient status code.
+///
+/// Note that the host is synchronous, so within an invocation a retry only helps if the capacity
+/// frees up in the meantime, e.g. due to the host's own accounting. Returns the status of the
+/// last attempt.
+pub fn send_with_retry(
+	recepient: ParaId,
+	blob: &[u8],
+	max_attempts: u32,
+) -> Result<(), StatusCode> {
+	let mut result = send(recepient, blob);
+	for _ in 1..max_attempts {
+		match result {
+			Err(code) if code.is_transient() => result = send(recepient, blob),
+			_ => break,
+		}
+	}
+	result
+}
+
+/// Returns the details of the failure of the preceding `send`.
+///
+/// Only meaningful if called right after `send` failed, since a successful `send` leaves the
+/// scratch buffer intact and other functions overwrite it.
+pub fn last_send_error() -> Option<SendError> {
+	SendError::decode(&mut &scratch_buf_read()[..]).ok()
+}
+
 pub fn poll() -> Vec<(ParaId, Vec<u8>)> {
 	unsafe {
 		ffi::poll();
 
 		let raw_poll_msg = scratch_buf_read();
-		<Vec<(ParaId, Vec<u8>)>>::decode(&mut &raw_poll_msg[..])
+		primitives::decode_inbound::<WireParaId, _>(&mut &raw_poll_msg[..])
 			.expect("poll is guaranteed to return this type")
 	}
 }
+
+pub fn poll_peek() -> Vec<(ParaId, Vec<u8>)> {
+	unsafe {
+		ffi::poll_peek();
+
+		let raw_poll_msg = scratch_buf_read();
+		primitives::decode_inbound::<WireParaId, _>(&mut &raw_poll_msg[..])
+			.expect("poll_peek is guaranteed to return this type")
+	}
+}
+
+pub fn gas_left() -> u64 {
+	unsafe { ffi::gas_left() }
+}
+
+pub fn caller_para_id() -> ParaId {
+	unsafe { ffi::caller_para_id() }
+}
+
+pub fn last_time_slice() -> Option<u32> {
+	match unsafe { ffi::last_time_slice() } {
+		-1 => None,
+		time_slice => Some(time_slice as u32),
+	}
+}
+
+pub fn host_capabilities() -> u64 {
+	unsafe { ffi::host_capabilities() }
+}
+
+pub fn random_bytes(len: usize) -> Vec<u8> {
+	unsafe {
+		let mut output = Vec::with_capacity(len);
+		ffi::random(output.as_mut_ptr(), len);
+		output.set_len(len);
+		output
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use codec::Encode;
+	use primitives::capabilities;
+
+	#[test]
+	fn chunked_read_reassembles_the_scratch_buffer() {
+		let contents = (0..1000).map(|n| n as u8).collect::<Vec<_>>();
+		mock::set_scratch(contents.clone());
+		assert_eq!(scratch_buf_read_chunked(600), contents);
+		assert_eq!(scratch_buf_read_chunked(1000), contents);
+		assert_eq!(scratch_buf_read_chunked(1), contents);
+	}
+
+	#[test]
+	#[should_panic(expected = "module aborted with code 42: out of stock")]
+	fn abort_passes_the_code_and_the_message() {
+		abort(42, "out of stock");
+	}
+
+	#[test]
+	fn try_storage_read_or_decodes_a_present_value() {
+		mock::storage_set(b"key", 42u32.encode());
+		assert_eq!(try_storage_read_or(b"key", 0u32), Ok(42));
+		assert_eq!(storage_read_or(b"key", 0u32), 42);
+	}
+
+	#[test]
+	fn try_storage_read_or_defaults_an_absent_value() {
+		assert_eq!(try_storage_read_or(b"key", 7u32), Ok(7));
+		assert_eq!(storage_read_or(b"key", 7u32), 7);
+	}
+
+	#[test]
+	fn try_storage_read_or_reports_a_corrupted_value() {
+		// Too short for a `u32`.
+		mock::storage_set(b"key", vec![1, 2]);
+		assert!(try_storage_read_or(b"key", 7u32).is_err());
+		assert_eq!(storage_read_or(b"key", 7u32), 7);
+	}
+
+	#[test]
+	fn storage_clear_empties_the_storage() {
+		mock::set_capabilities(capabilities::ALL);
+		storage_write(b"foo", b"bar");
+		storage_clear();
+		assert_eq!(storage_read(b"foo"), None);
+	}
+
+	#[test]
+	#[should_panic(expected = "not allowed to call `storage_clear`")]
+	fn storage_clear_requires_the_capability() {
+		storage_clear();
+	}
+
+	#[test]
+	fn host_capabilities_are_reported() {
+		mock::set_capabilities(capabilities::ICMP);
+		assert_eq!(host_capabilities(), capabilities::ICMP);
+	}
+
+	#[test]
+	fn random_bytes_are_deterministic() {
+		let first = random_bytes(16);
+		assert_eq!(first.len(), 16);
+		assert_ne!(random_bytes(16), first);
+		assert!(random_bytes(0).is_empty());
+		// A fresh host starts from the same state.
+		assert_eq!(
+			std::thread::spawn(|| random_bytes(16)).join().unwrap(),
+			first
+		);
+	}
+
+	#[test]
+	fn send_with_retry_retries_only_transient_failures() {
+		// Every attempt costs a unit of gas in the mock, which allows to count them.
+		let attempts = |recepient, max_attempts| {
+			mock::set_gas_left(Some(100));
+			let result = send_with_retry(recepient, b"foo", max_attempts);
+			// Not counting the call of `gas_left` itself.
+			(result, 100 - gas_left() - 1)
+		};
+		mock::fail_sends_to(1, StatusCode::Busy);
+		assert_eq!(attempts(1, 3), (Err(StatusCode::Busy), 3));
+		mock::fail_sends_to(2, StatusCode::ChannelClosed);
+		assert_eq!(attempts(2, 3), (Err(StatusCode::ChannelClosed), 1));
+		assert_eq!(attempts(3, 3), (Ok(()), 1));
+	}
+
+	#[test]
+	fn poll_peek_leaves_the_inbound_messages_in_place() {
+		mock::set_inbound(vec![(1, b"foo".to_vec())]);
+		assert_eq!(poll_peek(), vec![(1, b"foo".to_vec())]);
+		assert_eq!(poll_peek(), vec![(1, b"foo".to_vec())]);
+		// Unlike peeking, polling consumes the messages.
+		assert_eq!(poll(), vec![(1, b"foo".to_vec())]);
+		assert!(poll().is_empty());
+		assert!(poll_peek().is_empty());
+	}
+
+	#[test]
+	fn memory_grows_up_to_the_limit() {
+		assert_eq!(memory_size_pages(), 1);
+		assert_eq!(memory_grow(2), Some(1));
+		assert_eq!(memory_size_pages(), 3);
+		assert_eq!(memory_grow(mock::MAX_PAGES), None);
+		assert_eq!(memory_size_pages(), 3);
+	}
+
+	#[test]
+	fn ephemeral_storage_lasts_for_the_invocation() {
+		assert_eq!(ephemeral_read(b"foo"), None);
+		ephemeral_write(b"foo", b"bar");
+		assert_eq!(ephemeral_read(b"foo"), Some(b"bar".to_vec()));
+		// Not visible in the persistent storage.
+		assert_eq!(storage_read(b"foo"), None);
+
+		mock::clear_ephemeral();
+		assert_eq!(ephemeral_read(b"foo"), None);
+	}
+
+	#[test]
+	fn caller_and_last_time_slice_are_reported() {
+		assert_eq!(last_time_slice(), None);
+		mock::set_caller_para_id(7);
+		mock::set_last_time_slice(5);
+		assert_eq!(caller_para_id(), 7);
+		assert_eq!(last_time_slice(), Some(5));
+	}
+
+	#[test]
+	fn last_send_error_describes_the_failed_send() {
+		mock::fail_sends_to(3, StatusCode::ChannelClosed);
+		assert_eq!(send(3, b"foo"), Err(StatusCode::ChannelClosed));
+		let send_error = last_send_error().expect("the send failed");
+		assert_eq!(send_error.code, StatusCode::ChannelClosed.as_i32());
+		assert_eq!(send_error.recepient, 3);
+
+		mock::set_scratch(b"untouched".to_vec());
+		assert_eq!(send(1, b"foo"), Ok(()));
+		assert_eq!(mock::scratch(), b"untouched");
+	}
+}
@@ -0,0 +1,351 @@
+//! An in-process implementation of the SPREE host API for native tests.
+//!
+//! Mirrors the signatures of the host functions declared in `ffi`, so the bindings can be
+//! exercised without a wasm host. The state of the host is kept per thread, and since every test
+//! runs on a thread of its own, tests don't observe each other's state.
+//!
+//! Unlike the real host, the mock doesn't roll back the storage or restore the polled messages
+//! when the module traps, and the gas, if limited, is charged one unit per host call without ever
+//! running out.
+
+use codec::Encode;
+use primitives::{capabilities, ParaId, SendError, StatusCode, WireParaId, MAX_MESSAGE_LEN};
+use std::{
+	cell::RefCell,
+	collections::{BTreeMap, HashMap},
+	mem, slice,
+};
+
+/// The maximum number of wasm pages the mock memory can grow to.
+pub const MAX_PAGES: u32 = 16;
+
+struct Host {
+	scratch: Vec<u8>,
+	storage: HashMap<Vec<u8>, Vec<u8>>,
+	/// The number of `storage_write` calls, by the key.
+	storage_writes: HashMap<Vec<u8>, usize>,
+	ephemeral: HashMap<Vec<u8>, Vec<u8>>,
+	inbound: Vec<(ParaId, Vec<u8>)>,
+	outbound: BTreeMap<ParaId, Vec<u8>>,
+	/// Status codes `send` fails with, by the recepient.
+	send_failures: HashMap<ParaId, StatusCode>,
+	gas_left: Option<u64>,
+	caller_para_id: ParaId,
+	last_time_slice: Option<u32>,
+	capabilities: u64,
+	pages: u32,
+	random_state: u8,
+}
+
+impl Default for Host {
+	fn default() -> Self {
+		Host {
+			scratch: Vec::new(),
+			storage: HashMap::new(),
+			storage_writes: HashMap::new(),
+			ephemeral: HashMap::new(),
+			inbound: Vec::new(),
+			outbound: BTreeMap::new(),
+			send_failures: HashMap::new(),
+			gas_left: None,
+			caller_para_id: 0,
+			last_time_slice: None,
+			capabilities: capabilities::ALL & !capabilities::STORAGE_CLEAR,
+			pages: 1,
+			random_state: 0,
+		}
+	}
+}
+
+thread_local! {
+	static HOST: RefCell<Host> = RefCell::new(Host::default());
+}
+
+/// Access the state of the host, without charging gas.
+fn with<R>(f: impl FnOnce(&mut Host) -> R) -> R {
+	HOST.with(|host| f(&mut host.borrow_mut()))
+}
+
+/// Serve a host call, charging gas for it.
+fn call<R>(f: impl FnOnce(&mut Host) -> R) -> R {
+	with(|host| {
+		if let Some(ref mut gas_left) = host.gas_left {
+			*gas_left = gas_left.saturating_sub(1);
+		}
+		f(host)
+	})
+}
+
+unsafe fn bytes<'a>(ptr: *const u8, len: usize) -> &'a [u8] {
+	if len == 0 {
+		return &[];
+	}
+	slice::from_raw_parts(ptr, len)
+}
+
+unsafe fn write(out_ptr: *const u8, data: &[u8]) {
+	if !data.is_empty() {
+		slice::from_raw_parts_mut(out_ptr as *mut u8, data.len()).copy_from_slice(data);
+	}
+}
+
+pub unsafe fn scratch_buf_size() -> usize {
+	call(|host| host.scratch.len())
+}
+
+pub unsafe fn scratch_buf_read(out_ptr: *const u8) {
+	call(|host| write(out_ptr, &host.scratch))
+}
+
+pub unsafe fn scratch_buf_read_at(out_ptr: *const u8, offset: usize, len: usize) -> usize {
+	call(|host| {
+		let start = offset.min(host.scratch.len());
+		let end = start.saturating_add(len).min(host.scratch.len());
+		write(out_ptr, &host.scratch[start..end]);
+		end - start
+	})
+}
+
+pub unsafe fn scratch_buf_write(blob_ptr: *const u8, blob_len: usize) {
+	let blob = bytes(blob_ptr, blob_len).to_vec();
+	call(|host| host.scratch = blob)
+}
+
+pub unsafe fn send(para_id: ParaId, blob_ptr: *const u8, blob_len: usize) -> i32 {
+	let blob = bytes(blob_ptr, blob_len).to_vec();
+	call(|host| {
+		let status = if let Some(code) = host.send_failures.get(&para_id) {
+			*code
+		} else if blob.is_empty() {
+			StatusCode::EmptyBlob
+		} else if blob.len() > MAX_MESSAGE_LEN as usize {
+			StatusCode::TooLarge
+		} else {
+			StatusCode::Ok
+		};
+		let status = match status {
+			// Like the real host, overwrite the message sent earlier and report it.
+			StatusCode::Ok => match host.outbound.insert(para_id, blob) {
+				Some(_previous) => StatusCode::AlreadySent,
+				None => StatusCode::Ok,
+			},
+			code => code,
+		};
+		if status != StatusCode::Ok {
+			let send_error = SendError {
+				code: status.as_i32(),
+				recepient: para_id,
+				reason: format!("{:?}", status),
+			};
+			host.scratch = send_error.encode();
+		}
+		status.as_i32()
+	})
+}
+
+pub unsafe fn poll() {
+	call(|host| {
+		let inbound = mem::take(&mut host.inbound);
+		host.scratch.clear();
+		primitives::encode_inbound::<WireParaId, _>(&inbound, &mut host.scratch);
+	})
+}
+
+pub unsafe fn poll_peek() {
+	call(|host| {
+		host.scratch.clear();
+		primitives::encode_inbound::<WireParaId, _>(&host.inbound, &mut host.scratch);
+	})
+}
+
+pub unsafe fn storage_read(key_ptr: *const u8, key_len: usize) -> i32 {
+	let key = bytes(key_ptr, key_len);
+	call(|host| match host.storage.get(key) {
+		Some(val) => {
+			host.scratch = val.clone();
+			StatusCode::Ok.as_i32()
+		}
+		None => StatusCode::NotFound.as_i32(),
+	})
+}
+
+pub unsafe fn storage_write(
+	key_ptr: *const u8,
+	key_len: usize,
+	val_ptr: *const u8,
+	val_len: usize,
+) {
+	let key = bytes(key_ptr, key_len).to_vec();
+	let val = bytes(val_ptr, val_len).to_vec();
+	call(|host| {
+		*host.storage_writes.entry(key.clone()).or_default() += 1;
+		host.storage.insert(key, val);
+	})
+}
+
+pub unsafe fn memory_size_pages() -> u32 {
+	call(|host| host.pages)
+}
+
+pub unsafe fn memory_grow(pages: u32) -> i32 {
+	call(|host| match host.pages.checked_add(pages) {
+		Some(new_pages) if new_pages <= MAX_PAGES => {
+			let prev_pages = host.pages;
+			host.pages = new_pages;
+			prev_pages as i32
+		}
+		_ => -1,
+	})
+}
+
+pub unsafe fn ephemeral_read(key_ptr: *const u8, key_len: usize) -> i32 {
+	let key = bytes(key_ptr, key_len);
+	call(|host| match host.ephemeral.get(key) {
+		Some(val) => {
+			host.scratch = val.clone();
+			StatusCode::Ok.as_i32()
+		}
+		None => StatusCode::NotFound.as_i32(),
+	})
+}
+
+pub unsafe fn ephemeral_write(
+	key_ptr: *const u8,
+	key_len: usize,
+	val_ptr: *const u8,
+	val_len: usize,
+) {
+	let key = bytes(key_ptr, key_len).to_vec();
+	let val = bytes(val_ptr, val_len).to_vec();
+	call(|host| host.ephemeral.insert(key, val));
+}
+
+pub unsafe fn storage_increment(key_ptr: *const u8, key_len: usize, delta: u64) -> u64 {
+	let key = bytes(key_ptr, key_len).to_vec();
+	call(|host| {
+		let current = host.storage.get(&key).map_or(0, |raw| {
+			codec::Decode::decode(&mut &raw[..]).expect("the counter is a u64")
+		});
+		let next: u64 = current + delta;
+		host.storage.insert(key, next.encode());
+		next
+	})
+}
+
+pub unsafe fn storage_clear() {
+	call(|host| {
+		assert!(
+			host.capabilities & capabilities::STORAGE_CLEAR != 0,
+			"spree module is not allowed to call `storage_clear`"
+		);
+		host.storage.clear();
+	})
+}
+
+pub unsafe fn host_capabilities() -> u64 {
+	call(|host| host.capabilities)
+}
+
+pub unsafe fn abort(code: i32, msg_ptr: *const u8, msg_len: usize) {
+	let msg = String::from_utf8_lossy(bytes(msg_ptr, msg_len)).into_owned();
+	panic!("module aborted with code {}: {}", code, msg);
+}
+
+pub unsafe fn random(out_ptr: *const u8, len: usize) {
+	call(|host| {
+		let random = (0..len)
+			.map(|_| {
+				host.random_state = host.random_state.wrapping_mul(5).wrapping_add(17);
+				host.random_state
+			})
+			.collect::<Vec<_>>();
+		write(out_ptr, &random);
+	})
+}
+
+pub unsafe fn gas_left() -> u64 {
+	call(|host| host.gas_left.unwrap_or(u64::MAX))
+}
+
+pub unsafe fn caller_para_id() -> ParaId {
+	call(|host| host.caller_para_id)
+}
+
+pub unsafe fn last_time_slice() -> i64 {
+	call(|host| host.last_time_slice.map_or(-1, i64::from))
+}
+
+/// Replace the contents of the scratch buffer, e.g. with the request for `handle`.
+pub fn set_scratch(scratch: Vec<u8>) {
+	with(|host| host.scratch = scratch)
+}
+
+/// Returns the contents of the scratch buffer, e.g. the response left by `handle`.
+pub fn scratch() -> Vec<u8> {
+	with(|host| host.scratch.clone())
+}
+
+pub fn storage_get(key: &[u8]) -> Option<Vec<u8>> {
+	with(|host| host.storage.get(key).cloned())
+}
+
+/// Returns the keys present in the storage, sorted.
+pub fn storage_keys() -> Vec<Vec<u8>> {
+	with(|host| {
+		let mut keys = host.storage.keys().cloned().collect::<Vec<_>>();
+		keys.sort();
+		keys
+	})
+}
+
+/// Returns the number of times the module wrote the given storage key.
+pub fn storage_writes(key: &[u8]) -> usize {
+	with(|host| host.storage_writes.get(key).copied().unwrap_or(0))
+}
+
+pub fn storage_set(key: &[u8], val: Vec<u8>) {
+	with(|host| host.storage.insert(key.to_vec(), val));
+}
+
+/// Set the inbound bundles as `(sender, blob)`, in the order `poll` reports them.
+pub fn set_inbound(inbound: Vec<(ParaId, Vec<u8>)>) {
+	with(|host| host.inbound = inbound)
+}
+
+/// Returns the outbound bundles by the recepient.
+pub fn outbound() -> BTreeMap<ParaId, Vec<u8>> {
+	with(|host| host.outbound.clone())
+}
+
+/// Drop the ephemeral storage, as the end of an invocation would.
+pub fn clear_ephemeral() {
+	with(|host| host.ephemeral.clear())
+}
+
+/// Remove the outbound bundles, as the end of a block would.
+pub fn clear_outbound() {
+	with(|host| host.outbound.clear())
+}
+
+/// Make `send` to the given recepient fail with the given status code.
+pub fn fail_sends_to(recepient: ParaId, code: StatusCode) {
+	with(|host| host.send_failures.insert(recepient, code));
+}
+
+/// Limit the gas, or make it unlimited with `None`.
+pub fn set_gas_left(gas_left: Option<u64>) {
+	with(|host| host.gas_left = gas_left)
+}
+
+pub fn set_caller_para_id(caller_para_id: ParaId) {
+	with(|host| host.caller_para_id = caller_para_id)
+}
+
+/// Record the time slice of a successful invocation, as the host does once `handle` returns.
+pub fn set_last_time_slice(time_slice: u32) {
+	with(|host| host.last_time_slice = Some(time_slice))
+}
+
+pub fn set_capabilities(capabilities: u64) {
+	with(|host| host.capabilities = capabilities)
+}
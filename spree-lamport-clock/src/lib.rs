@@ -92,13 +92,129 @@
 // NB: This all assumes that we propagate panics into the host runtime. If we want to gracefully
 // receive panics in the parachain then the design might look totally different.
 
-use codec::{Decode, Encode};
+use codec::Encode;
 use std::collections::HashMap;
 
-mod ext;
+pub mod ext;
 mod storage;
 
-use primitives::{Req, Resp, TargetedMsg, TimestampedMsg};
+use primitives::{
+	LenientReq, ParaId, Req, Resp, RespEnvelope, StatusCode, TargetedMsg, Timestamp, TimestampedMsg,
+};
+
+/// The minimal amount of gas required to start a fan out.
+///
+/// If there is less gas left then the fan out is not performed and messages stay in the queue
+/// until the next time.
+const FAN_OUT_GAS_THRESHOLD: u64 = 100;
+
+/// The code the module aborts with if the request can't be decoded.
+const ABORT_BAD_REQUEST: i32 = 1;
+
+/// Group the given messages by their recepients.
+fn group_by_recepient(msgs: Vec<TargetedMsg>) -> HashMap<ParaId, Vec<TimestampedMsg>> {
+	msgs.into_iter().map(|msg| (msg.recepient, msg.msg)).fold(
+		HashMap::new(),
+		|mut acc, (recepient, msg)| {
+			acc.entry(recepient).or_insert_with(Vec::new).push(msg);
+			acc
+		},
+	)
+}
+
+/// Order the messages to a single recepient by descending priority, then by ascending timestamp.
+#[cfg(feature = "priority")]
+fn order_by_priority(msgs: &mut [TimestampedMsg]) {
+	msgs.sort_by_key(|msg| (core::cmp::Reverse(msg.priority), msg.at));
+}
+
+/// Messages carry no priority without the `priority` feature, so the order is left as is.
+#[cfg(not(feature = "priority"))]
+fn order_by_priority(_msgs: &mut [TimestampedMsg]) {}
+
+/// Drop the inbound messages that were acknowledged with `Req::Ack`.
+///
+/// Senders left without messages are dropped as well.
+fn skip_acked(resp: &mut Resp) {
+	let acks = storage::acks();
+	for (sender, msgs) in resp.inbound.iter_mut() {
+		if let Ok(pos) = acks.binary_search_by_key(sender, |(acked_sender, _)| *acked_sender) {
+			let watermark = acks[pos].1;
+			msgs.retain(|msg| msg.at > watermark);
+		}
+	}
+	resp.inbound.retain(|(_, msgs)| !msgs.is_empty());
+}
+
+/// Empty the queue returning the messages that haven't expired yet.
+fn take_unexpired_queue() -> Vec<TargetedMsg> {
+	let now = storage::current_timestamp();
+	storage::take_queue()
+		.into_iter()
+		.filter(|queued| !queued.msg.is_expired(now))
+		.collect()
+}
+
+/// Send all enqueued messages.
+///
+/// The recepients are served in the ascending order. If the gas runs low midway, the messages to
+/// the remaining recepients are left in the queue and the last served recepient is remembered, so
+/// that the next fan out resumes from where this one stopped.
+///
+/// Returns `false` if the fan out was deferred, fully or partially.
+fn fan_out() -> bool {
+	if ext::gas_left() < FAN_OUT_GAS_THRESHOLD {
+		// Not enough gas to send the messages. Leave them in the queue.
+		return false;
+	}
+
+	// Group all messages by the recepient.
+	let mut msg_by_recepient = group_by_recepient(take_unexpired_queue())
+		.into_iter()
+		.collect::<Vec<_>>();
+	msg_by_recepient.sort_by_key(|(recepient, _)| *recepient);
+	for (_, msgs) in msg_by_recepient.iter_mut() {
+		order_by_priority(msgs);
+	}
+	if let Some(cursor) = storage::fan_out_cursor() {
+		let resume_at = msg_by_recepient
+			.iter()
+			.position(|(recepient, _)| *recepient > cursor)
+			.unwrap_or(0);
+		msg_by_recepient.rotate_left(resume_at);
+	}
+
+	let mut pending = msg_by_recepient.into_iter();
+	while let Some((recepient, msgs)) = pending.next() {
+		let _ = ext::send(recepient, &msgs.encode());
+		if !pending.as_slice().is_empty() && ext::gas_left() < FAN_OUT_GAS_THRESHOLD {
+			storage::set_fan_out_cursor(Some(recepient));
+			let rest = pending
+				.flat_map(|(recepient, msgs)| {
+					msgs.into_iter()
+						.map(move |msg| TargetedMsg { recepient, msg })
+				})
+				.collect();
+			storage::enqueue_msgs(rest);
+			return false;
+		}
+	}
+	storage::set_fan_out_cursor(None);
+	true
+}
+
+fn enqueue(recepient: ParaId, payload: Vec<u8>, ttl: Option<Timestamp>) {
+	storage::enqueue_msg(storage::make_targeted(recepient, payload, ttl));
+}
+
+/// A direct entrypoint for enqueuing a message.
+///
+/// This is the same as `Req::Enqueue` without TTL, but it bypasses the `handle` entrypoint. The
+/// payload is passed in the scratch buffer as is. See ADC in the design note above.
+#[no_mangle]
+pub extern "C" fn enqueue_direct(recepient: ParaId) {
+	enqueue(recepient, ext::scratch_buf_read(), None);
+}
 
 /// A function that handles requests coming from the SPREE runtime environment, or ultimately from
 /// the parachain.
@@ -106,56 +222,590 @@ use primitives::{Req, Resp, TargetedMsg, TimestampedMsg};
 pub extern "C" fn handle(_time_slice: usize) {
 	// Execution starts with the scratch buffer filled with the input data payload passed from the
 	// parachain validation function.
-	let req = Req::decode(&mut &ext::scratch_buf_read()[..]).unwrap();
+	let raw_req = ext::scratch_buf_read();
+	let req = match primitives::decode_req_lenient(&raw_req, primitives::MAX_DECODE_DEPTH) {
+		Ok(LenientReq::Known(req)) => req,
+		// Requests introduced by newer callers are ignored unless strict decoding is requested.
+		Ok(LenientReq::Unknown { .. }) if cfg!(not(feature = "strict-requests")) => return,
+		Ok(LenientReq::Unknown { .. }) => ext::abort(ABORT_BAD_REQUEST, "unknown request"),
+		Err(_) => ext::abort(ABORT_BAD_REQUEST, "failed to decode the request"),
+	};
 	match req {
-		Req::Enqueue { recepient, payload } => {
-			let timestamp = storage::next_timestamp();
-			storage::enqueue_msg(TargetedMsg {
-				recepient,
-				msg: TimestampedMsg {
-					at: timestamp,
-					payload,
-				},
-			});
+		Req::Enqueue {
+			recepient,
+			payload,
+			#[cfg(feature = "ttl")]
+			ttl,
+		} => {
+			#[cfg(not(feature = "ttl"))]
+			let ttl = None;
+			enqueue(recepient, payload, ttl);
+		}
+		Req::EnqueueBatch { messages } => {
+			let msgs = messages
+				.into_iter()
+				.map(|(recepient, payload)| storage::make_targeted(recepient, payload, None))
+				.collect();
+			storage::enqueue_msgs(msgs);
 		}
 		Req::Poll => {
 			// Poll the incoming messages from our doppelgangers on the other sides.
 			//
 			// Each doppelganger sends one ICMP message containing a bundle of incoming timestamped
 			// messages.
-			let poll_result = ext::poll()
-				.into_iter()
-				.map(|(sender, raw_inbound_msgs)| {
-					(
-						sender,
-						<Vec<TimestampedMsg>>::decode(&mut &raw_inbound_msgs[..]).expect(
-							"doppelganger uses the same code;\
-							 therefore encoding/decoding should be symmetrical;\
-							 it shouldn't fail;\
-							 qed",
-						),
-					)
-				})
-				.collect();
-			let _ = Resp {
-				inbound: poll_result,
-			};
-			// TODO: Return the result.
 			//
-			// Should be trivial to return the data via some means, e.g. scratch buffer?
+			// Doppelgangers use the same code, so the bundles should always decode. Still, report
+			// `StatusCode::Malformed` in case they don't.
+			//
+			// The order in which the host reports the senders is unspecified, so sort them to
+			// present the inbound messages in a canonical order.
+			//
+			// Messages acknowledged with `Req::Ack` are not delivered again.
+			let mut poll_result = ext::poll();
+			poll_result.sort_by_key(|(sender, _)| *sender);
+			let envelope = match Resp::from_poll(poll_result) {
+				Ok(mut resp) => {
+					skip_acked(&mut resp);
+					RespEnvelope::ok(resp)
+				}
+				Err(_) => RespEnvelope::err(StatusCode::Malformed),
+			};
+			ext::scratch_buf_write(&envelope.encode());
 		}
 		Req::FanOut => {
-			// Group all messages by the recepient.
-			let msg_by_recepient = storage::take_queue()
+			fan_out();
+		}
+		Req::FanOutIfReady { min_messages } => {
+			let fanned_out = storage::message_queue::len() >= min_messages && fan_out();
+			ext::scratch_buf_write(&fanned_out.encode());
+		}
+		Req::Prepare => {
+			// Merge the queued messages into the bundles that were prepared previously but not
+			// yet committed.
+			let mut prepared = storage::take_prepared()
 				.into_iter()
-				.map(|msg| (msg.recepient, msg.msg))
-				.fold(HashMap::new(), |mut acc, (recepient, msg)| {
-					acc.entry(recepient).or_insert_with(Vec::new).push(msg);
-					acc
-				});
-			for (recepient, msgs) in msg_by_recepient {
-				ext::send(recepient, &msgs.encode());
+				.collect::<HashMap<_, _>>();
+			for (recepient, msgs) in group_by_recepient(take_unexpired_queue()) {
+				prepared
+					.entry(recepient)
+					.or_insert_with(Vec::new)
+					.extend(msgs);
 			}
+
+			// Sort the bundles so that the stored representation doesn't depend on the hash map
+			// iteration order.
+			let mut prepared = prepared.into_iter().collect::<Vec<_>>();
+			prepared.sort_by_key(|(recepient, _)| *recepient);
+			storage::set_prepared(prepared);
+		}
+		Req::Commit => {
+			// Keep the bundles that failed to send, so that the next commit retries them.
+			let failed = storage::take_prepared()
+				.into_iter()
+				.filter(|(recepient, msgs)| ext::send(*recepient, &msgs.encode()).is_err())
+				.collect();
+			storage::set_prepared(failed);
+		}
+		Req::Retract { recepient, payload } => {
+			let removed = storage::remove_first(|queued| {
+				queued.recepient == recepient && queued.msg.payload == payload
+			})
+			.is_some();
+			ext::scratch_buf_write(&removed.encode());
+		}
+		Req::QueueLen => {
+			ext::scratch_buf_write(&storage::message_queue::len().encode());
+		}
+		Req::QueueBytes => {
+			ext::scratch_buf_write(&storage::message_queue::byte_size().encode());
+		}
+		Req::LastTimeSlice => {
+			ext::scratch_buf_write(&ext::last_time_slice().encode());
+		}
+		Req::Ping { nonce } => {
+			// Deliberately doesn't touch the storage.
+			ext::scratch_buf_write(&nonce.encode());
+		}
+		Req::ExportState => {
+			ext::scratch_buf_write(&storage::export_state().encode());
+		}
+		Req::ImportState { snapshot } => {
+			storage::import_state(snapshot);
 		}
+		Req::Ack {
+			sender,
+			up_to_timestamp,
+		} => {
+			storage::ack(sender, up_to_timestamp);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::ext::mock;
+	use codec::Decode;
+	use primitives::{Envelope, RespEnvelope, StateSnapshot};
+
+	/// Handle the given request, returning the contents of the scratch buffer left.
+	fn call(req: Req) -> Vec<u8> {
+		mock::set_scratch(req.encode());
+		handle(0);
+		mock::scratch()
+	}
+
+	fn enqueue_to(recepient: ParaId, payload: &[u8]) {
+		call(Req::Enqueue {
+			recepient,
+			payload: payload.to_vec(),
+			#[cfg(feature = "ttl")]
+			ttl: None,
+		});
+	}
+
+	fn queue_len() -> u32 {
+		u32::decode(&mut &call(Req::QueueLen)[..]).unwrap()
+	}
+
+	#[test]
+	fn enqueue_touches_only_the_clock_and_the_queue() {
+		enqueue_to(1, b"foo");
+		let keys: &[&[u8]] = &[
+			b":current_timestamp",
+			b":nonce",
+			b":queue_bytes",
+			b":queue_len",
+			b":stack",
+		];
+		assert_eq!(mock::storage_keys(), keys);
+	}
+
+	#[test]
+	fn ping_echoes_the_nonce_without_touching_the_storage() {
+		let pong = call(Req::Ping { nonce: 0xdead_beef });
+		assert_eq!(u64::decode(&mut &pong[..]).unwrap(), 0xdead_beef);
+		assert!(mock::storage_keys().is_empty());
+	}
+
+	#[test]
+	fn imported_state_behaves_like_the_exported_one() {
+		// Every thread has a host of its own, so this is a separate module.
+		let (snapshot, expected) = std::thread::spawn(|| {
+			enqueue_to(1, b"foo");
+			enqueue_to(2, b"bar");
+			enqueue_to(3, b"qux");
+			// Interrupt the fan out right after serving the first recepient.
+			mock::set_gas_left(Some(FAN_OUT_GAS_THRESHOLD + 1));
+			assert!(!fan_out());
+			mock::set_gas_left(None);
+			let snapshot = call(Req::ExportState);
+			enqueue_to(1, b"baz");
+			(snapshot, call(Req::ExportState))
+		})
+		.join()
+		.unwrap();
+
+		// The cursor of the importing module is stale and must not survive the import.
+		storage::set_fan_out_cursor(Some(2));
+		call(Req::ImportState {
+			snapshot: StateSnapshot::decode(&mut &snapshot[..]).unwrap(),
+		});
+		assert_eq!(storage::fan_out_cursor(), Some(1));
+		// The clock, the nonces, the queue and the interrupted fan out carry on as they would in
+		// the exporting module.
+		enqueue_to(1, b"baz");
+		assert_eq!(call(Req::ExportState), expected);
+		assert_eq!(queue_len(), 3);
+		assert!(fan_out());
+		assert_eq!(
+			mock::outbound().keys().collect::<Vec<_>>(),
+			vec![&1, &2, &3]
+		);
+	}
+
+	#[test]
+	fn queue_bytes_match_the_encoded_queue() {
+		let queue_bytes = || u32::decode(&mut &call(Req::QueueBytes)[..]).unwrap();
+		// An empty queue is encoded as a single byte, whether it was ever written or not.
+		assert_eq!(queue_bytes(), 1);
+		for size in &[0, 1, 100, 1000] {
+			enqueue_to(1, &vec![0; *size]);
+			let encoded = mock::storage_get(b":stack").unwrap();
+			assert_eq!(queue_bytes() as usize, encoded.len());
+		}
+
+		fan_out();
+		assert_eq!(queue_bytes(), 1);
+	}
+
+	#[test]
+	fn interrupted_fan_out_resumes_with_the_remaining_recepients() {
+		for recepient in &[1, 2, 3] {
+			enqueue_to(*recepient, b"foo");
+		}
+
+		// Run out of gas right after serving the first recepient.
+		mock::set_gas_left(Some(FAN_OUT_GAS_THRESHOLD + 1));
+		assert!(!fan_out());
+		mock::set_gas_left(None);
+		assert_eq!(mock::outbound().keys().collect::<Vec<_>>(), vec![&1]);
+		assert_eq!(storage::fan_out_cursor(), Some(1));
+
+		// The resumed fan out doesn't send to the first recepient again.
+		mock::clear_outbound();
+		assert!(fan_out());
+		assert_eq!(mock::outbound().keys().collect::<Vec<_>>(), vec![&2, &3]);
+		assert_eq!(storage::fan_out_cursor(), None);
+	}
+
+	#[test]
+	fn queue_len_tracks_enqueues_and_partial_fan_out() {
+		assert_eq!(queue_len(), 0);
+		for (n, recepient) in [1, 2, 2, 3].iter().enumerate() {
+			enqueue_to(*recepient, b"foo");
+			assert_eq!(queue_len(), n as u32 + 1);
+		}
+
+		// Leave just enough gas to start the fan out, so it stops after the first recepient.
+		mock::set_gas_left(Some(FAN_OUT_GAS_THRESHOLD + 1));
+		assert!(!fan_out());
+		mock::set_gas_left(None);
+		assert_eq!(mock::outbound().keys().collect::<Vec<_>>(), vec![&1]);
+		assert_eq!(queue_len(), 3);
+		assert_eq!(storage::message_queue::queue().len(), 3);
+
+		mock::clear_outbound();
+		assert!(fan_out());
+		assert_eq!(mock::outbound().keys().collect::<Vec<_>>(), vec![&2, &3]);
+		assert_eq!(queue_len(), 0);
+		assert!(storage::message_queue::queue().is_empty());
+	}
+
+	#[test]
+	fn retracted_message_is_not_fanned_out() {
+		enqueue_to(1, b"foo");
+		enqueue_to(1, b"bar");
+		let retract = |payload: &[u8]| {
+			bool::decode(
+				&mut &call(Req::Retract {
+					recepient: 1,
+					payload: payload.to_vec(),
+				})[..],
+			)
+			.unwrap()
+		};
+		assert!(retract(b"foo"));
+		assert!(!retract(b"baz"));
+		assert_eq!(queue_len(), 1);
+
+		call(Req::FanOut);
+		let bundle = <Vec<TimestampedMsg>>::decode(&mut &mock::outbound()[&1][..]).unwrap();
+		let payloads = bundle
+			.into_iter()
+			.map(|msg| msg.payload)
+			.collect::<Vec<_>>();
+		assert_eq!(payloads, vec![b"bar".to_vec()]);
+	}
+
+	#[test]
+	fn envelope_survives_enqueue_and_fan_out() {
+		let envelope = Envelope::new(b"body".to_vec())
+			.with_header("content-type", b"text/plain".to_vec())
+			.with_header("trace", b"1".to_vec())
+			.with_header("trace", b"2".to_vec());
+		enqueue_to(1, &envelope.to_payload());
+		call(Req::FanOut);
+
+		let bundle = <Vec<TimestampedMsg>>::decode(&mut &mock::outbound()[&1][..]).unwrap();
+		assert_eq!(bundle.len(), 1);
+		let received = Envelope::from_payload(&bundle[0].payload).unwrap();
+		assert_eq!(received, envelope);
+		assert_eq!(received.header("trace"), Some(&b"1"[..]));
+	}
+
+	#[cfg(feature = "priority")]
+	#[test]
+	fn fan_out_sends_higher_priority_messages_first() {
+		let msgs = [(b"low", 0), (b"top", 2), (b"mid", 1), (b"hi2", 2)]
+			.iter()
+			.map(|(payload, priority)| {
+				let mut targeted = storage::make_targeted(1, payload.to_vec(), None);
+				targeted.msg.priority = *priority;
+				targeted
+			})
+			.collect();
+		storage::enqueue_msgs(msgs);
+		call(Req::FanOut);
+
+		let bundle = <Vec<TimestampedMsg>>::decode(&mut &mock::outbound()[&1][..]).unwrap();
+		let payloads = bundle
+			.iter()
+			.map(|msg| &msg.payload[..])
+			.collect::<Vec<_>>();
+		// The messages of the same priority keep the order of their timestamps.
+		assert_eq!(payloads, vec![b"top", b"hi2", b"mid", b"low"]);
+	}
+
+	#[test]
+	fn retract_does_not_underflow_a_lagging_counter() {
+		// A queue without the counter, as left by a version that didn't maintain it.
+		enqueue_to(1, b"foo");
+		mock::storage_set(b":queue_len", 0u32.encode());
+		call(Req::Retract {
+			recepient: 1,
+			payload: b"foo".to_vec(),
+		});
+		assert_eq!(queue_len(), 0);
+		assert!(storage::message_queue::queue().is_empty());
+	}
+
+	fn prepared() -> Vec<(ParaId, Vec<TimestampedMsg>)> {
+		<Vec<(ParaId, Vec<TimestampedMsg>)>>::decode(
+			&mut &mock::storage_get(b":prepared").unwrap()[..],
+		)
+		.unwrap()
+	}
+
+	#[test]
+	fn prepare_stashes_bundles_until_commit() {
+		enqueue_to(2, b"foo");
+		enqueue_to(1, b"bar");
+		enqueue_to(2, b"baz");
+		call(Req::Prepare);
+		assert_eq!(queue_len(), 0);
+		let stashed = prepared()
+			.into_iter()
+			.map(|(recepient, msgs)| (recepient, msgs.len()))
+			.collect::<Vec<_>>();
+		assert_eq!(stashed, vec![(1, 1), (2, 2)]);
+		assert!(mock::outbound().is_empty());
+
+		call(Req::Commit);
+		assert!(prepared().is_empty());
+		assert_eq!(mock::outbound().keys().collect::<Vec<_>>(), vec![&1, &2]);
+	}
+
+	#[test]
+	fn commit_keeps_the_bundles_that_failed_to_send() {
+		enqueue_to(1, b"foo");
+		enqueue_to(2, b"bar");
+		call(Req::Prepare);
+		mock::fail_sends_to(2, StatusCode::ChannelClosed);
+		call(Req::Commit);
+		assert_eq!(mock::outbound().keys().collect::<Vec<_>>(), vec![&1]);
+		let kept = prepared();
+		assert_eq!(kept.len(), 1);
+		assert_eq!(kept[0].0, 2);
+		assert_eq!(kept[0].1[0].payload, b"bar");
+	}
+
+	#[test]
+	fn batch_is_enqueued_with_a_single_queue_write() {
+		let messages = (0..50)
+			.map(|n| (n % 5, vec![n as u8]))
+			.collect::<Vec<(ParaId, Vec<u8>)>>();
+		call(Req::EnqueueBatch { messages });
+		assert_eq!(mock::storage_writes(b":stack"), 1);
+		assert_eq!(queue_len(), 50);
+
+		call(Req::FanOut);
+		let outbound = mock::outbound();
+		assert_eq!(outbound.len(), 5);
+		let sent = outbound
+			.values()
+			.map(|bundle| {
+				<Vec<TimestampedMsg>>::decode(&mut &bundle[..])
+					.unwrap()
+					.len()
+			})
+			.sum::<usize>();
+		assert_eq!(sent, 50);
+		assert_eq!(queue_len(), 0);
+	}
+
+	#[cfg(feature = "ttl")]
+	#[test]
+	fn expired_messages_are_dropped_at_fan_out() {
+		call(Req::Enqueue {
+			recepient: 1,
+			payload: b"stale".to_vec(),
+			ttl: Some(1),
+		});
+		enqueue_to(2, b"foo");
+		enqueue_to(2, b"bar");
+		assert_eq!(storage::current_timestamp(), 3);
+
+		call(Req::FanOut);
+		assert_eq!(mock::outbound().keys().collect::<Vec<_>>(), vec![&2]);
+		assert_eq!(queue_len(), 0);
+	}
+
+	#[test]
+	fn nonces_increase_and_survive_fan_out() {
+		enqueue_to(1, b"foo");
+		enqueue_to(2, b"bar");
+		enqueue_to(1, b"baz");
+		let nonces = storage::message_queue::queue()
+			.into_iter()
+			.map(|queued| queued.msg.nonce)
+			.collect::<Vec<_>>();
+		assert_eq!(nonces, vec![1, 2, 3]);
+		assert_eq!(storage::nonce::current_nonce(), 3);
+
+		call(Req::FanOut);
+		let sent_nonces = |recepient| {
+			<Vec<TimestampedMsg>>::decode(&mut &mock::outbound()[&recepient][..])
+				.unwrap()
+				.into_iter()
+				.map(|msg| msg.nonce)
+				.collect::<Vec<_>>()
+		};
+		assert_eq!(sent_nonces(1), vec![1, 3]);
+		assert_eq!(sent_nonces(2), vec![2]);
+
+		// Nonces are never reused, even once the queue is empty.
+		enqueue_to(1, b"qux");
+		assert_eq!(storage::message_queue::queue()[0].msg.nonce, 4);
+	}
+
+	#[test]
+	fn fan_out_if_ready_waits_for_the_threshold() {
+		let fan_out_if_ready =
+			|| bool::decode(&mut &call(Req::FanOutIfReady { min_messages: 3 })[..]).unwrap();
+		enqueue_to(1, b"foo");
+		enqueue_to(2, b"bar");
+		assert!(!fan_out_if_ready());
+		assert!(mock::outbound().is_empty());
+		assert_eq!(queue_len(), 2);
+
+		enqueue_to(1, b"baz");
+		assert!(fan_out_if_ready());
+		assert_eq!(mock::outbound().keys().collect::<Vec<_>>(), vec![&1, &2]);
+		assert_eq!(queue_len(), 0);
+	}
+
+	#[test]
+	fn make_targeted_advances_the_clock() {
+		let first = storage::make_targeted(1, b"foo".to_vec(), None);
+		let second = storage::make_targeted(2, b"bar".to_vec(), None);
+		assert_eq!(storage::current_timestamp(), 2);
+		assert_eq!((first.recepient, first.msg.at, first.msg.nonce), (1, 1, 1));
+		assert_eq!(
+			(second.recepient, second.msg.at, second.msg.nonce),
+			(2, 2, 2)
+		);
+		assert_eq!(second.msg.payload, b"bar".to_vec());
+	}
+
+	#[test]
+	#[should_panic(expected = "the message queue is corrupted")]
+	fn corrupted_queue_is_not_treated_as_empty() {
+		enqueue_to(1, b"foo");
+		mock::storage_set(b":stack", vec![0xff]);
+		// Going through `handle` would abort the test, since it can't unwind.
+		storage::enqueue_msg(storage::make_targeted(1, b"bar".to_vec(), None));
+	}
+
+	#[test]
+	#[should_panic(expected = "the acks are corrupted")]
+	fn corrupted_acks_are_not_treated_as_absent() {
+		mock::storage_set(b":acks", vec![0xff]);
+		// Going through `handle` would abort the test, since it can't unwind.
+		storage::ack(1, 1);
+	}
+
+	#[test]
+	#[should_panic(expected = "the prepared bundles are corrupted")]
+	fn corrupted_prepared_bundles_are_not_treated_as_absent() {
+		mock::storage_set(b":prepared", vec![0xff]);
+		storage::take_prepared();
+	}
+
+	#[test]
+	#[should_panic(expected = "the fan out cursor is corrupted")]
+	fn corrupted_fan_out_cursor_is_not_treated_as_absent() {
+		mock::storage_set(b":fanout_cursor", vec![0xff]);
+		storage::fan_out_cursor();
+	}
+
+	/// Poll the inbound messages, expecting the request to succeed.
+	fn poll() -> Resp {
+		let envelope = RespEnvelope::decode(&mut &call(Req::Poll)[..]).unwrap();
+		envelope.body.expect("poll should succeed")
+	}
+
+	#[test]
+	fn poll_presents_the_senders_sorted() {
+		let bundle = |payload: &[u8]| vec![storage::make_targeted(0, payload.to_vec(), None).msg];
+		mock::set_inbound(vec![
+			(3, bundle(b"foo").encode()),
+			(1, bundle(b"bar").encode()),
+			(2, bundle(b"baz").encode()),
+		]);
+		let senders = poll()
+			.inbound
+			.iter()
+			.map(|(sender, _)| *sender)
+			.collect::<Vec<_>>();
+		assert_eq!(senders, vec![1, 2, 3]);
+	}
+
+	#[test]
+	fn acked_messages_are_not_delivered_again() {
+		let msgs = (0..3)
+			.map(|n| storage::make_targeted(0, vec![n], None).msg)
+			.collect::<Vec<_>>();
+		let timestamps = msgs.iter().map(|msg| msg.at).collect::<Vec<_>>();
+		let other = vec![storage::make_targeted(0, b"other".to_vec(), None).msg];
+		let inbound = vec![(1, msgs.encode()), (2, other.encode())];
+		let delivered = || {
+			mock::set_inbound(inbound.clone());
+			poll()
+				.inbound
+				.into_iter()
+				.map(|(sender, msgs)| (sender, msgs.iter().map(|msg| msg.at).collect()))
+				.collect::<Vec<(ParaId, Vec<Timestamp>)>>()
+		};
+
+		call(Req::Ack {
+			sender: 1,
+			up_to_timestamp: timestamps[1],
+		});
+		assert_eq!(
+			delivered(),
+			vec![(1, vec![timestamps[2]]), (2, vec![other[0].at])]
+		);
+
+		// A lower watermark doesn't bring the acked messages back.
+		call(Req::Ack {
+			sender: 1,
+			up_to_timestamp: timestamps[0],
+		});
+		// A sender with all of its messages acked is left out.
+		call(Req::Ack {
+			sender: 2,
+			up_to_timestamp: other[0].at,
+		});
+		assert_eq!(delivered(), vec![(1, vec![timestamps[2]])]);
+		assert_eq!(storage::acks(), vec![(1, timestamps[1]), (2, other[0].at)]);
+	}
+
+	#[test]
+	fn malformed_inbound_is_reported_in_the_envelope() {
+		mock::set_inbound(vec![(1, b"garbage".to_vec())]);
+		let envelope = RespEnvelope::decode(&mut &call(Req::Poll)[..]).unwrap();
+		assert_eq!(envelope.status(), Some(StatusCode::Malformed));
+		assert!(envelope.body.is_none());
+	}
+
+	#[cfg(not(feature = "strict-requests"))]
+	#[test]
+	fn unknown_request_is_ignored() {
+		enqueue_to(1, b"foo");
+		mock::set_scratch(vec![0xff, 1, 2, 3]);
+		handle(0);
+		assert_eq!(mock::scratch(), vec![0xff, 1, 2, 3]);
+		assert_eq!(queue_len(), 1);
 	}
 }
@@ -1,22 +1,77 @@
 //! Utilities for dealing with storage of this SPREE module.
 //!
-//! There are only two fields exist at the moment:
+//! There are only eight fields exist at the moment:
 //! - `timestamp: Timestamp`
+//! - `nonce: u64`
 //! - `message_queue: Vec<TargetedMsg>`
+//! - `message_queue_len: u32`
+//! - `message_queue_bytes: u32`
+//! - `prepared: Vec<(ParaId, Vec<TimestampedMsg>)>`
+//! - `fan_out_cursor: Option<ParaId>`
+//! - `acks: Vec<(ParaId, Timestamp)>`
+//!
+//! The time slice of the last successful invocation is kept by the host itself, outside of the
+//! storage, see `ext::last_time_slice`.
+
+pub use acks::{ack, acks};
+pub use fan_out_cursor::{fan_out_cursor, set_fan_out_cursor};
+pub use message_queue::{enqueue_msg, enqueue_msgs, remove_first, take_queue};
+pub use nonce::next_nonce;
+pub use prepared::{set_prepared, take_prepared};
+pub use timestamp::{current_timestamp, next_timestamp};
+
+use primitives::{ParaId, StateSnapshot, TargetedMsg, Timestamp, TimestampedMsg};
+
+/// Build a message to the given recepient stamped with the next timestamp and nonce.
+///
+/// Note that this advances the clock, so the message is expected to be enqueued.
+pub fn make_targeted(recepient: ParaId, payload: Vec<u8>, ttl: Option<Timestamp>) -> TargetedMsg {
+	#[cfg(not(feature = "ttl"))]
+	let _ = ttl;
+	TargetedMsg {
+		recepient,
+		msg: TimestampedMsg {
+			at: next_timestamp(),
+			payload,
+			#[cfg(feature = "ttl")]
+			ttl,
+			nonce: next_nonce(),
+			#[cfg(feature = "priority")]
+			priority: 0,
+		},
+	}
+}
+
+/// Returns the full state of the module.
+pub fn export_state() -> StateSnapshot {
+	StateSnapshot {
+		timestamp: timestamp::current_timestamp(),
+		nonce: nonce::current_nonce(),
+		queue: message_queue::queue(),
+		prepared: prepared::prepared(),
+		acks: acks::acks(),
+		fan_out_cursor: fan_out_cursor::fan_out_cursor(),
+	}
+}
 
-pub use message_queue::{enqueue_msg, take_queue};
-pub use timestamp::next_timestamp;
+/// Overwrite the full state of the module.
+pub fn import_state(snapshot: StateSnapshot) {
+	timestamp::set_current_timestamp(snapshot.timestamp);
+	nonce::set_nonce(snapshot.nonce);
+	message_queue::replace_queue(snapshot.queue);
+	prepared::set_prepared(snapshot.prepared);
+	acks::set_acks(snapshot.acks);
+	fan_out_cursor::set_fan_out_cursor(snapshot.fan_out_cursor);
+}
 
 mod timestamp {
 	use crate::ext;
-	use codec::{Decode, Encode};
+	use codec::Encode;
 	use primitives::Timestamp;
 	const KEY_CURRENT_TIMESTAMP: &[u8] = b":current_timestamp";
 
 	pub fn current_timestamp() -> Timestamp {
-		ext::storage_read(KEY_CURRENT_TIMESTAMP)
-			.and_then(|raw_timestamp| Timestamp::decode(&mut &raw_timestamp[..]).ok())
-			.unwrap_or(0)
+		ext::try_storage_read_or(KEY_CURRENT_TIMESTAMP, 0).expect("the clock is corrupted")
 	}
 
 	pub fn set_current_timestamp(timestamp: Timestamp) {
@@ -26,36 +81,118 @@ mod timestamp {
 	}
 
 	pub fn next_timestamp() -> Timestamp {
-		let next = current_timestamp() + 1;
-		set_current_timestamp(next);
-		next
+		ext::storage_increment(KEY_CURRENT_TIMESTAMP, 1)
+	}
+}
+
+pub mod nonce {
+	use crate::ext;
+	use codec::Encode;
+	const KEY_NONCE: &[u8] = b":nonce";
+
+	/// Returns the last assigned nonce, or 0 if none were assigned.
+	pub fn current_nonce() -> u64 {
+		ext::try_storage_read_or(KEY_NONCE, 0).expect("the nonce is corrupted")
+	}
+
+	pub fn set_nonce(nonce: u64) {
+		nonce.using_encoded(|raw_nonce| {
+			ext::storage_write(KEY_NONCE, raw_nonce);
+		});
+	}
+
+	/// Returns the next nonce to be assigned to a message.
+	///
+	/// Nonces start from 1.
+	pub fn next_nonce() -> u64 {
+		ext::storage_increment(KEY_NONCE, 1)
 	}
 }
 
-mod message_queue {
+pub mod message_queue {
 	// Gotcha, it is actually a stack and a terribly inefficient implementation.
 	use crate::ext;
-	use codec::{Decode, Encode};
+	use codec::Encode;
 	use primitives::TargetedMsg;
 	const KEY_QUEUE: &[u8] = b":stack";
+	const KEY_QUEUE_LEN: &[u8] = b":queue_len";
+	const KEY_QUEUE_BYTES: &[u8] = b":queue_bytes";
 
+	/// Read the queue.
+	///
+	/// A corrupted queue aborts the invocation rather than being treated as empty, which would
+	/// silently lose the messages.
 	fn read_queue() -> Vec<TargetedMsg> {
-		ext::storage_read(KEY_QUEUE)
-			.and_then(|raw_queue| <Vec<TargetedMsg>>::decode(&mut &raw_queue[..]).ok())
-			.unwrap_or_else(Vec::new)
+		ext::try_storage_read_or(KEY_QUEUE, Vec::new()).expect("the message queue is corrupted")
 	}
 
 	fn write_queue(queue: Vec<TargetedMsg>) {
 		queue.using_encoded(|raw_queue| {
 			ext::storage_write(KEY_QUEUE, raw_queue);
+			(raw_queue.len() as u32).using_encoded(|raw_bytes| {
+				ext::storage_write(KEY_QUEUE_BYTES, raw_bytes);
+			});
 		});
 	}
 
+	fn set_len(len: u32) {
+		len.using_encoded(|raw_len| {
+			ext::storage_write(KEY_QUEUE_LEN, raw_len);
+		});
+	}
+
+	/// Returns the number of messages in the queue.
+	///
+	/// This only reads the counter and doesn't touch the queue itself.
+	pub fn len() -> u32 {
+		ext::try_storage_read_or(KEY_QUEUE_LEN, 0).expect("the queue length is corrupted")
+	}
+
+	/// Returns the size of the encoded queue in bytes.
+	///
+	/// Like `len`, this only reads the counter and doesn't touch the queue itself.
+	pub fn byte_size() -> u32 {
+		// An empty queue is encoded as a single byte.
+		ext::try_storage_read_or(KEY_QUEUE_BYTES, 1).expect("the queue size is corrupted")
+	}
+
 	/// Enqueue a given message into the queue.
 	pub fn enqueue_msg(msg: TargetedMsg) {
+		enqueue_msgs(vec![msg]);
+	}
+
+	/// Enqueue the given messages into the queue, rewriting the queue only once.
+	pub fn enqueue_msgs(new_msgs: Vec<TargetedMsg>) {
+		let new_len = new_msgs.len() as u32;
+		let mut msgs = read_queue();
+		msgs.extend(new_msgs);
+		write_queue(msgs);
+		set_len(len() + new_len);
+	}
+
+	/// Remove the first message in the queue that matches the given predicate.
+	///
+	/// Returns the removed message or `None` if there is no matching message.
+	pub fn remove_first(pred: impl Fn(&TargetedMsg) -> bool) -> Option<TargetedMsg> {
 		let mut msgs = read_queue();
-		msgs.push(msg);
+		let pos = msgs.iter().position(pred)?;
+		let msg = msgs.remove(pos);
 		write_queue(msgs);
+		// The counter might lag behind the queue written by an older version of the module.
+		set_len(len().saturating_sub(1));
+		Some(msg)
+	}
+
+	/// Returns the contents of the queue without changing it.
+	pub fn queue() -> Vec<TargetedMsg> {
+		read_queue()
+	}
+
+	/// Replace the contents of the queue.
+	pub fn replace_queue(msgs: Vec<TargetedMsg>) {
+		let new_len = msgs.len() as u32;
+		write_queue(msgs);
+		set_len(new_len);
 	}
 
 	/// Empty the queue returning its contents.
@@ -64,6 +201,88 @@ mod message_queue {
 	pub fn take_queue() -> Vec<TargetedMsg> {
 		let msgs = read_queue();
 		write_queue(Vec::new());
+		set_len(0);
 		msgs
 	}
 }
+
+mod prepared {
+	use crate::ext;
+	use codec::Encode;
+	use primitives::{ParaId, TimestampedMsg};
+	const KEY_PREPARED: &[u8] = b":prepared";
+
+	/// Store the given message bundles, grouped by the recepient, to be sent later.
+	pub fn set_prepared(prepared: Vec<(ParaId, Vec<TimestampedMsg>)>) {
+		prepared.using_encoded(|raw_prepared| {
+			ext::storage_write(KEY_PREPARED, raw_prepared);
+		});
+	}
+
+	/// Returns the prepared message bundles without changing them.
+	pub fn prepared() -> Vec<(ParaId, Vec<TimestampedMsg>)> {
+		ext::try_storage_read_or(KEY_PREPARED, Vec::new())
+			.expect("the prepared bundles are corrupted")
+	}
+
+	/// Empty the prepared message bundles returning them.
+	pub fn take_prepared() -> Vec<(ParaId, Vec<TimestampedMsg>)> {
+		let prepared = prepared();
+		set_prepared(Vec::new());
+		prepared
+	}
+}
+
+mod fan_out_cursor {
+	use crate::ext;
+	use codec::Encode;
+	use primitives::ParaId;
+	const KEY_FAN_OUT_CURSOR: &[u8] = b":fanout_cursor";
+
+	/// Returns the last recepient served by an interrupted fan out, if any.
+	pub fn fan_out_cursor() -> Option<ParaId> {
+		ext::try_storage_read_or(KEY_FAN_OUT_CURSOR, None).expect("the fan out cursor is corrupted")
+	}
+
+	pub fn set_fan_out_cursor(cursor: Option<ParaId>) {
+		cursor.using_encoded(|raw_cursor| {
+			ext::storage_write(KEY_FAN_OUT_CURSOR, raw_cursor);
+		});
+	}
+}
+
+mod acks {
+	use crate::ext;
+	use codec::Encode;
+	use primitives::{ParaId, Timestamp};
+	const KEY_ACKS: &[u8] = b":acks";
+
+	/// Returns the acknowledged timestamps as `(sender, up_to_timestamp)`, sorted by sender.
+	pub fn acks() -> Vec<(ParaId, Timestamp)> {
+		ext::try_storage_read_or(KEY_ACKS, Vec::new()).expect("the acks are corrupted")
+	}
+
+	pub fn set_acks(acks: Vec<(ParaId, Timestamp)>) {
+		acks.using_encoded(|raw_acks| {
+			ext::storage_write(KEY_ACKS, raw_acks);
+		});
+	}
+
+	/// Acknowledge the messages from the sender up to the given timestamp.
+	///
+	/// The watermark only moves forward.
+	pub fn ack(sender: ParaId, up_to_timestamp: Timestamp) {
+		let mut acks = acks();
+		match acks.binary_search_by_key(&sender, |(acked_sender, _)| *acked_sender) {
+			Ok(pos) => {
+				let watermark = &mut acks[pos].1;
+				if *watermark >= up_to_timestamp {
+					return;
+				}
+				*watermark = up_to_timestamp;
+			}
+			Err(pos) => acks.insert(pos, (sender, up_to_timestamp)),
+		}
+		set_acks(acks);
+	}
+}